@@ -0,0 +1,68 @@
+#![feature(test)]
+
+extern crate test;
+
+// Compares the val/sign format (2 bits per weight) with base-3 (1.6 bits per weight) for
+// ternary weights times int8 activations. `bench.bytes` is the size of the packed weights, so
+// the MB/s column shows how fast each format streams through its weights.
+#[cfg(test)]
+mod tests {
+    use matmul::{
+        constants::SIZE,
+        gemm::{compress_a, encode_base3, matmul_base3, matmul_int8, Layout},
+        test_util::test_util::rand_int8_vecs,
+    };
+    use test::{black_box, Bencher};
+
+    #[bench]
+    fn bench_int8_val_sign(bench: &mut Bencher) {
+        let (a, b) = rand_int8_vecs(SIZE * SIZE, SIZE * SIZE);
+        let (a_vals, a_signs) = compress_a(SIZE, SIZE, &a, Layout::ColMajor, SIZE);
+        bench.bytes = (a_vals.len() + a_signs.len()) as u64;
+
+        bench.iter(|| {
+            black_box(matmul_int8(SIZE, SIZE, SIZE, &a_vals, &a_signs, &b));
+        });
+    }
+
+    #[bench]
+    fn bench_int8_base3(bench: &mut Bencher) {
+        let (a, b) = rand_int8_vecs(SIZE * SIZE, SIZE * SIZE);
+        let a_base3 = encode_base3(SIZE, SIZE, &a, Layout::ColMajor, SIZE);
+        bench.bytes = a_base3.len() as u64;
+
+        bench.iter(|| {
+            black_box(matmul_base3(SIZE, SIZE, SIZE, &a_base3, &b));
+        });
+    }
+
+    #[bench]
+    fn bench_compress_val_sign(bench: &mut Bencher) {
+        let (a, _) = rand_int8_vecs(SIZE * SIZE, 0);
+        bench.bytes = (SIZE * SIZE / 4) as u64;
+
+        bench.iter(|| {
+            black_box(compress_a(SIZE, SIZE, &a, Layout::ColMajor, SIZE));
+        });
+    }
+
+    #[bench]
+    fn bench_compress_val_sign_row_major(bench: &mut Bencher) {
+        let (a, _) = rand_int8_vecs(SIZE * SIZE, 0);
+        bench.bytes = (SIZE * SIZE / 4) as u64;
+
+        bench.iter(|| {
+            black_box(compress_a(SIZE, SIZE, &a, Layout::RowMajor, SIZE));
+        });
+    }
+
+    #[bench]
+    fn bench_encode_base3(bench: &mut Bencher) {
+        let (a, _) = rand_int8_vecs(SIZE * SIZE, 0);
+        bench.bytes = (SIZE * SIZE.div_ceil(5)) as u64;
+
+        bench.iter(|| {
+            black_box(encode_base3(SIZE, SIZE, &a, Layout::ColMajor, SIZE));
+        });
+    }
+}
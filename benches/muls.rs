@@ -7,64 +7,147 @@ mod tests {
     use blas::sgemm;
     use matmul::{
         constants::SIZE,
+        gemm::{
+            compress_a, gemv_int8, matmul, matmul_i32, matmul_int8, matmul_packed, prep, Layout,
+            PackedTernaryMatrix,
+        },
         muls::{
-            mm1::matmul1,
-            mm10::{matmul10, prep10},
-            mm2::matmul2,
-            mm3::matmul3,
-            mm4::matmul4,
-            mm5::matmul5,
-            mm6::matmul6,
-            mm7::{matmul7, prep7},
-            mm8::{matmul8, prep8},
-            mm9::{matmul9, prep9},
+            find,
+            mm11::{matmul11_int8, pack11},
+            MatmulKernel,
         },
-        test_util::test_util::rand_vecs,
+        test_util::test_util::{rand_int8_vecs, rand_vecs, rand_vecs_sized},
     };
     use ndarray::Array2;
     use test::{black_box, Bencher};
 
+    fn bench_kernel(bench: &mut Bencher, kernel: &dyn MatmulKernel) {
+        let (a, b) = rand_vecs(SIZE * SIZE);
+        let prepared = kernel.prepare(SIZE, SIZE, SIZE, &a, &b);
+
+        bench.iter(|| {
+            black_box(kernel.execute(SIZE, SIZE, SIZE, &prepared));
+        });
+    }
+
     macro_rules! bench {
-        ($num: literal) => {
+        ($name: ident) => {
             paste::item! {
                 #[bench]
-                fn [< bench_mm $num >](bench: &mut Bencher) {
-                    let (a, b) = rand_vecs(SIZE * SIZE);
-                    bench.iter(|| {
-                        black_box([< matmul $num >](&a, &b));
-                    });
+                fn [< bench_ $name >](bench: &mut Bencher) {
+                    bench_kernel(bench, find(stringify!($name)).unwrap());
                 }
             }
         };
     }
-    macro_rules! bench_w_prep {
-        ($num: literal) => {
-            paste::item! {
-                #[bench]
-                fn [< bench_mm $num >](bench: &mut Bencher) {
-                    let (a, b) = rand_vecs(SIZE * SIZE);
-                    let (av, asi, bv, bs) = [< prep $num >](&a, &b);
 
-                    bench.iter(|| {
-                        black_box([< matmul $num >](&av, &asi, &bv, &bs));
-                    });
-                }
-            }
-        };
+    // bench!(mm1);
+    // bench!(mm2);
+    // bench!(mm3);
+    bench!(mm4);
+    bench!(mm5);
+    bench!(mm6);
+
+    // From now on, we compress ternary matrices
+    bench!(mm7);
+    bench!(mm8);
+    bench!(mm9);
+    bench!(mm10);
+    bench!(gemm);
+    bench!(mm11);
+
+    // Small batches (speculative decoding, small-batch serving) use 64x4 or 32x8 tiles
+    fn bench_small_batch(bench: &mut Bencher, n: usize) {
+        let (m, k) = (GEMV_SIZE, GEMV_SIZE);
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        let (a_vals, a_signs, b_vals, b_signs) = prep(m, k, n, &a, &b);
+
+        bench.iter(|| {
+            black_box(matmul(m, k, n, &a_vals, &a_signs, &b_vals, &b_signs));
+        });
     }
 
-    // bench!(1);
-    // bench!(2);
-    // bench!(3);
-    bench!(4);
-    bench!(5);
-    bench!(6);
+    #[bench]
+    fn bench_small_batch_4(bench: &mut Bencher) {
+        bench_small_batch(bench, 4);
+    }
 
-    // From now on, we compress ternary matrices
-    bench_w_prep!(7);
-    bench_w_prep!(8);
-    bench_w_prep!(9);
-    bench_w_prep!(10);
+    #[bench]
+    fn bench_small_batch_8(bench: &mut Bencher) {
+        bench_small_batch(bench, 8);
+    }
+
+    // Weights packed once up front against packing them in every call
+    const PACKED_N: usize = 16;
+
+    #[bench]
+    fn bench_gemm_i32(bench: &mut Bencher) {
+        let (m, k, n) = (GEMV_SIZE, GEMV_SIZE, PACKED_N);
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        let (a_vals, a_signs, b_vals, b_signs) = prep(m, k, n, &a, &b);
+
+        bench.iter(|| {
+            black_box(matmul_i32(m, k, n, &a_vals, &a_signs, &b_vals, &b_signs));
+        });
+    }
+
+    #[bench]
+    fn bench_gemm_packed(bench: &mut Bencher) {
+        let (m, k, n) = (GEMV_SIZE, GEMV_SIZE, PACKED_N);
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        let (_, _, b_vals, b_signs) = prep(m, k, n, &a, &b);
+        let packed = PackedTernaryMatrix::new(m, k, &a, Layout::ColMajor, m);
+
+        bench.iter(|| {
+            black_box(matmul_packed(&packed, n, &b_vals, &b_signs));
+        });
+    }
+
+    // Matrix-vector products of ternary weights and int8 activations, the lookup tables of
+    // mm11 against the lane masks of `gemv_int8` (which `matmul_int8` uses for n = 1)
+    const GEMV_SIZE: usize = 4096;
+
+    #[bench]
+    fn bench_gemv_mm11(bench: &mut Bencher) {
+        let (a, x) = rand_int8_vecs(GEMV_SIZE * GEMV_SIZE, GEMV_SIZE);
+        let a_packed = pack11(GEMV_SIZE, GEMV_SIZE, &a);
+
+        bench.iter(|| {
+            black_box(matmul11_int8(GEMV_SIZE, GEMV_SIZE, 1, &a_packed, &x));
+        });
+    }
+
+    #[bench]
+    fn bench_gemv_int8(bench: &mut Bencher) {
+        let (a, x) = rand_int8_vecs(GEMV_SIZE * GEMV_SIZE, GEMV_SIZE);
+        let (a_vals, a_signs) = compress_a(GEMV_SIZE, GEMV_SIZE, &a, Layout::ColMajor, GEMV_SIZE);
+
+        bench.iter(|| {
+            black_box(matmul_int8(GEMV_SIZE, GEMV_SIZE, 1, &a_vals, &a_signs, &x));
+        });
+    }
+
+    #[bench]
+    fn bench_gemv_int8_row_major(bench: &mut Bencher) {
+        let (a, x) = rand_int8_vecs(GEMV_SIZE * GEMV_SIZE, GEMV_SIZE);
+        let (a_vals, a_signs) = compress_a(GEMV_SIZE, GEMV_SIZE, &a, Layout::RowMajor, GEMV_SIZE);
+        let lda = GEMV_SIZE / 8;
+        let mut y = vec![0; GEMV_SIZE];
+
+        bench.iter(|| {
+            gemv_int8(
+                Layout::RowMajor,
+                GEMV_SIZE,
+                GEMV_SIZE,
+                &a_vals,
+                &a_signs,
+                lda,
+                &x,
+                &mut y,
+            );
+            black_box(&mut y);
+        });
+    }
 
     #[bench]
     fn bench_blas_f32(bench: &mut Bencher) {
@@ -76,12 +159,13 @@ mod tests {
         let mut c = vec![0_f32; SIZE * SIZE];
 
         bench.iter(|| {
-            black_box(unsafe {
+            unsafe {
                 sgemm(
                     b'N', b'N', size_32, size_32, size_32, 1.0, &a_f, size_32, &b_f, size_32, 1.0,
                     &mut c, size_32,
                 )
-            });
+            };
+            black_box(&mut c);
         });
     }
 
@@ -0,0 +1,89 @@
+/*
+ * C ABI of the ternary gemm kernels (capi/src/lib.rs). Link against libternary_gemm, the
+ * cdylib or staticlib of the matmul-capi crate.
+ *
+ * Ternary matrices hold -1, 0 and 1 as int8_t. Compressed matrices store them as val/sign
+ * bits along k, 8 trits per byte, so a compressed m x k A is m x ceil(k / 8) bytes and a
+ * compressed k x n B is ceil(k / 8) x n bytes. Leading dimensions of compressed matrices are
+ * in bytes. C is always col-major and accumulated into: C += A * B.
+ *
+ * Every function except ternary_compressed_len, ternary_packed_free and ternary_status_string
+ * returns TERNARY_OK or a negative status code.
+ */
+#ifndef TERNARY_GEMM_H
+#define TERNARY_GEMM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Layouts, the CBLAS values */
+#define TERNARY_ROW_MAJOR 101
+#define TERNARY_COL_MAJOR 102
+
+/* Status codes */
+#define TERNARY_OK 0
+#define TERNARY_ERR_NULL_POINTER (-1)
+#define TERNARY_ERR_INVALID_LAYOUT (-2)
+#define TERNARY_ERR_INVALID_TRIT (-3)
+#define TERNARY_ERR_SHAPE_MISMATCH (-4)
+#define TERNARY_ERR_MISALIGNED_K (-5)
+#define TERNARY_ERR_BUFFER_TOO_SMALL (-6)
+#define TERNARY_ERR_UNSUPPORTED_KERNEL (-7)
+#define TERNARY_ERR_PANIC (-8)
+
+/* Weights compressed and packed once for the selected kernel */
+typedef struct TernaryPacked ternary_packed_t;
+
+/* Bytes of the val (and of the sign) buffer of rows compressed rows of k trits: rows = m for
+ * ternary_compress_a, rows = n for ternary_compress_b */
+size_t ternary_compressed_len(size_t rows, size_t k);
+
+/* Compresses an m x k ternary A (leading dimension lda) into contiguous vals and signs of
+ * ternary_compressed_len(m, k) bytes, in the same layout: leading dimension m for col-major,
+ * ceil(k / 8) for row-major */
+int ternary_compress_a(int a_layout, size_t m, size_t k, const int8_t *a, size_t lda,
+                       uint8_t *vals, uint8_t *signs);
+
+/* Compresses a k x n ternary B (leading dimension ldb) into contiguous vals and signs of
+ * ternary_compressed_len(n, k) bytes, in the same layout: leading dimension ceil(k / 8) for
+ * col-major, n for row-major */
+int ternary_compress_b(int b_layout, size_t k, size_t n, const int8_t *b, size_t ldb,
+                       uint8_t *vals, uint8_t *signs);
+
+/* Compresses and packs an m x k ternary A (leading dimension lda) into *out. Release it with
+ * ternary_packed_free. */
+int ternary_prepack(int a_layout, size_t m, size_t k, const int8_t *a, size_t lda,
+                    ternary_packed_t **out);
+
+/* Releases packed weights, NULL is ignored */
+void ternary_packed_free(ternary_packed_t *packed);
+
+/* C += A * B for compressed A (m x k) and B (k x n), col-major int32 C (leading dimension
+ * ldc) */
+int ternary_tgemm(int a_layout, int b_layout, size_t m, size_t n, size_t k,
+                  const uint8_t *a_vals, const uint8_t *a_signs, size_t lda,
+                  const uint8_t *b_vals, const uint8_t *b_signs, size_t ldb, int32_t *c,
+                  size_t ldc);
+
+/* C += A * B for packed A (m x k) and compressed B (k x n) */
+int ternary_tgemm_packed(const ternary_packed_t *a, int b_layout, size_t n,
+                         const uint8_t *b_vals, const uint8_t *b_signs, size_t ldb, int32_t *c,
+                         size_t ldc);
+
+/* y += A * x for compressed A (m x k), an int8 vector x of length k and an int32 vector y of
+ * length m */
+int ternary_gemv(int a_layout, size_t m, size_t k, const uint8_t *a_vals,
+                 const uint8_t *a_signs, size_t lda, const int8_t *x, int32_t *y);
+
+/* A static description of a status code */
+const char *ternary_status_string(int status);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TERNARY_GEMM_H */
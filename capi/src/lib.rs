@@ -0,0 +1,315 @@
+//! C ABI of the gemm kernels of `matmul`, declared in `include/ternary_gemm.h`. The crate
+//! builds `libternary_gemm` as a cdylib and a staticlib, so `matmul` itself stays a plain Rust
+//! library.
+//!
+//! Same conventions as the Rust API: compressed matrices are compressed along k, C is col-major
+//! and accumulated into (`C += A * B`). Matrix arguments take a layout (the CBLAS values
+//! [`TERNARY_ROW_MAJOR`] and [`TERNARY_COL_MAJOR`]), their dimensions and a leading dimension,
+//! like BLAS. Every function returns a status code instead of panicking, so errors never
+//! unwind into C.
+use std::{
+    ffi::{c_char, c_int},
+    panic::{catch_unwind, AssertUnwindSafe},
+    slice,
+};
+
+use matmul::gemm::{
+    try_compress_a, try_compress_b, try_gemv_int8, try_ternary_gemm, try_ternary_gemm_packed,
+    Layout, PackedTernaryMatrix, TernaryError,
+};
+
+pub const TERNARY_ROW_MAJOR: c_int = 101;
+pub const TERNARY_COL_MAJOR: c_int = 102;
+
+pub const TERNARY_OK: c_int = 0;
+pub const TERNARY_ERR_NULL_POINTER: c_int = -1;
+pub const TERNARY_ERR_INVALID_LAYOUT: c_int = -2;
+pub const TERNARY_ERR_INVALID_TRIT: c_int = -3;
+pub const TERNARY_ERR_SHAPE_MISMATCH: c_int = -4;
+pub const TERNARY_ERR_MISALIGNED_K: c_int = -5;
+pub const TERNARY_ERR_BUFFER_TOO_SMALL: c_int = -6;
+pub const TERNARY_ERR_UNSUPPORTED_KERNEL: c_int = -7;
+pub const TERNARY_ERR_PANIC: c_int = -8;
+
+/// Weights packed by [`ternary_prepack`], opaque to C.
+pub struct TernaryPacked(PackedTernaryMatrix<'static>);
+
+fn status(err: TernaryError) -> c_int {
+    match err {
+        TernaryError::InvalidTrit { .. } => TERNARY_ERR_INVALID_TRIT,
+        TernaryError::ShapeMismatch { .. } => TERNARY_ERR_SHAPE_MISMATCH,
+        TernaryError::MisalignedK { .. } => TERNARY_ERR_MISALIGNED_K,
+        TernaryError::BufferTooSmall { .. } => TERNARY_ERR_BUFFER_TOO_SMALL,
+        TernaryError::UnsupportedKernel { .. } => TERNARY_ERR_UNSUPPORTED_KERNEL,
+    }
+}
+
+// Runs f, turning errors and panics into status codes
+fn guard(f: impl FnOnce() -> Result<(), c_int>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => TERNARY_OK,
+        Ok(Err(status)) => status,
+        Err(_) => TERNARY_ERR_PANIC,
+    }
+}
+
+fn layout(layout: c_int) -> Result<Layout, c_int> {
+    match layout {
+        TERNARY_ROW_MAJOR => Ok(Layout::RowMajor),
+        TERNARY_COL_MAJOR => Ok(Layout::ColMajor),
+        _ => Err(TERNARY_ERR_INVALID_LAYOUT),
+    }
+}
+
+// Elements a rows x cols matrix with leading dimension ld spans
+fn matrix_len(layout: Layout, rows: usize, cols: usize, ld: usize) -> Result<usize, c_int> {
+    if rows == 0 || cols == 0 {
+        return Ok(0);
+    }
+    let (outer, inner) = match layout {
+        Layout::ColMajor => (cols, rows),
+        Layout::RowMajor => (rows, cols),
+    };
+    if ld < inner {
+        return Err(TERNARY_ERR_SHAPE_MISMATCH);
+    }
+    (outer - 1)
+        .checked_mul(ld)
+        .and_then(|len| len.checked_add(inner))
+        .ok_or(TERNARY_ERR_SHAPE_MISMATCH)
+}
+
+// The caller's buffer of len elements as a slice
+unsafe fn buffer<'a, T>(data: *const T, len: usize) -> Result<&'a [T], c_int> {
+    match (data.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(TERNARY_ERR_NULL_POINTER),
+        (false, len) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn buffer_mut<'a, T>(data: *mut T, len: usize) -> Result<&'a mut [T], c_int> {
+    match (data.is_null(), len) {
+        (_, 0) => Ok(&mut []),
+        (true, _) => Err(TERNARY_ERR_NULL_POINTER),
+        (false, len) => Ok(slice::from_raw_parts_mut(data, len)),
+    }
+}
+
+/// Bytes of the val (and of the sign) buffer of `rows` compressed rows of k trits, the
+/// contiguous output of [`ternary_compress_a`] (rows = m) and [`ternary_compress_b`] (rows = n).
+#[no_mangle]
+pub extern "C" fn ternary_compressed_len(rows: usize, k: usize) -> usize {
+    rows.saturating_mul(k.div_ceil(8))
+}
+
+/// Compresses an `m x k` ternary A with leading dimension lda into contiguous val and sign
+/// buffers of [`ternary_compressed_len`]`(m, k)` bytes in the same layout (leading dimension
+/// m for col-major and `ceil(k / 8)` for row-major).
+///
+/// # Safety
+///
+/// `a` must point to the `m x k` matrix, `vals` and `signs` to buffers of
+/// `ternary_compressed_len(m, k)` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ternary_compress_a(
+    a_layout: c_int,
+    m: usize,
+    k: usize,
+    a: *const i8,
+    lda: usize,
+    vals: *mut u8,
+    signs: *mut u8,
+) -> c_int {
+    guard(|| {
+        let a_layout = layout(a_layout)?;
+        let a = buffer(a, matrix_len(a_layout, m, k, lda)?)?;
+        let (a_vals, a_signs) = try_compress_a(m, k, a, a_layout, lda).map_err(status)?;
+        buffer_mut(vals, a_vals.len())?.copy_from_slice(&a_vals);
+        buffer_mut(signs, a_signs.len())?.copy_from_slice(&a_signs);
+        Ok(())
+    })
+}
+
+/// Compresses a `k x n` ternary B with leading dimension ldb into contiguous val and sign
+/// buffers of [`ternary_compressed_len`]`(n, k)` bytes in the same layout (leading dimension
+/// `ceil(k / 8)` for col-major and n for row-major).
+///
+/// # Safety
+///
+/// `b` must point to the `k x n` matrix, `vals` and `signs` to buffers of
+/// `ternary_compressed_len(n, k)` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ternary_compress_b(
+    b_layout: c_int,
+    k: usize,
+    n: usize,
+    b: *const i8,
+    ldb: usize,
+    vals: *mut u8,
+    signs: *mut u8,
+) -> c_int {
+    guard(|| {
+        let b_layout = layout(b_layout)?;
+        let b = buffer(b, matrix_len(b_layout, k, n, ldb)?)?;
+        let (b_vals, b_signs) = try_compress_b(k, n, b, b_layout, ldb).map_err(status)?;
+        buffer_mut(vals, b_vals.len())?.copy_from_slice(&b_vals);
+        buffer_mut(signs, b_signs.len())?.copy_from_slice(&b_signs);
+        Ok(())
+    })
+}
+
+/// Compresses and packs an `m x k` ternary A (uncompressed, leading dimension lda) for the
+/// selected kernel, see [`PackedTernaryMatrix`]. Stores the packed weights in `*out`, to be
+/// released with [`ternary_packed_free`].
+///
+/// # Safety
+///
+/// `a` must point to the `m x k` matrix and `out` to a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ternary_prepack(
+    a_layout: c_int,
+    m: usize,
+    k: usize,
+    a: *const i8,
+    lda: usize,
+    out: *mut *mut TernaryPacked,
+) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err(TERNARY_ERR_NULL_POINTER);
+        }
+        let a_layout = layout(a_layout)?;
+        let a = buffer(a, matrix_len(a_layout, m, k, lda)?)?;
+        let packed = PackedTernaryMatrix::try_new(m, k, a, a_layout, lda).map_err(status)?;
+        *out = Box::into_raw(Box::new(TernaryPacked(packed)));
+        Ok(())
+    })
+}
+
+/// Releases weights packed by [`ternary_prepack`]. Null is ignored.
+///
+/// # Safety
+///
+/// `packed` must come from `ternary_prepack` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn ternary_packed_free(packed: *mut TernaryPacked) {
+    if !packed.is_null() {
+        drop(Box::from_raw(packed));
+    }
+}
+
+/// Computes `C += A * B` for compressed A (`m x k`) and B (`k x n`) with leading dimensions
+/// lda and ldb in compressed bytes, and col-major i32 C with leading dimension ldc, see
+/// [`matmul::gemm::ternary_gemm`].
+///
+/// # Safety
+///
+/// The pointers must point to matrices of the given shapes and leading dimensions.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn ternary_tgemm(
+    a_layout: c_int,
+    b_layout: c_int,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: *const u8,
+    a_signs: *const u8,
+    lda: usize,
+    b_vals: *const u8,
+    b_signs: *const u8,
+    ldb: usize,
+    c: *mut i32,
+    ldc: usize,
+) -> c_int {
+    guard(|| {
+        let (a_layout, b_layout) = (layout(a_layout)?, layout(b_layout)?);
+        let kb = k.div_ceil(8);
+        let a_len = matrix_len(a_layout, m, kb, lda)?;
+        let b_len = matrix_len(b_layout, kb, n, ldb)?;
+        let (a_vals, a_signs) = (buffer(a_vals, a_len)?, buffer(a_signs, a_len)?);
+        let (b_vals, b_signs) = (buffer(b_vals, b_len)?, buffer(b_signs, b_len)?);
+        let c = buffer_mut(c, matrix_len(Layout::ColMajor, m, n, ldc)?)?;
+        try_ternary_gemm(
+            a_layout, b_layout, m, n, k, a_vals, a_signs, lda, b_vals, b_signs, ldb, c, ldc,
+        )
+        .map_err(status)
+    })
+}
+
+/// Computes `C += A * B` like [`ternary_tgemm`], but for A packed by [`ternary_prepack`].
+///
+/// # Safety
+///
+/// `a` must come from `ternary_prepack`, the other pointers must point to matrices of the
+/// given shapes and leading dimensions.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn ternary_tgemm_packed(
+    a: *const TernaryPacked,
+    b_layout: c_int,
+    n: usize,
+    b_vals: *const u8,
+    b_signs: *const u8,
+    ldb: usize,
+    c: *mut i32,
+    ldc: usize,
+) -> c_int {
+    guard(|| {
+        let Some(TernaryPacked(a)) = a.as_ref() else {
+            return Err(TERNARY_ERR_NULL_POINTER);
+        };
+        let b_layout = layout(b_layout)?;
+        let b_len = matrix_len(b_layout, a.k().div_ceil(8), n, ldb)?;
+        let (b_vals, b_signs) = (buffer(b_vals, b_len)?, buffer(b_signs, b_len)?);
+        let c = buffer_mut(c, matrix_len(Layout::ColMajor, a.m(), n, ldc)?)?;
+        try_ternary_gemm_packed(a, b_layout, n, b_vals, b_signs, ldb, c, ldc).map_err(status)
+    })
+}
+
+/// Computes `y += A * x` for compressed A (`m x k`, leading dimension lda in compressed bytes)
+/// and an int8 vector x of length k into the i32 vector y of length m, see
+/// [`matmul::gemm::gemv_int8`].
+///
+/// # Safety
+///
+/// The pointers must point to a matrix and vectors of the given sizes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn ternary_gemv(
+    a_layout: c_int,
+    m: usize,
+    k: usize,
+    a_vals: *const u8,
+    a_signs: *const u8,
+    lda: usize,
+    x: *const i8,
+    y: *mut i32,
+) -> c_int {
+    guard(|| {
+        let a_layout = layout(a_layout)?;
+        let a_len = matrix_len(a_layout, m, k.div_ceil(8), lda)?;
+        let (a_vals, a_signs) = (buffer(a_vals, a_len)?, buffer(a_signs, a_len)?);
+        let (x, y) = (buffer(x, k)?, buffer_mut(y, m)?);
+        try_gemv_int8(a_layout, m, k, a_vals, a_signs, lda, x, y).map_err(status)
+    })
+}
+
+/// A static, NUL-terminated description of a status code.
+#[no_mangle]
+pub extern "C" fn ternary_status_string(status: c_int) -> *const c_char {
+    let message: &'static [u8] = match status {
+        TERNARY_OK => b"ok\0",
+        TERNARY_ERR_NULL_POINTER => b"null pointer\0",
+        TERNARY_ERR_INVALID_LAYOUT => b"invalid layout\0",
+        TERNARY_ERR_INVALID_TRIT => b"not a ternary value\0",
+        TERNARY_ERR_SHAPE_MISMATCH => b"shape mismatch\0",
+        TERNARY_ERR_MISALIGNED_K => b"misaligned k\0",
+        TERNARY_ERR_BUFFER_TOO_SMALL => b"buffer too small\0",
+        TERNARY_ERR_UNSUPPORTED_KERNEL => b"unsupported kernel\0",
+        TERNARY_ERR_PANIC => b"internal error\0",
+        _ => b"unknown status\0",
+    };
+    message.as_ptr().cast()
+}
@@ -0,0 +1,103 @@
+/* Tests the C ABI against a naive matmul, compiled and run by tests/c_abi.rs */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "ternary_gemm.h"
+
+#define M 37
+#define K 70
+#define N 21
+
+static int failures = 0;
+
+#define CHECK(cond)                                                           \
+    do {                                                                      \
+        if (!(cond)) {                                                        \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+            failures++;                                                       \
+        }                                                                     \
+    } while (0)
+
+#define CHECK_STATUS(call, expected)                                          \
+    do {                                                                      \
+        int status_ = (call);                                                 \
+        if (status_ != (expected)) {                                          \
+            fprintf(stderr, "%s:%d: %s returned %d (%s)\n", __FILE__, __LINE__, #call, \
+                    status_, ternary_status_string(status_));                \
+            failures++;                                                       \
+        }                                                                     \
+    } while (0)
+
+static int8_t trit(void) { return (int8_t)(rand() % 3) - 1; }
+
+int main(void) {
+    /* Row-major A (weights), col-major B (activations), col-major C */
+    static int8_t a[M * K], b[K * N];
+    static int32_t expected[M * N], c[M * N];
+    srand(42);
+    for (size_t i = 0; i < M * K; i++) a[i] = trit();
+    for (size_t i = 0; i < K * N; i++) b[i] = trit();
+    for (size_t i = 0; i < M; i++) {
+        for (size_t j = 0; j < N; j++) {
+            int32_t sum = 0;
+            for (size_t p = 0; p < K; p++) sum += a[i * K + p] * b[p + j * K];
+            expected[i + j * M] = sum;
+        }
+    }
+
+    size_t kb = (K + 7) / 8;
+    CHECK(ternary_compressed_len(M, K) == M * kb);
+    uint8_t *a_vals = malloc(ternary_compressed_len(M, K));
+    uint8_t *a_signs = malloc(ternary_compressed_len(M, K));
+    uint8_t *b_vals = malloc(ternary_compressed_len(N, K));
+    uint8_t *b_signs = malloc(ternary_compressed_len(N, K));
+    CHECK_STATUS(ternary_compress_a(TERNARY_ROW_MAJOR, M, K, a, K, a_vals, a_signs), TERNARY_OK);
+    CHECK_STATUS(ternary_compress_b(TERNARY_COL_MAJOR, K, N, b, K, b_vals, b_signs), TERNARY_OK);
+
+    memset(c, 0, sizeof(c));
+    CHECK_STATUS(ternary_tgemm(TERNARY_ROW_MAJOR, TERNARY_COL_MAJOR, M, N, K, a_vals, a_signs, kb,
+                               b_vals, b_signs, kb, c, M),
+                 TERNARY_OK);
+    CHECK(memcmp(c, expected, sizeof(c)) == 0);
+
+    /* Packed weights, accumulating into C */
+    ternary_packed_t *packed = NULL;
+    CHECK_STATUS(ternary_prepack(TERNARY_ROW_MAJOR, M, K, a, K, &packed), TERNARY_OK);
+    CHECK_STATUS(ternary_tgemm_packed(packed, TERNARY_COL_MAJOR, N, b_vals, b_signs, kb, c, M),
+                 TERNARY_OK);
+    for (size_t i = 0; i < M * N; i++) CHECK(c[i] == 2 * expected[i]);
+    ternary_packed_free(packed);
+    ternary_packed_free(NULL);
+
+    /* gemv with the first col of B as int8 activations */
+    int32_t y[M] = {0};
+    CHECK_STATUS(ternary_gemv(TERNARY_ROW_MAJOR, M, K, a_vals, a_signs, kb, b, y), TERNARY_OK);
+    for (size_t i = 0; i < M; i++) CHECK(y[i] == expected[i]);
+
+    /* Errors are status codes */
+    CHECK_STATUS(ternary_tgemm(TERNARY_ROW_MAJOR, TERNARY_COL_MAJOR, M, N, K, a_vals, a_signs,
+                               kb, b_vals, b_signs, kb, c, M - 1),
+                 TERNARY_ERR_SHAPE_MISMATCH);
+    CHECK_STATUS(ternary_tgemm(7, TERNARY_COL_MAJOR, M, N, K, a_vals, a_signs, kb, b_vals,
+                               b_signs, kb, c, M),
+                 TERNARY_ERR_INVALID_LAYOUT);
+    CHECK_STATUS(ternary_gemv(TERNARY_ROW_MAJOR, M, K, NULL, a_signs, kb, b, y),
+                 TERNARY_ERR_NULL_POINTER);
+    CHECK_STATUS(ternary_prepack(TERNARY_ROW_MAJOR, M, K, a, K, NULL), TERNARY_ERR_NULL_POINTER);
+    a[5] = 2;
+    CHECK_STATUS(ternary_compress_a(TERNARY_ROW_MAJOR, M, K, a, K, a_vals, a_signs),
+                 TERNARY_ERR_INVALID_TRIT);
+    CHECK(strcmp(ternary_status_string(TERNARY_ERR_INVALID_TRIT), "not a ternary value") == 0);
+
+    free(a_vals);
+    free(a_signs);
+    free(b_vals);
+    free(b_signs);
+    if (failures) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("ok\n");
+    return 0;
+}
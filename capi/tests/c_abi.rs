@@ -0,0 +1,43 @@
+//! Compiles tests/c/test_ternary_gemm.c against include/ternary_gemm.h and the cdylib of the
+//! crate, and runs it. `cargo test` builds the cdylib along with the rlib the tests link.
+//!
+//! The C compiler is `$CC` or `cc`, invoked with unix linker flags, so the test only runs on
+//! unix targets.
+#![cfg(unix)]
+
+use std::{env, path::PathBuf, process::Command};
+
+#[test]
+fn test_c_abi() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // target/<profile>/deps/c_abi-<hash>, the cdylib is built next to it. Cargo doesn't
+    // always copy it up to target/<profile> when it runs tests.
+    let exe = env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap();
+    let out = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("test_ternary_gemm");
+
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(cc)
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(root.join("tests/c/test_ternary_gemm.c"))
+        .arg("-o")
+        .arg(&out)
+        .arg("-L")
+        .arg(lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lternary_gemm")
+        .status()
+        .expect("failed to run the C compiler");
+    assert!(status.success(), "compiling the C test failed");
+
+    let output = Command::new(&out).output().unwrap();
+    assert!(
+        output.status.success(),
+        "C test failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
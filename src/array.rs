@@ -0,0 +1,442 @@
+//! ndarray integration: [`TernaryArray2`] holds a compressed ternary matrix with its shape,
+//! converts from and to `ArrayView2<i8>` and multiplies with i8 and f32 views via
+//! [`TernaryArray2::dot`] and [`TernaryArray2::dot_f32`], using the gemm kernels.
+//!
+//! Views in either memory order are used in place. Other strides (e.g. a slice with a step)
+//! are copied into a contiguous array first.
+use std::cmp::min;
+
+use ndarray::{linalg::Dot, s, Array2, ArrayView2, CowArray, Ix2, ShapeBuilder};
+
+use crate::gemm::{
+    compress_b, gemm_int8, gemm_int8_f32, quantize_activations, ternary_gemm, try_compress_a,
+    validate_trits, Granularity, Layout, QuantizedActivations, Scale, TernaryError, TernaryTensor,
+};
+
+/// A ternary `m x k` matrix compressed along k into val/sign bits, in the memory order of the
+/// array it was created from, with its weight scales.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TernaryArray2 {
+    m: usize,
+    k: usize,
+    layout: Layout,
+    vals: Vec<u8>,
+    signs: Vec<u8>,
+    granularity: Granularity,
+    scales: Vec<f32>,
+}
+
+// A contiguous view of a in its memory order, copied to row-major if it has other strides
+fn contiguous<'a, T: Clone>(a: &ArrayView2<'a, T>) -> (CowArray<'a, T, Ix2>, Layout) {
+    if a.is_standard_layout() {
+        (CowArray::from(*a), Layout::RowMajor)
+    } else if a.t().is_standard_layout() {
+        (CowArray::from(*a), Layout::ColMajor)
+    } else {
+        let values = a.iter().cloned().collect();
+        let copy = Array2::from_shape_vec(a.dim(), values).unwrap();
+        (CowArray::from(copy), Layout::RowMajor)
+    }
+}
+
+// Quantizes the cols of an f32 B per token, see `quantize_activations`
+fn quantize_rhs(rhs: &ArrayView2<'_, f32>) -> QuantizedActivations {
+    let k = rhs.nrows();
+    // The tokens (cols of B) have to be contiguous, like a col-major B
+    let b_t = rhs.t();
+    let b = b_t.as_standard_layout();
+    quantize_activations(k, rhs.ncols(), b.as_slice().unwrap(), k.max(1))
+}
+
+// Leading dimension of a contiguous rows x cols matrix
+fn ld(layout: Layout, rows: usize, cols: usize) -> usize {
+    match layout {
+        Layout::ColMajor => rows.max(1),
+        Layout::RowMajor => cols.max(1),
+    }
+}
+
+impl TernaryArray2 {
+    /// Compresses a ternary matrix. Panics on values other than -1, 0 and 1, see
+    /// [`TernaryArray2::try_from_view`].
+    pub fn from_view(a: ArrayView2<'_, i8>) -> Self {
+        Self::try_from_view(a).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [`TernaryArray2::from_view`], but returns an error for invalid values. The
+    /// index of an invalid value is in the memory order of the (contiguous) view. The weight
+    /// scale is 1.
+    pub fn try_from_view(a: ArrayView2<'_, i8>) -> Result<Self, TernaryError> {
+        let (m, k) = a.dim();
+        let (a, layout) = contiguous(&a);
+        let values = a.as_slice_memory_order().unwrap();
+        let (vals, signs) = try_compress_a(m, k, values, layout, ld(layout, m, k))?;
+        Ok(Self {
+            m,
+            k,
+            layout,
+            vals,
+            signs,
+            granularity: Granularity::PerTensor,
+            scales: vec![1.],
+        })
+    }
+
+    /// Shape `(m, k)`
+    pub fn dim(&self) -> (usize, usize) {
+        (self.m, self.k)
+    }
+
+    /// Memory order of the compressed bits
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// The compressed val and sign bits, `m x ceil(k / 8)` in [`TernaryArray2::layout`]
+    pub fn compressed(&self) -> (&[u8], &[u8]) {
+        (&self.vals, &self.signs)
+    }
+
+    /// The weight scales, 1 unless the array was converted from a [`TernaryTensor`]
+    pub fn scales(&self) -> (Granularity, &[f32]) {
+        (self.granularity, &self.scales)
+    }
+
+    /// The scales as the weight scale of an epilogue, see [`TernaryTensor::w_scale`]
+    pub fn w_scale(&self) -> Option<Scale<'_>> {
+        match self.granularity {
+            Granularity::PerTensor => Some(Scale::PerTensor(self.scales[0])),
+            Granularity::PerChannel => Some(Scale::PerChannel(&self.scales)),
+            Granularity::PerGroup(_) => None,
+        }
+    }
+
+    fn lda(&self) -> usize {
+        ld(self.layout, self.m, self.k.div_ceil(8))
+    }
+
+    /// Decompresses into an array in the same memory order
+    pub fn to_array(&self) -> Array2<i8> {
+        let lda = self.lda();
+        let value = |r: usize, c: usize| {
+            let i = match self.layout {
+                Layout::ColMajor => r + (c / 8) * lda,
+                Layout::RowMajor => r * lda + c / 8,
+            };
+            let bit = 1 << (c % 8);
+            match (self.vals[i] & bit != 0, self.signs[i] & bit != 0) {
+                (false, _) => 0,
+                (true, false) => 1,
+                (true, true) => -1,
+            }
+        };
+        match self.layout {
+            Layout::RowMajor => Array2::from_shape_fn((self.m, self.k), |(r, c)| value(r, c)),
+            Layout::ColMajor => Array2::from_shape_fn((self.m, self.k).f(), |(r, c)| value(r, c)),
+        }
+    }
+
+    /// Matrix product with an i8 or f32 `k x n` view, like ndarray's `dot`. Panics if the
+    /// shapes don't match.
+    ///
+    /// * `ArrayView2<i8>` gives the exact `Array2<i32>`. Ternary B uses the ternary kernels
+    ///   ([`ternary_gemm`]), any other int8 B [`gemm_int8`].
+    /// * `ArrayView2<f32>` gives `Array2<f32>`, see [`TernaryArray2::dot_f32`]. The product is
+    ///   scaled by the weight scales. Scales per group are applied by multiplying every group
+    ///   of k on its own, which is slower (and recompresses the groups if their size isn't a
+    ///   multiple of 8).
+    pub fn dot<Rhs>(&self, rhs: &Rhs) -> <Self as Dot<Rhs>>::Output
+    where
+        Self: Dot<Rhs>,
+    {
+        Dot::dot(self, rhs)
+    }
+
+    /// Matrix product with an f32 `k x n` view like a BitNet layer, `(A * B) * w_scale + bias`
+    /// with a bias per row of A. B is quantized to int8 per column first (see
+    /// [`quantize_activations`]) and dequantized by [`gemm_int8_f32`], so the product is
+    /// approximate like in BitNet inference. Panics if the shapes, scales or bias don't match.
+    pub fn dot_f32(
+        &self,
+        rhs: &ArrayView2<'_, f32>,
+        w_scale: Scale<'_>,
+        bias: Option<&[f32]>,
+    ) -> Array2<f32> {
+        let (k, n) = rhs.dim();
+        self.check_k(k);
+        let m = self.m;
+        let x = quantize_rhs(rhs);
+
+        let mut c = vec![0.; m * n];
+        gemm_int8_f32(
+            self.layout,
+            Layout::ColMajor,
+            m,
+            n,
+            k,
+            &self.vals,
+            &self.signs,
+            self.lda(),
+            &x.values,
+            k.max(1),
+            &x.epilogue(w_scale, bias),
+            &mut c,
+            m.max(1),
+        );
+        Array2::from_shape_vec((m, n).f(), c).unwrap()
+    }
+
+    // The f32 product with scales per group of `size` k: C accumulates the product of every
+    // group of cols of A and rows of B, scaled with the group's scale of every row
+    fn dot_per_group(&self, rhs: &ArrayView2<'_, f32>, size: usize) -> Array2<f32> {
+        let (k, n) = rhs.dim();
+        self.check_k(k);
+        let (m, lda) = (self.m, self.lda());
+        let x = quantize_rhs(rhs);
+        let groups = k.div_ceil(size);
+        // Groups that don't start at a byte of the compressed bits are compressed again
+        let a = (!size.is_multiple_of(8)).then(|| self.to_array());
+
+        let mut c = vec![0.; m * n];
+        for g in 0..groups {
+            let ks = (g * size)..min(k, (g + 1) * size);
+            let scales: Vec<f32> = (0..m).map(|r| self.scales[r * groups + g]).collect();
+            let recompressed;
+            let (layout, vals, signs, lda) = match &a {
+                None => {
+                    let start = match self.layout {
+                        Layout::ColMajor => ks.start / 8 * lda,
+                        Layout::RowMajor => ks.start / 8,
+                    };
+                    (self.layout, &self.vals[start..], &self.signs[start..], lda)
+                }
+                Some(a) => {
+                    recompressed = Self::from_view(a.slice(s![.., ks.clone()]));
+                    let (vals, signs) = recompressed.compressed();
+                    (recompressed.layout, vals, signs, recompressed.lda())
+                }
+            };
+            gemm_int8_f32(
+                layout,
+                Layout::ColMajor,
+                m,
+                n,
+                ks.len(),
+                vals,
+                signs,
+                lda,
+                &x.values[ks.start..],
+                k.max(1),
+                &x.epilogue(Scale::PerChannel(&scales), None),
+                &mut c,
+                m.max(1),
+            );
+        }
+        Array2::from_shape_vec((m, n).f(), c).unwrap()
+    }
+
+    fn check_k(&self, k: usize) {
+        if k != self.k {
+            let err = TernaryError::ShapeMismatch {
+                what: "k",
+                expected: self.k,
+                found: k,
+            };
+            panic!("{}", err);
+        }
+    }
+}
+
+impl From<TernaryTensor> for TernaryArray2 {
+    fn from(tensor: TernaryTensor) -> Self {
+        Self {
+            m: tensor.m,
+            k: tensor.k,
+            layout: tensor.layout(),
+            vals: tensor.vals,
+            signs: tensor.signs,
+            granularity: tensor.granularity,
+            scales: tensor.scales,
+        }
+    }
+}
+
+impl From<&TernaryArray2> for Array2<i8> {
+    fn from(a: &TernaryArray2) -> Self {
+        a.to_array()
+    }
+}
+
+impl Dot<ArrayView2<'_, i8>> for TernaryArray2 {
+    type Output = Array2<i32>;
+
+    fn dot(&self, rhs: &ArrayView2<'_, i8>) -> Array2<i32> {
+        let (k, n) = rhs.dim();
+        self.check_k(k);
+        let (m, lda) = (self.m, self.lda());
+        let (b, b_layout) = contiguous(rhs);
+        let b = b.as_slice_memory_order().unwrap();
+        let ldb = ld(b_layout, k, n);
+
+        let mut c = vec![0; m * n];
+        // b is contiguous, so it holds exactly the values of B
+        if validate_trits(b).is_ok() {
+            let (b_vals, b_signs) = compress_b(k, n, b, b_layout, ldb);
+            ternary_gemm(
+                self.layout,
+                b_layout,
+                m,
+                n,
+                k,
+                &self.vals,
+                &self.signs,
+                lda,
+                &b_vals,
+                &b_signs,
+                ld(b_layout, k.div_ceil(8), n),
+                &mut c,
+                m.max(1),
+            );
+        } else {
+            gemm_int8(
+                self.layout,
+                b_layout,
+                m,
+                n,
+                k,
+                &self.vals,
+                &self.signs,
+                lda,
+                b,
+                ldb,
+                &mut c,
+                m.max(1),
+            );
+        }
+        Array2::from_shape_vec((m, n).f(), c).unwrap()
+    }
+}
+
+impl Dot<ArrayView2<'_, f32>> for TernaryArray2 {
+    type Output = Array2<f32>;
+
+    fn dot(&self, rhs: &ArrayView2<'_, f32>) -> Array2<f32> {
+        match self.granularity {
+            Granularity::PerTensor => self.dot_f32(rhs, Scale::PerTensor(self.scales[0]), None),
+            Granularity::PerChannel => self.dot_f32(rhs, Scale::PerChannel(&self.scales), None),
+            Granularity::PerGroup(size) => self.dot_per_group(rhs, size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2};
+
+    use crate::{
+        gemm::{quantize_weights, Granularity, Layout, Scale, TernaryError, TernaryTensor},
+        test_util::test_util::rand_vecs_sized,
+    };
+
+    use super::TernaryArray2;
+
+    #[test]
+    fn test() {
+        let (m, k, n) = (37, 70, 21);
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        let a_row = Array2::from_shape_vec((m, k), a).unwrap();
+        let a_col = a_row.t().as_standard_layout().into_owned().reversed_axes();
+        let b_row = Array2::from_shape_vec((k, n), b).unwrap();
+        let b_col = b_row.t().as_standard_layout().into_owned().reversed_axes();
+
+        let to_i32 = |x: &Array2<i8>| x.mapv(i32::from);
+        let expected = to_i32(&a_row).dot(&to_i32(&b_row));
+        for (a, layout) in [(&a_row, Layout::RowMajor), (&a_col, Layout::ColMajor)] {
+            let ternary = TernaryArray2::from_view(a.view());
+            assert_eq!((ternary.dim(), ternary.layout()), ((m, k), layout));
+            assert_eq!(ternary.to_array(), a);
+
+            // Ternary B, int8 B and a strided view
+            for b in [&b_row, &b_col] {
+                assert_eq!(ternary.dot(&b.view()), expected);
+                let mut b_int8 = b.clone();
+                b_int8[(3, 4)] = 100;
+                assert_eq!(ternary.dot(&b_int8.view()), to_i32(a).dot(&to_i32(&b_int8)));
+            }
+            let strided = b_row.slice(s![.., ..;2]);
+            assert_eq!(
+                ternary.dot(&strided),
+                to_i32(a).dot(&strided.mapv(i32::from))
+            );
+
+            // f32 B with integers up to an absmax of 127 per token quantizes exactly
+            let mut b_f32 = b_row.mapv(|x| f32::from(x) * 127.);
+            b_f32[(0, 0)] = 63.;
+            let expected = a.mapv(f32::from).dot(&b_f32);
+            let c = ternary.dot(&b_f32.view());
+            assert!(c.iter().zip(&expected).all(|(x, y)| (x - y).abs() < 1e-2));
+        }
+
+        // Row-major views of a larger array are copied first
+        let ternary = TernaryArray2::from_view(a_row.slice(s![..;2, ..]));
+        assert_eq!(ternary.to_array(), a_row.slice(s![..;2, ..]));
+    }
+
+    #[test]
+    fn test_scales() {
+        let (m, k, n) = (6, 40, 5);
+        let w: Vec<f32> = (0..(m * k)).map(|i| ((i * 7) % 5) as f32 - 2.).collect();
+        let weights = quantize_weights(m, k, &w, Layout::RowMajor, k, Granularity::PerChannel);
+        let ternary = TernaryArray2::from(TernaryTensor::from(&weights));
+        assert_eq!(
+            ternary.scales(),
+            (Granularity::PerChannel, &weights.scales[..])
+        );
+
+        let a = Array2::from_shape_vec((m, k), weights.values.clone()).unwrap();
+        let b = Array2::from_shape_fn((k, n), |(r, c)| ((r + 2 * c) % 3) as f32 - 1.);
+        let scales = Array2::from_shape_vec((m, 1), weights.scales.clone()).unwrap();
+        let product = a.mapv(f32::from).dot(&b) * &scales;
+        let close =
+            |x: &Array2<f32>, y: &Array2<f32>| x.iter().zip(y).all(|(x, y)| (x - y).abs() < 1e-3);
+        // dot applies the scales the tensor was converted with
+        assert!(close(&ternary.dot(&b.view()), &product));
+
+        // Scales per group, in groups that do and don't start at a compressed byte
+        for size in [16, 7] {
+            let granularity = Granularity::PerGroup(size);
+            let weights = quantize_weights(m, k, &w, Layout::RowMajor, k, granularity);
+            let groups = k.div_ceil(size);
+            let dequantized = Array2::from_shape_fn((m, k), |(r, c)| {
+                f32::from(weights.values[r * k + c]) * weights.scales[r * groups + c / size]
+            });
+            let ternary = TernaryArray2::from(TernaryTensor::from(&weights));
+            assert!(close(&ternary.dot(&b.view()), &dequantized.dot(&b)));
+        }
+
+        let bias: Vec<f32> = (0..m).map(|i| i as f32).collect();
+        let c = ternary.dot_f32(&b.view(), Scale::PerChannel(&weights.scales), Some(&bias));
+        let bias = Array2::from_shape_vec((m, 1), bias).unwrap();
+        assert!(close(&c, &(product + &bias)));
+    }
+
+    #[test]
+    #[should_panic(expected = "k mismatch: expected 8, found 7")]
+    fn test_dot_shape() {
+        let a = TernaryArray2::from_view(Array2::<i8>::zeros((4, 8)).view());
+        a.dot(&Array2::<i8>::zeros((7, 2)).view());
+    }
+
+    #[test]
+    fn test_try_from_view() {
+        let mut a = Array2::<i8>::zeros((3, 5));
+        a[(1, 2)] = -2;
+        assert_eq!(
+            TernaryArray2::try_from_view(a.view()),
+            Err(TernaryError::InvalidTrit {
+                index: 7,
+                value: -2
+            })
+        );
+    }
+}
@@ -0,0 +1,571 @@
+//! A versioned file format for weights packed into the panels of a microkernel, loaded by
+//! memory-mapping the file so the panels are never copied.
+//!
+//! The file starts with a 64 byte header (all integers little-endian):
+//!
+//! | offset | size | field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 8    | magic `TERNPACK`                                   |
+//! | 8      | 4    | format version, [`VERSION`]                        |
+//! | 12     | 4    | alignment of the panels in the file, [`ALIGNMENT`] |
+//! | 16     | 4    | number of matrices                                 |
+//! | 20     | 4    | reserved, 0                                        |
+//! | 24     | 8    | size of the index in bytes                         |
+//! | 32     | 8    | checksum of the index                              |
+//! | 40     | 24   | reserved, 0                                        |
+//!
+//! The index follows with an entry per matrix: its name and kernel name (u32 length + utf-8),
+//! m and k (u64), mr, mc and kc (u32), the file offsets of the val and sign panels and their
+//! length (u64) and the checksum of the panels (u64). The panels start at multiples of the
+//! alignment, so mapped panels are as aligned as the page-aligned mapping.
+//!
+//! Checksums are FNV-1a over the little-endian 8 byte words of the data (the last word zero
+//! padded), every word mixed with the splitmix64 finalizer first. Without the mixing a
+//! change only carries from low to high bits, two flips of the top bit of different words
+//! would cancel. [`MappedContainer::open`] checks the index, [`MappedContainer::matrix`] checks the
+//! panels of a matrix the first time it hands them out.
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::OnceLock,
+};
+
+use memmap2::Mmap;
+
+use crate::gemm::{Kernel, PackedTernaryMatrix, TernaryError};
+
+const MAGIC: &[u8; 8] = b"TERNPACK";
+/// Version of the container format
+pub const VERSION: u32 = 2;
+/// Alignment of the panels in the file, a cache line
+pub const ALIGNMENT: usize = 64;
+const HEADER_SIZE: usize = 64;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Errors of writing and loading containers.
+#[derive(Debug)]
+pub enum ContainerError {
+    Io(io::Error),
+    /// Not a container, an unsupported version or a truncated/corrupt file
+    Format(String),
+    /// The checksum of the index or the panels of a matrix doesn't match
+    Checksum {
+        what: String,
+    },
+    /// The matrix doesn't exist
+    MissingMatrix(String),
+    /// The panels don't fit the matrix or were packed for a kernel the CPU doesn't support
+    Ternary(TernaryError),
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::Io(err) => write!(f, "io error: {}", err),
+            ContainerError::Format(msg) => write!(f, "invalid container: {}", msg),
+            ContainerError::Checksum { what } => write!(f, "checksum mismatch of {}", what),
+            ContainerError::MissingMatrix(name) => write!(f, "no matrix named {}", name),
+            ContainerError::Ternary(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for ContainerError {}
+
+impl From<io::Error> for ContainerError {
+    fn from(err: io::Error) -> Self {
+        ContainerError::Io(err)
+    }
+}
+
+impl From<TernaryError> for ContainerError {
+    fn from(err: TernaryError) -> Self {
+        ContainerError::Ternary(err)
+    }
+}
+
+fn format_error<T>(msg: impl Into<String>) -> Result<T, ContainerError> {
+    Err(ContainerError::Format(msg.into()))
+}
+
+// splitmix64's finalizer, spreads every bit of a word over all bits
+fn mix(mut word: u64) -> u64 {
+    word = (word ^ (word >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    word = (word ^ (word >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    word ^ (word >> 31)
+}
+
+// FNV-1a over mixed 8 byte words, continuing from hash
+fn checksum(mut hash: u64, data: &[u8]) -> u64 {
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        let word = mix(u64::from_le_bytes(word.try_into().unwrap()));
+        hash = (hash ^ word).wrapping_mul(FNV_PRIME);
+    }
+    if !words.remainder().is_empty() {
+        let mut word = [0; 8];
+        word[..words.remainder().len()].copy_from_slice(words.remainder());
+        hash = (hash ^ mix(u64::from_le_bytes(word))).wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// An entry of the index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContainerEntry {
+    pub name: String,
+    /// Name of the kernel the panels were packed for, see [`Kernel::name`]
+    pub kernel: String,
+    pub m: usize,
+    pub k: usize,
+    pub mr: usize,
+    pub mc: usize,
+    pub kc: usize,
+    /// File offset of the val panels
+    pub vals_offset: usize,
+    /// File offset of the sign panels
+    pub signs_offset: usize,
+    /// Bytes of the val panels and of the sign panels
+    pub len: usize,
+    pub checksum: u64,
+}
+
+/// Writes packed matrices into a container.
+#[derive(Default)]
+pub struct ContainerWriter<'a> {
+    matrices: Vec<(String, &'a PackedTernaryMatrix<'a>)>,
+}
+
+impl<'a> ContainerWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a packed matrix
+    pub fn add(&mut self, name: &str, matrix: &'a PackedTernaryMatrix<'a>) {
+        self.matrices.push((name.to_string(), matrix));
+    }
+
+    // The index entries, with the panels laid out after the header and the index
+    fn entries(&self) -> (Vec<ContainerEntry>, Vec<u8>) {
+        let mut entries: Vec<ContainerEntry> = self
+            .matrices
+            .iter()
+            .map(|(name, matrix)| {
+                let (vals, signs) = matrix.panel_bytes();
+                ContainerEntry {
+                    name: name.clone(),
+                    kernel: matrix.kernel().name().to_string(),
+                    m: matrix.m(),
+                    k: matrix.k(),
+                    mr: matrix.mr(),
+                    mc: matrix.mc(),
+                    kc: matrix.kc(),
+                    vals_offset: 0,
+                    signs_offset: 0,
+                    len: vals.len(),
+                    checksum: checksum(checksum(FNV_OFFSET, vals), signs),
+                }
+            })
+            .collect();
+
+        // The index has a fixed size, so the offsets can be filled in afterwards
+        let index_len = encode_index(&entries).len();
+        let mut offset = (HEADER_SIZE + index_len).next_multiple_of(ALIGNMENT);
+        for entry in &mut entries {
+            entry.vals_offset = offset;
+            entry.signs_offset = (offset + entry.len).next_multiple_of(ALIGNMENT);
+            offset = (entry.signs_offset + entry.len).next_multiple_of(ALIGNMENT);
+        }
+        let index = encode_index(&entries);
+        (entries, index)
+    }
+
+    /// Writes the container, streaming the panels
+    pub fn write_to(&self, out: &mut impl Write) -> Result<(), ContainerError> {
+        let (entries, index) = self.entries();
+
+        let mut header = [0; HEADER_SIZE];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(ALIGNMENT as u32).to_le_bytes());
+        header[16..20].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&(index.len() as u64).to_le_bytes());
+        header[32..40].copy_from_slice(&checksum(FNV_OFFSET, &index).to_le_bytes());
+        out.write_all(&header)?;
+        out.write_all(&index)?;
+
+        let mut pos = HEADER_SIZE + index.len();
+        let padding = [0; ALIGNMENT];
+        for (entry, (_, matrix)) in entries.iter().zip(&self.matrices) {
+            let (vals, signs) = matrix.panel_bytes();
+            for (offset, panels) in [(entry.vals_offset, vals), (entry.signs_offset, signs)] {
+                out.write_all(&padding[..(offset - pos)])?;
+                out.write_all(panels)?;
+                pos = offset + panels.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the container to a file
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), ContainerError> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)?;
+        Ok(out.flush()?)
+    }
+}
+
+fn encode_index(entries: &[ContainerEntry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for entry in entries {
+        for string in [&entry.name, &entry.kernel] {
+            out.extend_from_slice(&(string.len() as u32).to_le_bytes());
+            out.extend_from_slice(string.as_bytes());
+        }
+        for value in [entry.m, entry.k] {
+            out.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+        for value in [entry.mr, entry.mc, entry.kc] {
+            out.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        for value in [entry.vals_offset, entry.signs_offset, entry.len] {
+            out.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+        out.extend_from_slice(&entry.checksum.to_le_bytes());
+    }
+    out
+}
+
+// Reads little-endian values from the index
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ContainerError> {
+        match self.bytes.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => format_error("unexpected end of index"),
+        }
+    }
+
+    fn u32(&mut self) -> Result<usize, ContainerError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<usize, ContainerError> {
+        let value = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        usize::try_from(value).map_or_else(|_| format_error("value out of range"), Ok)
+    }
+
+    fn string(&mut self) -> Result<String, ContainerError> {
+        let len = self.u32()?;
+        match String::from_utf8(self.take(len)?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => format_error("string is not utf-8"),
+        }
+    }
+
+    fn entry(&mut self) -> Result<ContainerEntry, ContainerError> {
+        Ok(ContainerEntry {
+            name: self.string()?,
+            kernel: self.string()?,
+            m: self.u64()?,
+            k: self.u64()?,
+            mr: self.u32()?,
+            mc: self.u32()?,
+            kc: self.u32()?,
+            vals_offset: self.u64()?,
+            signs_offset: self.u64()?,
+            len: self.u64()?,
+            checksum: u64::from_le_bytes(self.take(8)?.try_into().unwrap()),
+        })
+    }
+}
+
+/// A memory-mapped container. [`MappedContainer::matrix`] borrows the panels of a matrix
+/// straight from the mapping.
+pub struct MappedContainer {
+    /// The index in file order
+    pub entries: Vec<ContainerEntry>,
+    // Whether the checksum of the panels of each entry matches, computed on first use
+    verified: Vec<OnceLock<bool>>,
+    map: Mmap,
+}
+
+impl MappedContainer {
+    /// Maps a container and checks its header and index.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this or any other process, while the
+    /// container or a matrix borrowed from it is alive. The panels are read straight from the
+    /// mapping, a change of the file would change them under the kernels (or make reading
+    /// them fault).
+    pub unsafe fn open(path: impl AsRef<Path>) -> Result<Self, ContainerError> {
+        let file = File::open(path)?;
+        // Safety: the file is only read, and the caller guarantees nobody changes it while it
+        // is mapped
+        let map = unsafe { Mmap::map(&file)? };
+
+        let Some(header) = map.get(..HEADER_SIZE) else {
+            return format_error("missing header");
+        };
+        if &header[..8] != MAGIC {
+            return format_error("missing TERNPACK magic");
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let mut bytes = [0; 8];
+            bytes[..range.len()].copy_from_slice(&header[range]);
+            u64::from_le_bytes(bytes)
+        };
+        let version = field(8..12) as u32;
+        if version != VERSION {
+            return format_error(format!("unsupported version {}", version));
+        }
+        let alignment = field(12..16) as usize;
+        if !alignment.is_power_of_two() {
+            return format_error(format!("alignment {} is not a power of two", alignment));
+        }
+        let (count, index_len) = (field(16..20) as usize, field(24..32));
+        let Some(index) = usize::try_from(index_len)
+            .ok()
+            .and_then(|len| map.get(HEADER_SIZE..HEADER_SIZE.checked_add(len)?))
+        else {
+            return format_error("index larger than the file");
+        };
+        if checksum(FNV_OFFSET, index) != field(32..40) {
+            return Err(ContainerError::Checksum {
+                what: "index".to_string(),
+            });
+        }
+
+        let mut reader = Reader {
+            bytes: index,
+            pos: 0,
+        };
+        let entries = (0..count)
+            .map(|_| reader.entry())
+            .collect::<Result<Vec<_>, _>>()?;
+        for entry in &entries {
+            for offset in [entry.vals_offset, entry.signs_offset] {
+                if !offset.is_multiple_of(alignment) {
+                    return format_error(format!("panels of {} not aligned", entry.name));
+                }
+                if offset
+                    .checked_add(entry.len)
+                    .is_none_or(|end| end > map.len())
+                {
+                    return format_error(format!("panels of {} outside the file", entry.name));
+                }
+            }
+        }
+        let verified = entries.iter().map(|_| OnceLock::new()).collect();
+        Ok(Self {
+            entries,
+            verified,
+            map,
+        })
+    }
+
+    /// Looks up a matrix in the index
+    pub fn entry(&self, name: &str) -> Option<&ContainerEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Wraps the panels of a matrix without copying. The first call for a matrix checks the
+    /// panels, returns an error if the checksum doesn't match or the CPU doesn't support the
+    /// kernel they were packed for.
+    pub fn matrix(&self, name: &str) -> Result<PackedTernaryMatrix<'_>, ContainerError> {
+        let i = self
+            .entries
+            .iter()
+            .position(|entry| entry.name == name)
+            .ok_or_else(|| ContainerError::MissingMatrix(name.to_string()))?;
+        let entry = &self.entries[i];
+        let kernel: Kernel = match entry.kernel.parse() {
+            Ok(kernel) => kernel,
+            Err(err) => return format_error(err),
+        };
+        let vals = &self.map[entry.vals_offset..(entry.vals_offset + entry.len)];
+        let signs = &self.map[entry.signs_offset..(entry.signs_offset + entry.len)];
+        let verified = self.verified[i]
+            .get_or_init(|| checksum(checksum(FNV_OFFSET, vals), signs) == entry.checksum);
+        if !verified {
+            return Err(ContainerError::Checksum {
+                what: format!("matrix {}", name),
+            });
+        }
+
+        let matrix = PackedTernaryMatrix::try_from_panels(
+            kernel, entry.m, entry.k, entry.mc, entry.kc, vals, signs,
+        )?;
+        if matrix.mr() != entry.mr {
+            let err = TernaryError::ShapeMismatch {
+                what: "mr",
+                expected: matrix.mr(),
+                found: entry.mr,
+            };
+            return Err(err.into());
+        }
+        Ok(matrix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        gemm::{compress_b, matmul_packed, Kernel, Layout, PackedTernaryMatrix, TernaryError},
+        test_util::test_util::rand_vecs_sized,
+    };
+
+    use super::{ContainerError, ContainerWriter, MappedContainer, ALIGNMENT, HEADER_SIZE};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ternary-{}-{}.pack", std::process::id(), name))
+    }
+
+    #[test]
+    fn test() {
+        let shapes = [(70, 300, 9), (1, 1, 1), (300, 4200, 20)];
+        let matrices: Vec<_> = shapes
+            .iter()
+            .map(|&(m, k, n)| {
+                let (a, b) = rand_vecs_sized(m * k, k * n);
+                let packed = PackedTernaryMatrix::new(m, k, &a, Layout::ColMajor, m);
+                (packed, b)
+            })
+            .collect();
+
+        let mut writer = ContainerWriter::new();
+        for (i, (packed, _)) in matrices.iter().enumerate() {
+            writer.add(&format!("layer.{}", i), packed);
+        }
+        let path = temp_path("test");
+        writer.write(&path).unwrap();
+        // Safety: the file isn't changed while the container is alive
+        let container = unsafe { MappedContainer::open(&path) }.unwrap();
+
+        assert_eq!(container.entries.len(), 3);
+        for (i, ((packed, b), &(m, k, n))) in matrices.iter().zip(&shapes).enumerate() {
+            let entry = container.entry(&format!("layer.{}", i)).unwrap();
+            assert_eq!(entry.vals_offset % ALIGNMENT, 0);
+            assert_eq!(entry.signs_offset % ALIGNMENT, 0);
+
+            let mapped = container.matrix(&entry.name).unwrap();
+            assert_eq!(
+                (mapped.m(), mapped.k(), mapped.kernel()),
+                (m, k, packed.kernel())
+            );
+            // Borrowed from the mapping, not copied
+            let (vals, _) = mapped.panel_bytes();
+            let start = container.map.as_ptr() as usize + entry.vals_offset;
+            assert_eq!(vals.as_ptr() as usize, start);
+            assert_eq!(mapped.panel_bytes(), packed.panel_bytes());
+
+            let (b_vals, b_signs) = compress_b(k, n, b, Layout::ColMajor, k);
+            assert_eq!(
+                matmul_packed(&mapped, n, &b_vals, &b_signs),
+                matmul_packed(packed, n, &b_vals, &b_signs)
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let (m, k) = (40, 100);
+        let (a, _) = rand_vecs_sized(m * k, 0);
+        let packed =
+            PackedTernaryMatrix::with_kernel(Kernel::Portable, m, k, &a, Layout::RowMajor, k);
+        let mut writer = ContainerWriter::new();
+        writer.add("w", &packed);
+        let mut bytes = Vec::new();
+        writer.write_to(&mut bytes).unwrap();
+
+        let path = temp_path("errors");
+        let open = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            // Safety: the file isn't changed while the container is alive
+            unsafe { MappedContainer::open(&path) }
+        };
+
+        assert!(matches!(open(&bytes[..30]), Err(ContainerError::Format(_))));
+        assert!(matches!(open(b"GGUF"), Err(ContainerError::Format(_))));
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_SIZE + 2] ^= 1;
+        assert!(matches!(
+            open(&corrupt),
+            Err(ContainerError::Checksum { .. })
+        ));
+        assert!(matches!(
+            open(&bytes[..(bytes.len() - 1)]),
+            Err(ContainerError::Format(_))
+        ));
+
+        // Alignments that aren't a power of two or that the panels don't have
+        for alignment in [0u32, 48, 1 << 20] {
+            let mut corrupt = bytes.clone();
+            corrupt[12..16].copy_from_slice(&alignment.to_le_bytes());
+            assert!(matches!(open(&corrupt), Err(ContainerError::Format(_))));
+        }
+
+        // A flipped bit in the panels is caught when the matrix is loaded, every time
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let container = open(&corrupt).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                container.matrix("w"),
+                Err(ContainerError::Checksum { .. })
+            ));
+        }
+        assert!(matches!(
+            container.matrix("v"),
+            Err(ContainerError::MissingMatrix(_))
+        ));
+
+        // Flips of the top bits of two words cancel in plain FNV-1a over words
+        let vals_offset = container.entry("w").unwrap().vals_offset;
+        let mut corrupt = bytes.clone();
+        corrupt[vals_offset + 7] ^= 0x80;
+        corrupt[vals_offset + 15] ^= 0x80;
+        let container = open(&corrupt).unwrap();
+        assert!(matches!(
+            container.matrix("w"),
+            Err(ContainerError::Checksum { .. })
+        ));
+        fs::remove_file(&path).unwrap();
+
+        let (vals, signs) = packed.panel_bytes();
+        assert_eq!(
+            PackedTernaryMatrix::try_from_panels(
+                Kernel::Portable,
+                m + 16,
+                k,
+                packed.mc(),
+                packed.kc(),
+                vals,
+                signs
+            )
+            .err(),
+            Some(TernaryError::ShapeMismatch {
+                what: "vals",
+                expected: vals.len() + 16 * k.div_ceil(8),
+                found: vals.len()
+            })
+        );
+    }
+}
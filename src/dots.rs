@@ -1,10 +1,46 @@
-use std::{
-    arch::aarch64::{uint8x16_t, uint8x8_t, vcombine_u8, vtstq_u8},
-    simd::{num::SimdUint, Simd},
-};
+use std::simd::{num::SimdUint, Simd};
 
+// Tests one bit of a byte per lane, the first 8 lanes for the high byte and the last 8 lanes
+// for the low byte of a u16
+const SHIFTER: [u8; 16] = [
+    0b10000000, 0b01000000, 0b00100000, 0b00010000, 0b00001000, 0b00000100, 0b00000010, 0b00000001,
+    0b10000000, 0b01000000, 0b00100000, 0b00010000, 0b00001000, 0b00000100, 0b00000010, 0b00000001,
+];
+
+/// Expands the bits of a u16 (highest first) into 16 lanes, -1 where the bit is set and 0
+/// otherwise.
+#[cfg(target_arch = "aarch64")]
+unsafe fn bit_masks(bits: u16) -> Simd<i8, 16> {
+    use std::arch::aarch64::{uint8x16_t, uint8x8_t, vcombine_u8, vtstq_u8};
+
+    let shifter = uint8x16_t::from(Simd::from_array(SHIFTER));
+
+    let bits_first = uint8x8_t::from(Simd::splat((bits >> 8) as u8));
+    let bits_second = uint8x8_t::from(Simd::splat(bits as u8));
+    let bits_vec = vcombine_u8(bits_first, bits_second);
+
+    Simd::from(vtstq_u8(bits_vec, shifter)).cast::<i8>()
+}
+
+/// Portable version of [`bit_masks`] for all other targets.
+#[cfg(not(target_arch = "aarch64"))]
+unsafe fn bit_masks(bits: u16) -> Simd<i8, 16> {
+    use std::simd::cmp::SimdPartialEq;
+
+    let mut bits_vec = Simd::splat(bits as u8);
+    bits_vec[..8].fill((bits >> 8) as u8);
+
+    (bits_vec & Simd::from_array(SHIFTER))
+        .simd_ne(Simd::splat(0))
+        .to_simd()
+}
+
+/// Multiplies 16 values of a with 16 ternary values of b (highest bit first) by negating and
+/// masking a with shifted bits of b.
+/// # Safety
+/// Always safe, kept unsafe to match [`dot_masks`]
 pub unsafe fn dot_shifted_mask_multiplication(a: &[i8], b_vals: u16, b_signs: u16) -> Vec<i8> {
-    let mut output = Simd::from_slice(&a);
+    let mut output = Simd::from_slice(a);
 
     let negatives_mask = Simd::from_array([
         (b_signs & 0b1000000000000000) >> 15,
@@ -48,35 +84,24 @@ pub unsafe fn dot_shifted_mask_multiplication(a: &[i8], b_vals: u16, b_signs: u1
     ])
     .cast::<i8>();
 
-    output = output * ones_mask;
+    output *= ones_mask;
 
     output.to_array().to_vec()
 }
 
+/// Multiplies 16 values of a with 16 ternary values of b (highest bit first) using lane masks.
+/// # Safety
+/// Uses NEON intrinsics on aarch64, the CPU has to support NEON
 pub unsafe fn dot_masks(a: &[i8], b_vals: u16, b_signs: u16) -> Vec<i8> {
-    let simd = Simd::from_slice(&a);
+    let simd = Simd::from_slice(a);
     let negatives = -simd;
     let mut output = Simd::splat(0);
 
-    let shifter = uint8x16_t::from(Simd::from_array([
-        0b10000000, 0b01000000, 0b00100000, 0b00010000, 0b00001000, 0b00000100, 0b00000010,
-        0b00000001, 0b10000000, 0b01000000, 0b00100000, 0b00010000, 0b00001000, 0b00000100,
-        0b00000010, 0b00000001,
-    ]));
-
-    let b_signs_first = uint8x8_t::from(Simd::splat((b_signs >> 8) as u8));
-    let b_signs_second = uint8x8_t::from(Simd::splat(b_signs as u8));
-    let b_signs_vec = vcombine_u8(b_signs_first, b_signs_second);
-
-    let negatives_mask = Simd::from(vtstq_u8(b_signs_vec, shifter)).cast::<i8>();
+    let negatives_mask = bit_masks(b_signs);
 
     output |= negatives & negatives_mask;
 
-    let b_vals_first = uint8x8_t::from(Simd::splat((b_vals >> 8) as u8));
-    let b_vals_second = uint8x8_t::from(Simd::splat(b_vals as u8));
-    let b_vals_vec = vcombine_u8(b_vals_first, b_vals_second);
-
-    let ones_mask = Simd::from(vtstq_u8(b_vals_vec, shifter)).cast::<i8>();
+    let ones_mask = bit_masks(b_vals);
 
     output |= simd & (ones_mask ^ negatives_mask);
 
@@ -84,8 +109,8 @@ pub unsafe fn dot_masks(a: &[i8], b_vals: u16, b_signs: u16) -> Vec<i8> {
 }
 
 pub fn dot_naive(a: &[i8], b: &[i8]) -> Vec<i8> {
-    let simd_a: Simd<i8, 16> = Simd::from_slice(&a);
-    let simd_b = Simd::from_slice(&b);
+    let simd_a: Simd<i8, 16> = Simd::from_slice(a);
+    let simd_b = Simd::from_slice(b);
 
     (simd_a * simd_b).to_array().to_vec()
 }
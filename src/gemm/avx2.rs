@@ -0,0 +1,166 @@
+//! AVX2 microkernel for the compressed val/sign format.
+//!
+//! AVX2 has no per-byte popcount, so bits are counted with the nibble lookup table trick:
+//! `vpshufb` looks up the bit count of the low and high nibble of every byte in a 16 entry
+//! table. One ymm register holds one compressed k-byte of 32 rows of A, so the kernel
+//! computes 32x32 tiles of C. With only 16 ymm registers the 32 columns are processed as
+//! 4 register tiles of 32x8 i8 accumulators, which are widened into C every `I8_STEPS`
+//! steps of k, before they can overflow.
+use std::{arch::x86_64::*, cmp::min};
+
+use super::{at, Microkernel};
+
+pub const DOT32X32: Microkernel<i32> = Microkernel {
+    mr: 32,
+    nr: 32,
+    run: dot32x32,
+};
+
+// Number of k steps an i8 lane can accumulate, every step adds a value in [-8, 8]
+const I8_STEPS: usize = i8::MAX as usize / 8;
+
+#[inline]
+#[target_feature(enable = "avx2")]
+fn popcount(input: __m256i, lut: __m256i, low_mask: __m256i) -> __m256i {
+    let lo = _mm256_and_si256(input, low_mask);
+    let hi = _mm256_and_si256(_mm256_srli_epi16(input, 4), low_mask);
+    _mm256_add_epi8(_mm256_shuffle_epi8(lut, lo), _mm256_shuffle_epi8(lut, hi))
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+fn dot(
+    a_val: __m256i,
+    a_sign: __m256i,
+    b_val: __m256i,
+    b_sign: __m256i,
+    lut: __m256i,
+    low_mask: __m256i,
+) -> __m256i {
+    let val = _mm256_and_si256(a_val, b_val);
+    let sign = _mm256_and_si256(_mm256_xor_si256(a_sign, b_sign), val);
+
+    let val_count = popcount(val, lut, low_mask);
+    let sign_count = popcount(sign, lut, low_mask);
+    _mm256_sub_epi8(val_count, _mm256_add_epi8(sign_count, sign_count))
+}
+
+// Adds the 32 i8 lanes of ab to 32 consecutive i32s in c
+#[inline]
+#[target_feature(enable = "avx2")]
+fn add_widened(ab: __m256i, c: &mut [i32]) {
+    let halves = [
+        _mm256_castsi256_si128(ab),
+        _mm256_extracti128_si256::<1>(ab),
+    ];
+    for (h, half) in halves.into_iter().enumerate() {
+        let quarters = [half, _mm_srli_si128::<8>(half)];
+        for (q, quarter) in quarters.into_iter().enumerate() {
+            let c = &mut c[(h * 16 + q * 8)..(h * 16 + q * 8 + 8)];
+            // Safety: c is exactly 8 i32s long
+            unsafe {
+                let sum = _mm256_add_epi32(
+                    _mm256_loadu_si256(c.as_ptr() as *const __m256i),
+                    _mm256_cvtepi8_epi32(quarter),
+                );
+                _mm256_storeu_si256(c.as_mut_ptr() as *mut __m256i, sum);
+            }
+        }
+    }
+}
+
+/// Computes a 32x32 block of i32 C (col-major with stride ldc) from a packed 32-row panel of
+/// A and a packed 32-col panel of B, both k compressed bytes deep.
+/// # Safety
+/// The CPU has to support AVX2
+#[target_feature(enable = "avx2")]
+pub unsafe fn dot32x32(
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+    c: &mut [i32],
+    ldc: usize,
+) {
+    assert!(a_vals.len() >= 32 * k && a_signs.len() >= 32 * k);
+    assert!(b_vals.len() >= 32 * k && b_signs.len() >= 32 * k);
+
+    let lut = _mm256_setr_epi8(
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4, 0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3,
+        3, 4,
+    );
+    let low_mask = _mm256_set1_epi8(0x0f);
+
+    for k_start in (0..k).step_by(I8_STEPS) {
+        let k_end = min(k, k_start + I8_STEPS);
+
+        // One 32x8 register tile at a time
+        for nj in (0..32).step_by(8) {
+            let mut ab = [_mm256_setzero_si256(); 8];
+
+            for ki in k_start..k_end {
+                // Load one col of 32 rows of A
+                let a_val = _mm256_loadu_si256(a_vals[(ki * 32)..].as_ptr() as *const __m256i);
+                let a_sign = _mm256_loadu_si256(a_signs[(ki * 32)..].as_ptr() as *const __m256i);
+                // 8 cols of B in this register tile
+                let b_val = &b_vals[(ki * 32 + nj)..(ki * 32 + nj + 8)];
+                let b_sign = &b_signs[(ki * 32 + nj)..(ki * 32 + nj + 8)];
+
+                macro_rules! set_c {
+                    ($i:expr) => {
+                        // Broadcast ith col of B to all 32 rows
+                        let b_val_i = _mm256_set1_epi8(b_val[$i] as i8);
+                        let b_sign_i = _mm256_set1_epi8(b_sign[$i] as i8);
+
+                        let d = dot(a_val, a_sign, b_val_i, b_sign_i, lut, low_mask);
+                        ab[$i] = _mm256_add_epi8(ab[$i], d);
+                    };
+                }
+
+                set_c!(0);
+                set_c!(1);
+                set_c!(2);
+                set_c!(3);
+                set_c!(4);
+                set_c!(5);
+                set_c!(6);
+                set_c!(7);
+            }
+
+            for (i, ab_i) in ab.into_iter().enumerate() {
+                add_widened(ab_i, &mut c[at(0, nj + i, ldc)..(at(0, nj + i, ldc) + 32)]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        constants::SIZE,
+        gemm::{matmul_with, prep},
+        test_util::test_util::test_matmul_i32_shape,
+    };
+
+    use super::DOT32X32;
+
+    fn test_shape(m: usize, k: usize, n: usize) {
+        test_matmul_i32_shape(m, k, n, |a, b| {
+            let (av, asi, bv, bs) = prep(m, k, n, a, b);
+            matmul_with(DOT32X32, m, k, n, &av, &asi, &bv, &bs)
+        })
+    }
+
+    #[test]
+    fn test() {
+        if !is_x86_feature_detected!("avx2") {
+            eprintln!("skipping the AVX2 kernel test, the CPU doesn't support AVX2");
+            return;
+        }
+        test_shape(SIZE, SIZE, SIZE);
+        test_shape(37, 13, 21);
+        test_shape(20, 16384, 5);
+        test_shape(270, 4100, 260);
+    }
+}
@@ -0,0 +1,160 @@
+//! AVX-512 microkernel for the compressed val/sign format.
+//!
+//! BITALG adds a native per-byte popcount (`vpopcntb`), so the mm9 `dot` becomes an AND, a
+//! single `vpternlog` for `(a_sign ^ b_sign) & val`, two popcounts and a shift-subtract. One
+//! zmm register holds one compressed k-byte of 64 rows of A, so the kernel computes 64x32
+//! tiles of C. The 32 columns are processed as 2 register tiles of 64x16 i8 accumulators,
+//! which together with A, the broadcasts of B and temporaries fit the 32 zmm registers. The
+//! accumulators are widened into C every `I8_STEPS` steps of k, before they can overflow.
+use std::{arch::x86_64::*, cmp::min};
+
+use super::{at, Microkernel};
+
+pub const DOT64X32: Microkernel<i32> = Microkernel {
+    mr: 64,
+    nr: 32,
+    run: dot64x32,
+};
+
+// Number of k steps an i8 lane can accumulate, every step adds a value in [-8, 8]
+const I8_STEPS: usize = i8::MAX as usize / 8;
+
+/// Whether the CPU supports the features [`DOT64X32`] needs
+pub fn supported() -> bool {
+    is_x86_feature_detected!("avx512f")
+        && is_x86_feature_detected!("avx512bw")
+        && is_x86_feature_detected!("avx512bitalg")
+}
+
+#[inline]
+#[target_feature(enable = "avx512f,avx512bw,avx512bitalg")]
+fn dot(a_val: __m512i, a_sign: __m512i, b_val: __m512i, b_sign: __m512i) -> __m512i {
+    let val = _mm512_and_si512(a_val, b_val);
+    // (a_sign ^ b_sign) & val in one instruction, 0x28 is the truth table of that expression
+    let sign = _mm512_ternarylogic_epi64::<0x28>(a_sign, b_sign, val);
+
+    let val_count = _mm512_popcnt_epi8(val);
+    let sign_count = _mm512_popcnt_epi8(sign);
+    _mm512_sub_epi8(val_count, _mm512_add_epi8(sign_count, sign_count))
+}
+
+// Adds the 64 i8 lanes of ab to 64 consecutive i32s in c
+#[inline]
+#[target_feature(enable = "avx512f,avx512bw,avx512bitalg")]
+fn add_widened(ab: __m512i, c: &mut [i32]) {
+    let quarters = [
+        _mm512_extracti32x4_epi32::<0>(ab),
+        _mm512_extracti32x4_epi32::<1>(ab),
+        _mm512_extracti32x4_epi32::<2>(ab),
+        _mm512_extracti32x4_epi32::<3>(ab),
+    ];
+    for (q, quarter) in quarters.into_iter().enumerate() {
+        let c = &mut c[(q * 16)..(q * 16 + 16)];
+        // Safety: c is exactly 16 i32s long
+        unsafe {
+            let sum = _mm512_add_epi32(
+                _mm512_loadu_si512(c.as_ptr() as *const __m512i),
+                _mm512_cvtepi8_epi32(quarter),
+            );
+            _mm512_storeu_si512(c.as_mut_ptr() as *mut __m512i, sum);
+        }
+    }
+}
+
+/// Computes a 64x32 block of i32 C (col-major with stride ldc) from a packed 64-row panel of
+/// A and a packed 32-col panel of B, both k compressed bytes deep.
+/// # Safety
+/// The CPU has to support AVX-512 F, BW and BITALG
+#[target_feature(enable = "avx512f,avx512bw,avx512bitalg")]
+pub unsafe fn dot64x32(
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+    c: &mut [i32],
+    ldc: usize,
+) {
+    assert!(a_vals.len() >= 64 * k && a_signs.len() >= 64 * k);
+    assert!(b_vals.len() >= 32 * k && b_signs.len() >= 32 * k);
+
+    for k_start in (0..k).step_by(I8_STEPS) {
+        let k_end = min(k, k_start + I8_STEPS);
+
+        // One 64x16 register tile at a time
+        for nj in (0..32).step_by(16) {
+            let mut ab = [_mm512_setzero_si512(); 16];
+
+            for ki in k_start..k_end {
+                // Load one col of 64 rows of A
+                let a_val = _mm512_loadu_si512(a_vals[(ki * 64)..].as_ptr() as *const __m512i);
+                let a_sign = _mm512_loadu_si512(a_signs[(ki * 64)..].as_ptr() as *const __m512i);
+                // 16 cols of B in this register tile
+                let b_val = &b_vals[(ki * 32 + nj)..(ki * 32 + nj + 16)];
+                let b_sign = &b_signs[(ki * 32 + nj)..(ki * 32 + nj + 16)];
+
+                macro_rules! set_c {
+                    ($i:expr) => {
+                        // Broadcast ith col of B to all 64 rows
+                        let b_val_i = _mm512_set1_epi8(b_val[$i] as i8);
+                        let b_sign_i = _mm512_set1_epi8(b_sign[$i] as i8);
+
+                        let d = dot(a_val, a_sign, b_val_i, b_sign_i);
+                        ab[$i] = _mm512_add_epi8(ab[$i], d);
+                    };
+                }
+
+                set_c!(0);
+                set_c!(1);
+                set_c!(2);
+                set_c!(3);
+                set_c!(4);
+                set_c!(5);
+                set_c!(6);
+                set_c!(7);
+                set_c!(8);
+                set_c!(9);
+                set_c!(10);
+                set_c!(11);
+                set_c!(12);
+                set_c!(13);
+                set_c!(14);
+                set_c!(15);
+            }
+
+            for (i, ab_i) in ab.into_iter().enumerate() {
+                add_widened(ab_i, &mut c[at(0, nj + i, ldc)..(at(0, nj + i, ldc) + 64)]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        constants::SIZE,
+        gemm::{matmul_with, prep},
+        test_util::test_util::test_matmul_i32_shape,
+    };
+
+    use super::{supported, DOT64X32};
+
+    fn test_shape(m: usize, k: usize, n: usize) {
+        test_matmul_i32_shape(m, k, n, |a, b| {
+            let (av, asi, bv, bs) = prep(m, k, n, a, b);
+            matmul_with(DOT64X32, m, k, n, &av, &asi, &bv, &bs)
+        })
+    }
+
+    #[test]
+    fn test() {
+        if !supported() {
+            eprintln!("skipping the AVX-512 kernel test, the CPU doesn't support BITALG");
+            return;
+        }
+        test_shape(SIZE, SIZE, SIZE);
+        test_shape(37, 13, 21);
+        test_shape(20, 16384, 5);
+        test_shape(270, 4100, 260);
+    }
+}
@@ -0,0 +1,492 @@
+//! Base-3 packing of ternary weights: 5 trits per byte (3^5 = 243 <= 256), so 1.6 bits per
+//! weight instead of the 2 bits of the val/sign bitplanes.
+//!
+//! A byte stores the trits `t0..t4` of 5 consecutive k as
+//! `(t0 + 1) + 3 * (t1 + 1) + 9 * (t2 + 1) + 27 * (t3 + 1) + 81 * (t4 + 1)`, k is padded with
+//! zero trits up to a multiple of 5. Like the val/sign format, A is packed along k, so packed
+//! A is `m x ceil(k / 5)`.
+//!
+//! [`gemm_base3`] multiplies base-3 A with int8 activations. The microkernel reads the packed
+//! bytes directly and peels off one trit at a time with a multiply-shift division by 3.
+use std::{
+    cmp::min,
+    simd::{
+        num::{SimdInt, SimdUint},
+        Simd,
+    },
+};
+
+use super::{at, int8::pack_b_int8, pack::pack_a, Layout, TernaryError, KC, MC, MR, NC, NR};
+
+/// Number of trits in a byte
+pub const TRITS_PER_BYTE: usize = 5;
+
+// Number of bytes an i16 lane can accumulate before it has to be flushed into i32, every byte
+// adds 5 values in [-128, 128]
+const I16_STEPS: usize = i16::MAX as usize / (TRITS_PER_BYTE * 128);
+
+// Byte value of 5 zero trits, used to pad k
+const ZERO: u8 = 1 + 3 + 9 + 27 + 81;
+
+// Largest byte of 5 trits, 3^5 - 1
+const MAX_BYTE: u8 = 242;
+
+/// Packs a ternary `m x k` matrix A (leading dimension lda) into base-3 bytes along k.
+/// The output has the same layout, contiguous (leading dimension m for col-major and
+/// `ceil(k / 5)` for row-major). Panics on values other than -1, 0 and 1, see
+/// [`try_encode_base3`].
+pub fn encode_base3(m: usize, k: usize, a: &[i8], layout: Layout, lda: usize) -> Vec<u8> {
+    try_encode_base3(m, k, a, layout, lda).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`encode_base3`], but returns an error for invalid values or a buffer that doesn't
+/// fit the shape.
+pub fn try_encode_base3(
+    m: usize,
+    k: usize,
+    a: &[i8],
+    layout: Layout,
+    lda: usize,
+) -> Result<Vec<u8>, TernaryError> {
+    layout.validate(("a", "lda"), m, k, a.len(), lda)?;
+    let kb = k.div_ceil(TRITS_PER_BYTE);
+    let ld_out = match layout {
+        Layout::ColMajor => m,
+        Layout::RowMajor => kb,
+    };
+
+    let mut out = vec![ZERO; m * kb];
+    for r in 0..m {
+        for (bi, ks) in (0..k).step_by(TRITS_PER_BYTE).enumerate() {
+            let mut byte = 0;
+            // Horner's scheme, from the highest trit down
+            for ki in (ks..(ks + TRITS_PER_BYTE)).rev() {
+                let trit = if ki < k { a[layout.at(r, ki, lda)] } else { 0 };
+                byte = byte * 3
+                    + match trit {
+                        -1 => 0,
+                        0 => 1,
+                        1 => 2,
+                        value => {
+                            let index = layout.at(r, ki, lda);
+                            return Err(TernaryError::InvalidTrit { index, value });
+                        }
+                    };
+            }
+            out[layout.at(r, bi, ld_out)] = byte;
+        }
+    }
+    Ok(out)
+}
+
+// Checks the base-3 bytes of an m x kb A with leading dimension lda, the error has the index
+// of the first byte above 242 in a and the byte as i8
+fn validate_bytes(
+    m: usize,
+    kb: usize,
+    a: &[u8],
+    layout: Layout,
+    lda: usize,
+) -> Result<(), TernaryError> {
+    for r in 0..m {
+        for bi in 0..kb {
+            let index = layout.at(r, bi, lda);
+            if a[index] > MAX_BYTE {
+                let value = a[index] as i8;
+                return Err(TernaryError::InvalidTrit { index, value });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks base-3 A, as produced by [`encode_base3`], into a contiguous ternary `m x k`
+/// matrix in the same layout. Panics on bytes above 242, which don't encode 5 trits, see
+/// [`try_decode_base3`].
+pub fn decode_base3(m: usize, k: usize, packed: &[u8], layout: Layout) -> Vec<i8> {
+    try_decode_base3(m, k, packed, layout).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`decode_base3`], but returns an error for a buffer that doesn't fit the shape or
+/// a byte above 242. The error is an [`TernaryError::InvalidTrit`] with the index of the byte
+/// in packed and the byte as i8.
+pub fn try_decode_base3(
+    m: usize,
+    k: usize,
+    packed: &[u8],
+    layout: Layout,
+) -> Result<Vec<i8>, TernaryError> {
+    let kb = k.div_ceil(TRITS_PER_BYTE);
+    let (ld_in, ld_out) = match layout {
+        Layout::ColMajor => (m, m),
+        Layout::RowMajor => (kb, k),
+    };
+    layout.validate(("packed", "ld"), m, kb, packed.len(), ld_in.max(1))?;
+    validate_bytes(m, kb, packed, layout, ld_in)?;
+
+    let mut out = vec![0; m * k];
+    for r in 0..m {
+        for bi in 0..kb {
+            let mut byte = packed[layout.at(r, bi, ld_in)];
+            for ki in (bi * TRITS_PER_BYTE)..min(k, (bi + 1) * TRITS_PER_BYTE) {
+                out[layout.at(r, ki, ld_out)] = (byte % 3) as i8 - 1;
+                byte /= 3;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Computes a 16x16 block of i32 C (col-major with stride ldc) from a packed 16-row panel of
+/// base-3 A, `k` bytes deep, and a packed 16-col panel of int8 B, `5 * k` rows deep.
+pub fn dot16x16_base3(k: usize, a: &[u8], b: &[i8], c: &mut [i32], ldc: usize) {
+    // Load initial values from c
+    let mut ab = [Simd::<i32, 16>::splat(0); 16];
+    for (i, ab_i) in ab.iter_mut().enumerate() {
+        *ab_i = Simd::from_slice(&c[at(0, i, ldc)..(at(0, i, ldc) + 16)]);
+    }
+
+    for k_start in (0..k).step_by(I16_STEPS) {
+        let mut ab16 = [Simd::<i16, 16>::splat(0); 16];
+
+        for ki in k_start..min(k, k_start + I16_STEPS) {
+            // Load one col of 16 rows of A (=> 5*16=80 ternary values)
+            let mut a_byte =
+                Simd::<u8, 16>::from_slice(&a[(ki * 16)..(ki * 16 + 16)]).cast::<u16>();
+
+            for t in 0..TRITS_PER_BYTE {
+                // a / 3 as (a * 171) >> 9, exact for every a < 256, the remainder is the
+                // lowest trit + 1
+                let quotient = (a_byte * Simd::splat(171)) >> 9;
+                let trit = (a_byte - quotient * Simd::splat(3)).cast::<i16>() - Simd::splat(1);
+                a_byte = quotient;
+
+                // Load one row of 16 cols of B
+                let row = ki * TRITS_PER_BYTE + t;
+                let b_row = &b[(row * 16)..(row * 16 + 16)];
+
+                macro_rules! set_c {
+                    ($i:expr) => {
+                        ab16[$i] += trit * Simd::splat(b_row[$i] as i16);
+                    };
+                }
+
+                set_c!(0);
+                set_c!(1);
+                set_c!(2);
+                set_c!(3);
+                set_c!(4);
+                set_c!(5);
+                set_c!(6);
+                set_c!(7);
+                set_c!(8);
+                set_c!(9);
+                set_c!(10);
+                set_c!(11);
+                set_c!(12);
+                set_c!(13);
+                set_c!(14);
+                set_c!(15);
+            }
+        }
+
+        for (ab_i, ab16_i) in ab.iter_mut().zip(ab16) {
+            *ab_i += ab16_i.cast::<i32>();
+        }
+    }
+
+    for (i, ab_i) in ab.iter().enumerate() {
+        c[at(0, i, ldc)..(at(0, i, ldc) + 16)].copy_from_slice(&ab_i.to_array());
+    }
+}
+
+// Expects a and b to be packed, c is col-major with stride ldc
+fn inner_kernel(
+    m: usize,
+    k: usize,
+    n: usize,
+    packed_a: &[u8],
+    packed_b: &[i8],
+    c: &mut [i32],
+    ldc: usize,
+) {
+    for ni in (0..n).step_by(NR) {
+        for mi in (0..m).step_by(MR) {
+            let a = &packed_a[(mi * k)..];
+            let b = &packed_b[(ni * TRITS_PER_BYTE * k)..];
+
+            let rows = min(m - mi, MR);
+            let cols = min(n - ni, NR);
+            if rows == MR && cols == NR {
+                dot16x16_base3(k, a, b, &mut c[at(mi, ni, ldc)..], ldc);
+                continue;
+            }
+
+            // Edge tile: run the full microkernel on a padded copy and only write back the
+            // part that lies inside C
+            let mut tile = [0; MR * NR];
+            for j in 0..cols {
+                let src = at(mi, ni + j, ldc);
+                tile[at(0, j, MR)..(at(0, j, MR) + rows)].copy_from_slice(&c[src..src + rows]);
+            }
+            dot16x16_base3(k, a, b, &mut tile, MR);
+            for j in 0..cols {
+                let dst = at(mi, ni + j, ldc);
+                c[dst..dst + rows].copy_from_slice(&tile[at(0, j, MR)..(at(0, j, MR) + rows)]);
+            }
+        }
+    }
+}
+
+/// Computes `C += A * B` for base-3 ternary A (`m x k`, leading dimension lda in bytes, see
+/// [`encode_base3`]) and int8 B (`k x n`, leading dimension ldb), writing into col-major i32
+/// C with leading dimension ldc. Bytes of A above 242 aren't checked and give a wrong
+/// product, see [`try_gemm_base3`].
+#[allow(clippy::too_many_arguments)]
+pub fn gemm_base3(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a: &[u8],
+    lda: usize,
+    b: &[i8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) {
+    let kb = k.div_ceil(TRITS_PER_BYTE);
+    a_layout.check(m, kb, a.len(), lda);
+    b_layout.check(k, n, b.len(), ldb);
+    Layout::ColMajor.check(m, n, c.len(), ldc);
+    if m == 0 || n == 0 || kb == 0 {
+        return;
+    }
+
+    let mut packed_a = vec![0_u8; MC * KC];
+    let mut packed_b = vec![0_i8; TRITS_PER_BYTE * KC * NC];
+
+    // LOOP 5: Split B and C on the n-dimension into parts of nc size
+    for ni in (0..n).step_by(NC) {
+        let tile_n = min(n - ni, NC);
+
+        // LOOP 4: Split A and B on the k-dimension into parts of kc size
+        for ki in (0..kb).step_by(KC) {
+            let tile_k = min(kb - ki, KC);
+            // Rows of B in this block, the last block can end before 5 * tile_k
+            let rows_b = min(k - TRITS_PER_BYTE * ki, TRITS_PER_BYTE * tile_k);
+
+            let b_start = b_layout.at(TRITS_PER_BYTE * ki, ni, ldb);
+            pack_b_int8(
+                tile_k,
+                TRITS_PER_BYTE,
+                rows_b,
+                tile_n,
+                &b[b_start..],
+                b_layout,
+                ldb,
+                &mut packed_b,
+            );
+
+            // LOOP 3: Split A and C on the m-dimension into parts of mc
+            for mi in (0..m).step_by(MC) {
+                let tile_m = min(m - mi, MC);
+
+                let a_start = a_layout.at(mi, ki, lda);
+                pack_a(
+                    tile_m,
+                    tile_k,
+                    &a[a_start..],
+                    a_layout,
+                    lda,
+                    MR,
+                    &mut packed_a,
+                );
+
+                inner_kernel(
+                    tile_m,
+                    tile_k,
+                    tile_n,
+                    &packed_a,
+                    &packed_b,
+                    &mut c[at(mi, ni, ldc)..],
+                    ldc,
+                );
+            }
+        }
+    }
+}
+
+/// Same as [`gemm_base3`], but returns an error instead of panicking if a buffer or leading
+/// dimension doesn't fit the shapes, and rejects bytes of A above 242 (which don't encode 5
+/// trits) with [`TernaryError::InvalidTrit`].
+#[allow(clippy::too_many_arguments)]
+pub fn try_gemm_base3(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a: &[u8],
+    lda: usize,
+    b: &[i8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    let kb = k.div_ceil(TRITS_PER_BYTE);
+    a_layout.validate(("a", "lda"), m, kb, a.len(), lda)?;
+    b_layout.validate(("b", "ldb"), k, n, b.len(), ldb)?;
+    Layout::ColMajor.validate(("c", "ldc"), m, n, c.len(), ldc)?;
+    validate_bytes(m, kb, a, a_layout, lda)?;
+    gemm_base3(a_layout, b_layout, m, n, k, a, lda, b, ldb, c, ldc);
+    Ok(())
+}
+
+/// Multiplies base-3, contiguous col-major A (`m x k`, see [`encode_base3`]) with a
+/// contiguous col-major int8 B (`k x n`) into a new col-major `m x n` C.
+pub fn matmul_base3(m: usize, k: usize, n: usize, a: &[u8], b: &[i8]) -> Vec<i32> {
+    let mut c = vec![0; m * n];
+    gemm_base3(
+        Layout::ColMajor,
+        Layout::ColMajor,
+        m,
+        n,
+        k,
+        a,
+        m.max(1),
+        b,
+        k.max(1),
+        &mut c,
+        m.max(1),
+    );
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        constants::SIZE,
+        gemm::{compress_a, Layout, TernaryError},
+        test_util::test_util::{rand_int8_vecs, test_matmul_int8_shape},
+    };
+
+    use super::{
+        decode_base3, encode_base3, matmul_base3, try_decode_base3, try_encode_base3,
+        try_gemm_base3, I16_STEPS, TRITS_PER_BYTE,
+    };
+
+    #[test]
+    fn test_encode_decode() {
+        for layout in [Layout::ColMajor, Layout::RowMajor] {
+            for (m, k) in [(3, 5), (7, 13), (1, 1)] {
+                let (a, _) = rand_int8_vecs(m * k, 0);
+                let ld = match layout {
+                    Layout::ColMajor => m,
+                    Layout::RowMajor => k,
+                };
+                let packed = encode_base3(m, k, &a, layout, ld);
+                assert_eq!(packed.len(), m * k.div_ceil(5));
+                assert!(packed.iter().all(|byte| *byte < 243));
+                assert_eq!(decode_base3(m, k, &packed, layout), a);
+            }
+        }
+        // Lowest k in the lowest trit
+        assert_eq!(
+            encode_base3(1, 5, &[1, -1, 0, 0, -1], Layout::ColMajor, 1),
+            [2 + 9 + 27]
+        );
+    }
+
+    #[test]
+    fn test_try() {
+        let a = [1, 0, -1, 2, 0, 1];
+        assert_eq!(
+            try_encode_base3(2, 3, &a, Layout::RowMajor, 3),
+            Err(TernaryError::InvalidTrit { index: 3, value: 2 })
+        );
+        assert!(matches!(
+            try_encode_base3(2, 3, &a[..5], Layout::RowMajor, 3),
+            Err(TernaryError::BufferTooSmall { .. })
+        ));
+        assert_eq!(
+            try_decode_base3(2, 5, &[121, 243], Layout::ColMajor),
+            Err(TernaryError::InvalidTrit {
+                index: 1,
+                value: 243_u8 as i8
+            })
+        );
+        assert_eq!(
+            try_decode_base3(1, 5, &[242], Layout::ColMajor),
+            Ok(vec![1; 5])
+        );
+
+        // gemm: a byte above 242 in row-major A and buffers too small for the shapes
+        let (row, col) = (Layout::RowMajor, Layout::ColMajor);
+        let (b, mut c) = ([1_i8; 10], [0_i32; 4]);
+        assert_eq!(
+            try_gemm_base3(row, col, 2, 2, 5, &[121, 250], 1, &b, 5, &mut c, 2),
+            Err(TernaryError::InvalidTrit {
+                index: 1,
+                value: 250_u8 as i8
+            })
+        );
+        assert_eq!(c, [0; 4]);
+        assert!(matches!(
+            try_gemm_base3(row, col, 2, 2, 5, &[121], 1, &b, 5, &mut c, 2),
+            Err(TernaryError::BufferTooSmall { .. })
+        ));
+        assert!(matches!(
+            try_gemm_base3(row, col, 2, 2, 5, &[121, 121], 1, &b[..9], 5, &mut c, 2),
+            Err(TernaryError::BufferTooSmall { .. })
+        ));
+        assert!(matches!(
+            try_gemm_base3(row, col, 2, 2, 5, &[121, 121], 1, &b, 5, &mut c[..3], 2),
+            Err(TernaryError::BufferTooSmall { .. })
+        ));
+        // 121 is 5 zero trits, 242 five ones
+        try_gemm_base3(row, col, 2, 2, 5, &[121, 242], 1, &b, 5, &mut c, 2).unwrap();
+        assert_eq!(c, [0, 5, 0, 5]);
+    }
+
+    #[test]
+    fn test_size() {
+        // 1.6 instead of 2 bits per weight
+        let (m, k) = (64, 4000);
+        let (a, _) = rand_int8_vecs(m * k, 0);
+        let (vals, signs) = compress_a(m, k, &a, Layout::ColMajor, m);
+        let base3 = encode_base3(m, k, &a, Layout::ColMajor, m);
+        assert_eq!(base3.len() * 5, (vals.len() + signs.len()) * 4);
+    }
+
+    fn test_shape(m: usize, k: usize, n: usize) {
+        test_matmul_int8_shape(m, k, n, |a, b| {
+            let packed = encode_base3(m, k, a, Layout::ColMajor, m);
+            matmul_base3(m, k, n, &packed, b)
+        })
+    }
+
+    #[test]
+    fn test() {
+        test_shape(SIZE, SIZE, SIZE);
+        test_shape(37, 13, 21);
+        // Larger than MC, KC (in bytes) and NC
+        test_shape(270, 2600, 260);
+    }
+
+    #[test]
+    fn test_extremes() {
+        // Every i16 accumulator has to be flushed
+        let (m, k, n) = (16, TRITS_PER_BYTE * I16_STEPS * 3 + 2, 16);
+        let a: Vec<i8> = (0..m * k).map(|i| if i % m < 8 { 1 } else { -1 }).collect();
+        let b = vec![-128_i8; k * n];
+
+        let packed = encode_base3(m, k, &a, Layout::ColMajor, m);
+        let c = matmul_base3(m, k, n, &packed, &b);
+        for (i, e) in c.iter().enumerate() {
+            let sign = if i % m < 8 { -1 } else { 1 };
+            assert_eq!(*e, sign * 128 * k as i32);
+        }
+    }
+}
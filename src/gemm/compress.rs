@@ -0,0 +1,345 @@
+use std::{
+    cmp::min,
+    ops::Range,
+    simd::{cmp::SimdPartialEq, cmp::SimdPartialOrd, Select, Simd},
+};
+
+use super::{Layout, TernaryError};
+
+// Rows compressed at once when the rows are contiguous
+const ROW_LANES: usize = 16;
+// Ternary values compressed at once when k is contiguous, one u64 bitmask (8 bytes)
+const K_LANES: usize = 64;
+
+// Returns the (val, sign) bits of the ternary value at input[index]
+fn trit_bits(input: &[i8], index: usize) -> Result<(u8, u8), TernaryError> {
+    match input[index] {
+        -1 => Ok((1, 1)),
+        1 => Ok((1, 0)),
+        0 => Ok((0, 0)),
+        value => Err(TernaryError::InvalidTrit { index, value }),
+    }
+}
+
+// Checks the lanes of x, loaded from index `start` of a contiguous input
+#[inline(always)]
+fn check_trits<const N: usize>(x: Simd<i8, N>, start: usize) -> Result<(), TernaryError> {
+    let invalid = x.simd_gt(Simd::splat(1)) | x.simd_lt(Simd::splat(-1));
+    match invalid.first_set() {
+        Some(lane) => Err(TernaryError::InvalidTrit {
+            index: start + lane,
+            value: x[lane],
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that every value is -1, 0 or 1.
+pub fn validate_trits(values: &[i8]) -> Result<(), TernaryError> {
+    let mut chunks = values.chunks_exact(K_LANES);
+    for (i, chunk) in chunks.by_ref().enumerate() {
+        check_trits(Simd::<i8, K_LANES>::from_slice(chunk), i * K_LANES)?;
+    }
+    let start = values.len() - chunks.remainder().len();
+    check_trits(
+        Simd::<i8, K_LANES>::load_or_default(chunks.remainder()),
+        start,
+    )
+}
+
+// Element (r, ki) is read from input[r * rs + ki * ks] and byte (r, ki / 8) is written to
+// out[r * out_rs + (ki / 8) * out_ks], one value at a time. Only used for the rows the SIMD
+// paths don't cover.
+#[allow(clippy::too_many_arguments)]
+fn compress_scalar(
+    rows: Range<usize>,
+    k: usize,
+    input: &[i8],
+    (rs, ks): (usize, usize),
+    (out_rs, out_ks): (usize, usize),
+    vals: &mut [u8],
+    signs: &mut [u8],
+) -> Result<(), TernaryError> {
+    for ki in 0..k {
+        for ri in rows.clone() {
+            let (v, s) = trit_bits(input, ri * rs + ki * ks)?;
+            let out = ri * out_rs + (ki / 8) * out_ks;
+            vals[out] |= v << (ki % 8);
+            signs[out] |= s << (ki % 8);
+        }
+    }
+    Ok(())
+}
+
+// Rows are contiguous (col-major A, row-major B): compares 16 rows of each of the 8 values of
+// a byte and ors the masks into 16 val and sign bytes at once.
+fn compress_contiguous_rows(
+    rows: usize,
+    k: usize,
+    input: &[i8],
+    ks: usize,
+    (out_rs, out_ks): (usize, usize),
+    vals: &mut [u8],
+    signs: &mut [u8],
+) -> Result<(), TernaryError> {
+    let full = rows - rows % ROW_LANES;
+
+    for kb in 0..k.div_ceil(8) {
+        for ri in (0..full).step_by(ROW_LANES) {
+            let mut v = Simd::<u8, ROW_LANES>::splat(0);
+            let mut s = Simd::<u8, ROW_LANES>::splat(0);
+            for j in 0..min(8, k - kb * 8) {
+                let start = ri + (kb * 8 + j) * ks;
+                let x = Simd::<i8, ROW_LANES>::from_slice(&input[start..(start + ROW_LANES)]);
+                check_trits(x, start)?;
+                let (bit, zero) = (Simd::splat(1 << j), Simd::splat(0));
+                v |= x.simd_ne(Simd::splat(0)).select(bit, zero);
+                s |= x.simd_lt(Simd::splat(0)).select(bit, zero);
+            }
+
+            let out = ri * out_rs + kb * out_ks;
+            if out_rs == 1 {
+                v.copy_to_slice(&mut vals[out..(out + ROW_LANES)]);
+                s.copy_to_slice(&mut signs[out..(out + ROW_LANES)]);
+            } else {
+                for r in 0..ROW_LANES {
+                    vals[out + r * out_rs] = v[r];
+                    signs[out + r * out_rs] = s[r];
+                }
+            }
+        }
+    }
+
+    compress_scalar(full..rows, k, input, (1, ks), (out_rs, out_ks), vals, signs)
+}
+
+// k is contiguous (row-major A, col-major B): compares 64 values of a row at once, the
+// bitmasks are exactly 8 val and sign bytes with the lowest k in the lowest bit.
+fn compress_contiguous_k(
+    rows: usize,
+    k: usize,
+    input: &[i8],
+    rs: usize,
+    (out_rs, out_ks): (usize, usize),
+    vals: &mut [u8],
+    signs: &mut [u8],
+) -> Result<(), TernaryError> {
+    for ri in 0..rows {
+        let row = &input[(ri * rs)..];
+        for ki in (0..k).step_by(K_LANES) {
+            // Values past k are loaded as zeros, which pads the last byte
+            let x = if ki + K_LANES <= k {
+                Simd::<i8, K_LANES>::from_slice(&row[ki..(ki + K_LANES)])
+            } else {
+                Simd::load_or_default(&row[ki..k])
+            };
+            check_trits(x, ri * rs + ki)?;
+            let v = x.simd_ne(Simd::splat(0)).to_bitmask().to_le_bytes();
+            let s = x.simd_lt(Simd::splat(0)).to_bitmask().to_le_bytes();
+
+            let (out, bytes) = (
+                ri * out_rs + ki / 8 * out_ks,
+                min(K_LANES, k - ki).div_ceil(8),
+            );
+            if out_ks == 1 {
+                vals[out..(out + bytes)].copy_from_slice(&v[..bytes]);
+                signs[out..(out + bytes)].copy_from_slice(&s[..bytes]);
+            } else {
+                for j in 0..bytes {
+                    vals[out + j * out_ks] = v[j];
+                    signs[out + j * out_ks] = s[j];
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Compresses `rows` ternary vectors of length k into val and sign bytes along k in a single
+// pass. Element (r, ki) is read from input[r * rs + ki * ks] and byte (r, ki / 8) is written to
+// out[r * out_rs + (ki / 8) * out_ks]. If k is not a multiple of 8 the last byte is padded
+// with zeros, which never contribute to a dot product.
+fn compress_along_k(
+    rows: usize,
+    k: usize,
+    input: &[i8],
+    (rs, ks): (usize, usize),
+    out_strides: (usize, usize),
+) -> Result<(Vec<u8>, Vec<u8>), TernaryError> {
+    let kb = k.div_ceil(8);
+    let mut vals = vec![0_u8; rows * kb];
+    let mut signs = vec![0_u8; rows * kb];
+
+    if rs == 1 {
+        compress_contiguous_rows(rows, k, input, ks, out_strides, &mut vals, &mut signs)?;
+    } else if ks == 1 {
+        compress_contiguous_k(rows, k, input, rs, out_strides, &mut vals, &mut signs)?;
+    } else {
+        let strides = (rs, ks);
+        compress_scalar(
+            0..rows,
+            k,
+            input,
+            strides,
+            out_strides,
+            &mut vals,
+            &mut signs,
+        )?;
+    }
+
+    Ok((vals, signs))
+}
+
+/// Compresses an `m x k` ternary matrix A with leading dimension lda into `m x ceil(k / 8)`
+/// val and sign matrices. The output keeps the layout of the input and is contiguous, so its
+/// leading dimension is `m` for col-major and `ceil(k / 8)` for row-major. Panics on values
+/// other than -1, 0 and 1, see [`try_compress_a`].
+pub fn compress_a(m: usize, k: usize, a: &[i8], layout: Layout, lda: usize) -> (Vec<u8>, Vec<u8>) {
+    try_compress_a(m, k, a, layout, lda).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`compress_a`], but returns an error for invalid values or a buffer that doesn't
+/// fit the shape.
+pub fn try_compress_a(
+    m: usize,
+    k: usize,
+    a: &[i8],
+    layout: Layout,
+    lda: usize,
+) -> Result<(Vec<u8>, Vec<u8>), TernaryError> {
+    layout.validate(("a", "lda"), m, k, a.len(), lda)?;
+    let kb = k.div_ceil(8);
+    match layout {
+        Layout::ColMajor => compress_along_k(m, k, a, (1, lda), (1, m)),
+        Layout::RowMajor => compress_along_k(m, k, a, (lda, 1), (kb, 1)),
+    }
+}
+
+/// Compresses a `k x n` ternary matrix B with leading dimension ldb into `ceil(k / 8) x n`
+/// val and sign matrices. The output keeps the layout of the input and is contiguous, so its
+/// leading dimension is `ceil(k / 8)` for col-major and `n` for row-major. Panics on values
+/// other than -1, 0 and 1, see [`try_compress_b`].
+pub fn compress_b(k: usize, n: usize, b: &[i8], layout: Layout, ldb: usize) -> (Vec<u8>, Vec<u8>) {
+    try_compress_b(k, n, b, layout, ldb).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`compress_b`], but returns an error for invalid values or a buffer that doesn't
+/// fit the shape.
+pub fn try_compress_b(
+    k: usize,
+    n: usize,
+    b: &[i8],
+    layout: Layout,
+    ldb: usize,
+) -> Result<(Vec<u8>, Vec<u8>), TernaryError> {
+    layout.validate(("b", "ldb"), k, n, b.len(), ldb)?;
+    let kb = k.div_ceil(8);
+    match layout {
+        Layout::ColMajor => compress_along_k(n, k, b, (ldb, 1), (kb, 1)),
+        Layout::RowMajor => compress_along_k(n, k, b, (1, ldb), (1, n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::test_util::rand_vecs_sized;
+
+    use super::{
+        compress_a, compress_along_k, compress_b, compress_scalar, try_compress_a, try_compress_b,
+        validate_trits, Layout, TernaryError,
+    };
+
+    #[test]
+    fn test_compress_pads_k() {
+        // 2x3 col-major: rows [1, -1, 0] and [0, 1, -1]
+        let a = [1, 0, -1, 1, 0, -1];
+        let (vals, signs) = compress_a(2, 3, &a, Layout::ColMajor, 2);
+        assert_eq!(vals, [0b011, 0b110]);
+        assert_eq!(signs, [0b010, 0b100]);
+
+        // The transpose as a 3x2 col-major B gives the same bytes
+        let b = [1, -1, 0, 0, 1, -1];
+        let (vals, signs) = compress_b(3, 2, &b, Layout::ColMajor, 3);
+        assert_eq!(vals, [0b011, 0b110]);
+        assert_eq!(signs, [0b010, 0b100]);
+    }
+
+    #[test]
+    fn test_compress_strided() {
+        // Same 2x3 A, row-major with a padding column
+        let a = [1, -1, 0, 9, 0, 1, -1, 9];
+        let (vals, signs) = compress_a(2, 3, &a, Layout::RowMajor, 4);
+        assert_eq!(vals, [0b011, 0b110]);
+        assert_eq!(signs, [0b010, 0b100]);
+    }
+
+    #[test]
+    fn test_compress_simd() {
+        // Tails of the 16 row and 64 value SIMD paths, strided inputs and both layouts
+        for (rows, k, pad) in [(1, 1, 0), (16, 64, 0), (37, 70, 3), (50, 200, 1), (3, 9, 2)] {
+            let (input, _) = rand_vecs_sized((rows + pad) * (k + pad), 0);
+            let kb = k.div_ceil(8);
+
+            // Rows or k contiguous in the input, both output layouts
+            let outputs = [(1, rows), (kb, 1)];
+            for in_strides in [(1, rows + pad), (k + pad, 1)] {
+                for out_strides in outputs {
+                    let (mut vals, mut signs) = (vec![0; rows * kb], vec![0; rows * kb]);
+                    let (vs, ss) = (&mut vals, &mut signs);
+                    compress_scalar(0..rows, k, &input, in_strides, out_strides, vs, ss).unwrap();
+
+                    let compressed = compress_along_k(rows, k, &input, in_strides, out_strides);
+                    let shape = (rows, k, in_strides, out_strides);
+                    assert_eq!(compressed, Ok((vals, signs)), "{:?}", shape);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not a ternary value")]
+    fn test_compress_invalid() {
+        let mut a = vec![0; 32 * 8];
+        a[100] = 2;
+        compress_a(32, 8, &a, Layout::ColMajor, 32);
+    }
+
+    #[test]
+    fn test_try_compress() {
+        // One invalid value for the 16-row, 64-value and scalar paths
+        let (m, k) = (20, 70);
+        for index in [100, m * k - 1, 19] {
+            let mut a = vec![1; m * k];
+            a[index] = -3;
+            let err = TernaryError::InvalidTrit { index, value: -3 };
+            assert_eq!(
+                try_compress_a(m, k, &a, Layout::ColMajor, m),
+                Err(err.clone())
+            );
+            assert_eq!(
+                try_compress_b(k, m, &a, Layout::ColMajor, k),
+                Err(err.clone())
+            );
+            assert_eq!(validate_trits(&a), Err(err));
+        }
+        assert_eq!(validate_trits(&[1, 0, -1]), Ok(()));
+
+        let a = vec![0; m * k];
+        assert_eq!(
+            try_compress_a(m, k, &a[1..], Layout::ColMajor, m),
+            Err(TernaryError::BufferTooSmall {
+                what: "a",
+                len: m * k - 1,
+                required: m * k
+            })
+        );
+        assert_eq!(
+            try_compress_b(k, m, &a, Layout::RowMajor, m - 1),
+            Err(TernaryError::ShapeMismatch {
+                what: "ldb",
+                expected: m,
+                found: m - 1
+            })
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Runtime selection of the i32 microkernel used by [`super::ternary_gemm`].
+//!
+//! By default the fastest kernel the CPU supports is picked on first use, so one binary runs
+//! on mixed fleets. The `TERNARY_KERNEL` env var (e.g. `TERNARY_KERNEL=avx2`) or
+//! [`set_kernel`] force a specific kernel instead.
+use std::{
+    env, fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+};
+
+use super::{pick, Microkernel, DOT16X16_I32, DOT32X8_I32, DOT64X4_I32};
+
+/// Env var that forces a kernel by name, see [`Kernel::name`].
+pub const KERNEL_ENV: &str = "TERNARY_KERNEL";
+
+/// The i32 microkernels, from slowest to fastest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    /// 16x16 tiles in portable SIMD, runs everywhere
+    Portable,
+    /// 16x16 tiles with NEON `vcnt` (aarch64)
+    Neon,
+    /// 32x32 tiles with `vpshufb` nibble popcounts (x86_64 AVX2)
+    Avx2,
+    /// 64x32 tiles with `vpopcntb` (x86_64 AVX-512 F, BW and BITALG)
+    Avx512,
+}
+
+impl Kernel {
+    pub const ALL: [Kernel; 4] = [Kernel::Portable, Kernel::Neon, Kernel::Avx2, Kernel::Avx512];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kernel::Portable => "portable",
+            Kernel::Neon => "neon",
+            Kernel::Avx2 => "avx2",
+            Kernel::Avx512 => "avx512",
+        }
+    }
+
+    /// Whether the kernel is compiled in and the CPU supports it
+    pub fn is_supported(self) -> bool {
+        match self {
+            Kernel::Portable => true,
+            #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+            Kernel::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => super::avx512::supported(),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// The fastest kernel the CPU supports
+    pub fn detect() -> Kernel {
+        Kernel::ALL
+            .into_iter()
+            .rev()
+            .find(|kernel| kernel.is_supported())
+            .unwrap_or(Kernel::Portable)
+    }
+
+    // Only call for supported kernels
+    pub(super) fn microkernel(self) -> Microkernel<i32> {
+        assert!(self.is_supported(), "kernel {} is not supported", self);
+        match self {
+            #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+            Kernel::Neon => super::NEON16X16_I32,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => super::avx2::DOT32X32,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => super::avx512::DOT64X32,
+            _ => DOT16X16_I32,
+        }
+    }
+
+    // The microkernel for a B with n cols. The 16x16 kernels switch to 64x4 or 32x8 tiles for
+    // small batches. There are no small-batch tiles for the AVX2 and AVX-512 kernels, they
+    // always use their full tiles.
+    pub(super) fn microkernel_for(self, n: usize) -> Microkernel<i32> {
+        let kernel = self.microkernel();
+        match self {
+            Kernel::Portable => pick(n, kernel, [DOT64X4_I32, DOT32X8_I32]),
+            #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+            Kernel::Neon => pick(n, kernel, [super::NEON64X4_I32, super::NEON32X8_I32]),
+            _ => kernel,
+        }
+    }
+}
+
+impl fmt::Display for Kernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Kernel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Kernel::ALL
+            .into_iter()
+            .find(|kernel| kernel.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("unknown kernel {:?}", s))
+    }
+}
+
+// 0 while no kernel is selected, the index of the kernel in `Kernel::ALL` + 1 otherwise
+static SELECTED: AtomicU8 = AtomicU8::new(0);
+
+/// Forces [`super::ternary_gemm`] to use the given kernel, `None` goes back to the env var or
+/// detection. Panics if the CPU doesn't support the kernel.
+pub fn set_kernel(kernel: Option<Kernel>) {
+    let selected = match kernel {
+        Some(kernel) => {
+            assert!(kernel.is_supported(), "kernel {} is not supported", kernel);
+            kernel as u8 + 1
+        }
+        None => 0,
+    };
+    SELECTED.store(selected, Ordering::Relaxed);
+}
+
+// The kernel named in `TERNARY_KERNEL` or the detected one, read once
+static DEFAULT: OnceLock<Kernel> = OnceLock::new();
+
+// The kernel named by the env var, warns and falls back to detection if it is unknown or
+// unsupported
+fn kernel_from_env(name: Option<&str>) -> Kernel {
+    let Some(name) = name else {
+        return Kernel::detect();
+    };
+    let kernel = name.parse::<Kernel>().and_then(|kernel| {
+        if kernel.is_supported() {
+            Ok(kernel)
+        } else {
+            Err(format!("kernel {} is not supported", kernel))
+        }
+    });
+    kernel.unwrap_or_else(|err| {
+        let detected = Kernel::detect();
+        eprintln!("ignoring {}: {}, using {}", KERNEL_ENV, err, detected);
+        detected
+    })
+}
+
+/// The kernel [`super::ternary_gemm`] uses: the one set with [`set_kernel`], otherwise the one
+/// named in `TERNARY_KERNEL`, otherwise [`Kernel::detect`]. The env var is read on first use,
+/// an unknown or unsupported kernel in it is ignored with a warning.
+pub fn selected_kernel() -> Kernel {
+    match SELECTED.load(Ordering::Relaxed) {
+        0 => *DEFAULT.get_or_init(|| kernel_from_env(env::var(KERNEL_ENV).ok().as_deref())),
+        selected => Kernel::ALL[selected as usize - 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gemm::{matmul_with, prep},
+        test_util::test_util::test_matmul_i32_shape,
+    };
+
+    use super::{kernel_from_env, Kernel};
+
+    #[test]
+    fn test_names() {
+        for kernel in Kernel::ALL {
+            assert_eq!(kernel.name().parse::<Kernel>(), Ok(kernel));
+        }
+        assert_eq!(" AVX2".parse::<Kernel>(), Ok(Kernel::Avx2));
+        assert!("sse".parse::<Kernel>().is_err());
+    }
+
+    #[test]
+    fn test_detect() {
+        assert!(Kernel::Portable.is_supported());
+        assert!(Kernel::detect().is_supported());
+        #[cfg(target_arch = "x86_64")]
+        assert!(!Kernel::Neon.is_supported());
+    }
+
+    #[test]
+    fn test_supported_kernels() {
+        let (m, k, n) = (70, 300, 45);
+        for kernel in Kernel::ALL
+            .into_iter()
+            .filter(|kernel| kernel.is_supported())
+        {
+            test_matmul_i32_shape(m, k, n, |a, b| {
+                let (av, asi, bv, bs) = prep(m, k, n, a, b);
+                matmul_with(kernel.microkernel(), m, k, n, &av, &asi, &bv, &bs)
+            });
+            // Small batches, which may pick a different tile
+            for n in [3, 8] {
+                test_matmul_i32_shape(m, k, n, |a, b| {
+                    let (av, asi, bv, bs) = prep(m, k, n, a, b);
+                    matmul_with(kernel.microkernel_for(n), m, k, n, &av, &asi, &bv, &bs)
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn test_kernel_from_env() {
+        assert_eq!(kernel_from_env(None), Kernel::detect());
+        assert_eq!(kernel_from_env(Some("portable")), Kernel::Portable);
+        // Typos and unsupported kernels fall back to detection
+        assert_eq!(kernel_from_env(Some("avx3")), Kernel::detect());
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(kernel_from_env(Some("neon")), Kernel::detect());
+    }
+}
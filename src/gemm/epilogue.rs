@@ -0,0 +1,122 @@
+//! Fused dequantization of [`super::gemm_int8_f32`]. BitNet b1.58 layers compute
+//! `y = (W * x) * (w_scale * x_scale) + bias` from ternary weights and int8 activations. The
+//! microkernel applies the scales and the bias when it stores its i32 dot products, so C is
+//! written as f32 in a single pass.
+use std::{
+    cmp::min,
+    simd::{num::SimdInt, Simd},
+};
+
+use super::{at, int8::Store, TernaryError};
+
+/// A quantization scale, either one for the whole tensor or one per channel: per row of the
+/// weights A or per column (token) of the activations B.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scale<'a> {
+    PerTensor(f32),
+    PerChannel(&'a [f32]),
+}
+
+impl Scale<'_> {
+    // Scale of channel i
+    fn at(self, i: usize) -> f32 {
+        match self {
+            Scale::PerTensor(scale) => scale,
+            // Only read for channels past the matrix by padded edge tiles
+            Scale::PerChannel(scales) => scales.get(i).copied().unwrap_or(0.),
+        }
+    }
+
+    // Scales of the 16 channels from start, zero past the end
+    fn lanes(self, start: usize) -> Simd<f32, 16> {
+        match self {
+            Scale::PerTensor(scale) => Simd::splat(scale),
+            Scale::PerChannel(scales) => {
+                let start = min(start, scales.len());
+                Simd::load_or_default(&scales[start..min(start + 16, scales.len())])
+            }
+        }
+    }
+
+    fn validate(self, what: &'static str, channels: usize) -> Result<(), TernaryError> {
+        match self {
+            Scale::PerChannel(scales) if scales.len() < channels => {
+                Err(TernaryError::BufferTooSmall {
+                    what,
+                    len: scales.len(),
+                    required: channels,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What [`super::gemm_int8_f32`] applies to the i32 dot products of an `m x n` block of C:
+/// `C += (A * B) * (w_scale * x_scale) + bias`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Epilogue<'a> {
+    /// Scale of the ternary weights, per tensor or per row of A (m scales)
+    pub w_scale: Scale<'a>,
+    /// Scale of the int8 activations, per tensor or per column of B (n scales, one per token)
+    pub x_scale: Scale<'a>,
+    /// Optional bias per row of A (m values)
+    pub bias: Option<&'a [f32]>,
+}
+
+impl Epilogue<'_> {
+    /// No scaling and no bias, C is the exact product converted to f32
+    pub const IDENTITY: Epilogue<'static> = Epilogue {
+        w_scale: Scale::PerTensor(1.),
+        x_scale: Scale::PerTensor(1.),
+        bias: None,
+    };
+
+    // Checks that the per-channel scales and the bias cover an m x n C
+    pub(super) fn validate(&self, m: usize, n: usize) -> Result<(), TernaryError> {
+        self.w_scale.validate("w_scale", m)?;
+        self.x_scale.validate("x_scale", n)?;
+        match self.bias {
+            Some(bias) if bias.len() < m => Err(TernaryError::BufferTooSmall {
+                what: "bias",
+                len: bias.len(),
+                required: m,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Dequantizes a single dot product of row r and col c
+    pub(super) fn apply(&self, r: usize, c: usize, dot: i32) -> f32 {
+        let bias = self.bias.map_or(0., |bias| bias[r]);
+        dot as f32 * (self.w_scale.at(r) * self.x_scale.at(c)) + bias
+    }
+}
+
+impl Store for Epilogue<'_> {
+    type Elem = f32;
+
+    #[inline(always)]
+    fn store(
+        &self,
+        ab: &[Simd<i32, 16>; 16],
+        (row, col): (usize, usize),
+        first: bool,
+        c: &mut [f32],
+        ldc: usize,
+    ) {
+        let w_scale = self.w_scale.lanes(row);
+        // The kc blocks of k add up in C, only the first one adds the bias
+        let bias = match self.bias {
+            Some(bias) if first => Scale::PerChannel(bias).lanes(row),
+            _ => Simd::splat(0.),
+        };
+
+        for (i, ab_i) in ab.iter().enumerate() {
+            let scale = w_scale * Simd::splat(self.x_scale.at(col + i));
+            let c_i = &mut c[at(0, i, ldc)..(at(0, i, ldc) + 16)];
+            let out = Simd::from_slice(c_i) + (ab_i.cast::<f32>() * scale + bias);
+            c_i.copy_from_slice(&out.to_array());
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! The error type of the fallible `try_` functions, which validate weights and operands up
+//! front instead of panicking halfway through a multiplication.
+use std::{error::Error, fmt};
+
+use super::Kernel;
+
+/// Why malformed weights or operands were rejected by the `try_` functions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TernaryError {
+    /// A value other than -1, 0 or 1 at `index` of the input buffer
+    InvalidTrit { index: usize, value: i8 },
+    /// A dimension that doesn't fit the operation, e.g. `lda` smaller than the matrix (then
+    /// `expected` is the minimum) or a matrix packed for another blocking
+    ShapeMismatch {
+        what: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    /// k has to be a multiple of `multiple` for this kernel or format
+    MisalignedK { k: usize, multiple: usize },
+    /// A buffer with fewer than `required` elements
+    BufferTooSmall {
+        what: &'static str,
+        len: usize,
+        required: usize,
+    },
+    /// Weights packed for a kernel the CPU doesn't support
+    UnsupportedKernel { kernel: Kernel },
+}
+
+impl fmt::Display for TernaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TernaryError::InvalidTrit { index, value } => {
+                write!(f, "not a ternary value: {} at index {}", value, index)
+            }
+            TernaryError::ShapeMismatch {
+                what,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} mismatch: expected {}, found {}",
+                what, expected, found
+            ),
+            TernaryError::MisalignedK { k, multiple } => {
+                write!(f, "k = {} is not a multiple of {}", k, multiple)
+            }
+            TernaryError::BufferTooSmall {
+                what,
+                len,
+                required,
+            } => write!(
+                f,
+                "{} buffer too small: {} elements, needs {}",
+                what, len, required
+            ),
+            TernaryError::UnsupportedKernel { kernel } => {
+                write!(f, "kernel {} is not supported", kernel)
+            }
+        }
+    }
+}
+
+impl Error for TernaryError {}
@@ -0,0 +1,361 @@
+//! Compressed ternary A times an int8 vector with i32 accumulation, the n = 1 case of
+//! [`super::gemm_int8`] during token-by-token decoding.
+//!
+//! With a single col of B the 16x16 microkernels waste 15/16 of their work and packing is
+//! pure overhead. Instead, every compressed byte of A is loaded exactly once, straight from
+//! the caller's buffer, so the product is bound by memory bandwidth. The rows are split
+//! between [`super::threads`] threads of the rayon pool.
+//!
+//! Col-major A is vectorized over 16 rows with broadcast activations, like `dot16x16_int8`.
+//! Row-major A (the usual layout of weights) is vectorized along k: x is reordered once so the
+//! activations of bit i of 16 consecutive bytes of a row are contiguous.
+use std::{
+    cmp::min,
+    simd::{
+        num::{SimdInt, SimdUint},
+        Simd,
+    },
+};
+
+use super::{int8::I16_STEPS, threads, threads::split, Layout, TernaryError, MC};
+
+// Bytes of a row of row-major A per vector
+const LANES: usize = 16;
+
+// Lane masks of one bit of 16 bytes of A (widened to i16), -1 where the weight is non-zero /
+// negative. Shifts the bit into the sign bit and spreads it over the lane with an arithmetic
+// shift, which is cheaper than a compare and a widening of the mask.
+#[inline(always)]
+fn masks(vals: Simd<i16, 16>, signs: Simd<i16, 16>, bit: usize) -> (Simd<i16, 16>, Simd<i16, 16>) {
+    let shift = Simd::splat(15 - bit as i16);
+    let spread = Simd::splat(15);
+    ((vals << shift) >> spread, (signs << shift) >> spread)
+}
+
+// Negates (two's complement) and masks the activations like dot_shifted_mask_multiplication
+#[inline(always)]
+fn mul(x: Simd<i16, 16>, keep: Simd<i16, 16>, neg: Simd<i16, 16>) -> Simd<i16, 16> {
+    ((x ^ neg) - neg) & keep
+}
+
+// y[r] += A[r, :] * x for col-major A with its first row at a_vals[0]. x is padded to 8 * kb.
+fn gemv_col_major(kb: usize, a_vals: &[u8], a_signs: &[u8], lda: usize, x: &[i16], y: &mut [i32]) {
+    let rows = y.len();
+    let mut acc = vec![Simd::<i16, 16>::splat(0); rows.div_ceil(16)];
+
+    for k_start in (0..kb).step_by(I16_STEPS) {
+        acc.fill(Simd::splat(0));
+
+        for ki in k_start..min(kb, k_start + I16_STEPS) {
+            let col_vals = &a_vals[(ki * lda)..(ki * lda + rows)];
+            let col_signs = &a_signs[(ki * lda)..(ki * lda + rows)];
+            let xs: [Simd<i16, 16>; 8] = std::array::from_fn(|bit| Simd::splat(x[ki * 8 + bit]));
+
+            for (ri, acc_i) in acc.iter_mut().enumerate() {
+                let end = min(rows, ri * 16 + 16);
+                let vals = Simd::<u8, 16>::load_or_default(&col_vals[(ri * 16)..end]).cast();
+                let signs = Simd::<u8, 16>::load_or_default(&col_signs[(ri * 16)..end]).cast();
+                for (bit, x_bit) in xs.iter().enumerate() {
+                    let (keep, neg) = masks(vals, signs, bit);
+                    *acc_i += mul(*x_bit, keep, neg);
+                }
+            }
+        }
+
+        for (y_i, acc_i) in y.chunks_mut(16).zip(&acc) {
+            for (e, a) in y_i.iter_mut().zip(acc_i.to_array()) {
+                *e += a as i32;
+            }
+        }
+    }
+}
+
+// Reorders x (padded with zeros) into chunks of 16 bytes of A, chunk c holds for every bit i
+// the activations x[128 * c + 8 * j + i] of the 16 bytes j
+fn transpose_x(kb: usize, x: &[i8]) -> Vec<i16> {
+    let chunks = kb.div_ceil(LANES);
+    let mut xt = vec![0; chunks * LANES * 8];
+    for (ki, e) in x.iter().enumerate() {
+        let (c, j, bit) = (ki / (LANES * 8), ki / 8 % LANES, ki % 8);
+        xt[c * LANES * 8 + bit * LANES + j] = *e as i16;
+    }
+    xt
+}
+
+// y[r] += A[r, :] * x for row-major A with its first row at a_vals[0], x from `transpose_x`
+fn gemv_row_major(kb: usize, a_vals: &[u8], a_signs: &[u8], lda: usize, xt: &[i16], y: &mut [i32]) {
+    let chunks = kb.div_ceil(LANES);
+
+    for (r, y_r) in y.iter_mut().enumerate() {
+        let row_vals = &a_vals[(r * lda)..(r * lda + kb)];
+        let row_signs = &a_signs[(r * lda)..(r * lda + kb)];
+        let mut sum = Simd::<i32, 16>::splat(0);
+
+        for c_start in (0..chunks).step_by(I16_STEPS) {
+            let mut acc = Simd::<i16, 16>::splat(0);
+            for c in c_start..min(chunks, c_start + I16_STEPS) {
+                let end = min(kb, c * LANES + LANES);
+                let vals = Simd::<u8, 16>::load_or_default(&row_vals[(c * LANES)..end]).cast();
+                let signs = Simd::<u8, 16>::load_or_default(&row_signs[(c * LANES)..end]).cast();
+                for bit in 0..8 {
+                    let start = c * LANES * 8 + bit * LANES;
+                    let x_bit = Simd::from_slice(&xt[start..(start + LANES)]);
+                    let (keep, neg) = masks(vals, signs, bit);
+                    acc += mul(x_bit, keep, neg);
+                }
+            }
+            sum += acc.cast::<i32>();
+        }
+
+        *y_r += sum.reduce_sum();
+    }
+}
+
+// Splits the rows of y between `threads` threads, x is prepared for the layout of A
+#[allow(clippy::too_many_arguments)]
+fn gemv_with(
+    threads: usize,
+    a_layout: Layout,
+    m: usize,
+    kb: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    x: &[i16],
+    y: &mut [i32],
+) {
+    let run = |start: usize, y: &mut [i32]| {
+        let a_start = a_layout.at(start, 0, lda);
+        let (a_vals, a_signs) = (&a_vals[a_start..], &a_signs[a_start..]);
+        match a_layout {
+            Layout::ColMajor => gemv_col_major(kb, a_vals, a_signs, lda, x, y),
+            Layout::RowMajor => gemv_row_major(kb, a_vals, a_signs, lda, x, y),
+        }
+    };
+
+    // Don't wake threads for less than MC rows each
+    let parts = threads.min(m.div_ceil(MC));
+    if parts <= 1 {
+        run(0, &mut y[..m]);
+        return;
+    }
+
+    rayon::scope(|s| {
+        let mut rest = &mut y[..m];
+        for range in split(m, MC, parts) {
+            let (part, tail) = rest.split_at_mut(range.len());
+            rest = tail;
+            let run = &run;
+            s.spawn(move |_| run(range.start, part));
+        }
+    });
+}
+
+/// Computes `y += A * x` for compressed ternary A (`m x k`, leading dimension lda in
+/// compressed bytes) and an int8 vector x of length k, writing into the i32 vector y of
+/// length m. Splits the rows between [`threads`] threads.
+#[allow(clippy::too_many_arguments)]
+pub fn gemv_int8(
+    a_layout: Layout,
+    m: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    x: &[i8],
+    y: &mut [i32],
+) {
+    let kb = k.div_ceil(8);
+    a_layout.check(m, kb, a_vals.len().min(a_signs.len()), lda);
+    assert!(x.len() >= k, "vector buffer too small");
+    assert!(y.len() >= m, "vector buffer too small");
+    if m == 0 || kb == 0 {
+        return;
+    }
+
+    let x = match a_layout {
+        // Padded to whole bytes of A
+        Layout::ColMajor => {
+            let mut xs = vec![0; 8 * kb];
+            for (e, xi) in xs.iter_mut().zip(&x[..k]) {
+                *e = *xi as i16;
+            }
+            xs
+        }
+        Layout::RowMajor => transpose_x(kb, &x[..k]),
+    };
+    gemv_with(threads(), a_layout, m, kb, a_vals, a_signs, lda, &x, y);
+}
+
+/// Same as [`gemv_int8`], but returns an error instead of panicking if a buffer or leading
+/// dimension doesn't fit the shapes.
+#[allow(clippy::too_many_arguments)]
+pub fn try_gemv_int8(
+    a_layout: Layout,
+    m: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    x: &[i8],
+    y: &mut [i32],
+) -> Result<(), TernaryError> {
+    let kb = k.div_ceil(8);
+    a_layout.validate(("a_vals", "lda"), m, kb, a_vals.len(), lda)?;
+    a_layout.validate(("a_signs", "lda"), m, kb, a_signs.len(), lda)?;
+    for (what, len, required) in [("x", x.len(), k), ("y", y.len(), m)] {
+        if len < required {
+            return Err(TernaryError::BufferTooSmall {
+                what,
+                len,
+                required,
+            });
+        }
+    }
+    gemv_int8(a_layout, m, k, a_vals, a_signs, lda, x, y);
+    Ok(())
+}
+
+/// Multiplies compressed, contiguous col-major A (`m x k`, see [`super::compress_a`]) with an
+/// int8 vector x of length k into a new vector of length m.
+pub fn matvec_int8(m: usize, k: usize, a_vals: &[u8], a_signs: &[u8], x: &[i8]) -> Vec<i32> {
+    let mut y = vec![0; m];
+    gemv_int8(Layout::ColMajor, m, k, a_vals, a_signs, m.max(1), x, &mut y);
+    y
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array1, Array2, ShapeBuilder};
+
+    use crate::{
+        gemm::{compress_a, Layout, TernaryError},
+        test_util::test_util::{rand_int8_vecs, test_matmul_int8_shape},
+    };
+
+    use super::{gemv_int8, gemv_with, matvec_int8, transpose_x, try_gemv_int8};
+
+    #[test]
+    fn test() {
+        for (m, k) in [(1, 1), (37, 13), (96, 250), (600, 4100)] {
+            test_matmul_int8_shape(m, k, 1, |a, x| {
+                let (av, asi) = compress_a(m, k, a, Layout::ColMajor, m);
+                matvec_int8(m, k, &av, &asi, x)
+            });
+        }
+    }
+
+    // Copies a contiguous col-major `m x k` matrix into a contiguous row-major one
+    fn to_row_major(m: usize, k: usize, a: &[i8]) -> Vec<i8> {
+        (0..m * k).map(|i| a[i / k + m * (i % k)]).collect()
+    }
+
+    #[test]
+    fn test_row_major() {
+        // Weights of an `m x k` linear layer, with padding between the rows
+        for (m, k) in [(1, 1), (37, 13), (600, 4100)] {
+            let lda = k + 9;
+            let (a, x) = rand_int8_vecs(m * lda, k);
+            let (av, asi) = compress_a(m, k, &a, Layout::RowMajor, lda);
+            let mut y = vec![1; m];
+            gemv_int8(Layout::RowMajor, m, k, &av, &asi, k.div_ceil(8), &x, &mut y);
+
+            let a_array =
+                Array2::from_shape_vec((m, lda), a.iter().map(|e| *e as i32).collect()).unwrap();
+            let x_array = Array1::from_iter(x.iter().map(|e| *e as i32));
+            let res_true = a_array.slice(s![.., ..k]).dot(&x_array) + 1;
+            assert_eq!(Array1::from(y), res_true, "m = {}, k = {}", m, k);
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        let (m, k): (usize, usize) = (1100, 300);
+        let kb = k.div_ceil(8);
+        let (a, x) = rand_int8_vecs(m * k, k);
+        let a_array =
+            Array2::from_shape_vec((m, k).f(), a.iter().map(|e| *e as i32).collect()).unwrap();
+        let res_true = a_array.dot(&Array1::from_iter(x.iter().map(|e| *e as i32)));
+
+        let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+        // Padded to whole bytes of A
+        let xs: Vec<i16> = (0..8 * kb)
+            .map(|i| x.get(i).map_or(0, |e| *e as i16))
+            .collect();
+        let (av_row, asi_row) = compress_a(m, k, &to_row_major(m, k, &a), Layout::RowMajor, k);
+        let xt = transpose_x(kb, &x);
+
+        for threads in [1, 2, 3, 16] {
+            let mut y = vec![0; m];
+            gemv_with(threads, Layout::ColMajor, m, kb, &av, &asi, m, &xs, &mut y);
+            assert_eq!(Array1::from(y), res_true, "threads = {}", threads);
+
+            let mut y = vec![0; m];
+            gemv_with(
+                threads,
+                Layout::RowMajor,
+                m,
+                kb,
+                &av_row,
+                &asi_row,
+                kb,
+                &xt,
+                &mut y,
+            );
+            assert_eq!(Array1::from(y), res_true, "threads = {}", threads);
+        }
+    }
+
+    #[test]
+    fn test_extremes() {
+        // -128 can't be negated in i8, and every i16 accumulator has to be flushed (row-major
+        // flushes after I16_STEPS chunks of 16 bytes)
+        let (m, k) = (20, 8 * 16 * super::I16_STEPS * 3 + 5);
+        let a: Vec<i8> = (0..m * k).map(|i| if i % m < 8 { 1 } else { -1 }).collect();
+        let x = vec![-128_i8; k];
+        let expected: Vec<i32> = (0..m)
+            .map(|i| {
+                if i < 8 {
+                    -128 * k as i32
+                } else {
+                    128 * k as i32
+                }
+            })
+            .collect();
+
+        let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+        assert_eq!(matvec_int8(m, k, &av, &asi, &x), expected);
+
+        let (av, asi) = compress_a(m, k, &to_row_major(m, k, &a), Layout::RowMajor, k);
+        let mut y = vec![0; m];
+        gemv_int8(Layout::RowMajor, m, k, &av, &asi, k.div_ceil(8), &x, &mut y);
+        assert_eq!(y, expected);
+    }
+
+    #[test]
+    fn test_try() {
+        let (m, k) = (40, 30);
+        let (a, x) = rand_int8_vecs(m * k, k);
+        let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+        let mut y = vec![0; m];
+        try_gemv_int8(Layout::ColMajor, m, k, &av, &asi, m, &x, &mut y).unwrap();
+        assert_eq!(y, matvec_int8(m, k, &av, &asi, &x));
+
+        assert_eq!(
+            try_gemv_int8(Layout::ColMajor, m, k, &av, &asi, m, &x[1..], &mut y),
+            Err(TernaryError::BufferTooSmall {
+                what: "x",
+                len: k - 1,
+                required: k
+            })
+        );
+        assert_eq!(
+            try_gemv_int8(Layout::ColMajor, m, k, &av, &asi, m - 1, &x, &mut y),
+            Err(TernaryError::ShapeMismatch {
+                what: "lda",
+                expected: m,
+                found: m - 1
+            })
+        );
+    }
+}
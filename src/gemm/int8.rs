@@ -0,0 +1,807 @@
+//! Ternary weights (compressed A) times int8 activations (B) with i32 accumulation.
+//!
+//! Uses the masking trick from `dots::dot_masks`/`dots::dot_shifted_mask_multiplication`
+//! instead of popcounts: the val and sign bits of 16 rows of A are expanded into lane masks,
+//! which conditionally negate and zero a broadcast activation of B. B is packed as raw int8,
+//! 8 k-rows per compressed byte of A.
+use std::{
+    cmp::min,
+    simd::{cmp::SimdPartialEq, num::SimdInt, Simd},
+};
+
+use super::{
+    at, gemv::gemv_int8, pack::pack_a, Epilogue, Layout, PackedActivations, TernaryError, KC, MC,
+    MR, NC, NR,
+};
+
+// Number of compressed k steps an i16 lane can accumulate before it has to be flushed into
+// i32, every step adds 8 values in [-128, 128]
+pub(super) const I16_STEPS: usize = i16::MAX as usize / (8 * 128);
+
+/// Packs a block of raw int8 B (`k` rows, `n` cols, leading dimension ldb) into NR-col
+/// panels that are `kb` bytes of A (so `per_byte * kb` rows) deep. Each panel stores NR
+/// contiguous values per row, rows past k and cols past n are zero padded.
+#[allow(clippy::too_many_arguments)]
+pub fn pack_b_int8(
+    kb: usize,
+    per_byte: usize,
+    k: usize,
+    n: usize,
+    b: &[i8],
+    layout: Layout,
+    ldb: usize,
+    packed: &mut [i8],
+) {
+    let mut offset = 0;
+    for ni in (0..n).step_by(NR) {
+        let cols = NR.min(n - ni);
+        let panel = &mut packed[offset..(offset + per_byte * kb * NR)];
+        panel.fill(0);
+        for ki in 0..k {
+            for j in 0..cols {
+                panel[ki * NR + j] = b[layout.at(ki, ni + j, ldb)];
+            }
+        }
+
+        offset += per_byte * kb * NR;
+    }
+}
+
+/// Packs all of int8 B (`k x n`, leading dimension ldb) into the panels of every kc x nc
+/// block, in the order the drivers visit the blocks (n blocks outside, k blocks inside).
+/// The panels take `8 * ceil(k / 8) * n` bytes with n rounded up to a multiple of 16.
+/// [`super::quantize_activations_packed`] writes this layout directly.
+#[cfg(test)]
+pub(super) fn pack_b_blocks(k: usize, n: usize, b: &[i8], layout: Layout, ldb: usize) -> Vec<i8> {
+    let kb = k.div_ceil(8);
+    let mut packed = vec![0; 8 * kb * n.next_multiple_of(NR)];
+    let mut offset = 0;
+    for ni in (0..n).step_by(NC) {
+        let tile_n = min(n - ni, NC);
+        for ki in (0..kb).step_by(KC) {
+            let tile_k = min(kb - ki, KC);
+            let rows_b = min(k - 8 * ki, 8 * tile_k);
+            let size = 8 * tile_k * tile_n.next_multiple_of(NR);
+            pack_b_int8(
+                tile_k,
+                8,
+                rows_b,
+                tile_n,
+                &b[layout.at(8 * ki, ni, ldb)..],
+                layout,
+                ldb,
+                &mut packed[offset..(offset + size)],
+            );
+            offset += size;
+        }
+    }
+    packed
+}
+
+// Where `gemm_int8_with` gets the 16-col panels of B from
+#[derive(Clone, Copy)]
+enum PanelsB<'a> {
+    // Raw int8 B, packed into the panels of every kc x nc block on the fly
+    Raw {
+        layout: Layout,
+        b: &'a [i8],
+        ldb: usize,
+    },
+    // All blocks packed up front in the layout of `pack_b_blocks`
+    Packed(&'a [i8]),
+}
+
+// The store step of the microkernel, writes its 16x16 block of i32 dot products into C
+pub(super) trait Store {
+    type Elem: Copy + Default;
+
+    // Adds the dot products `ab` (one vector of 16 rows per col) of the block at (row, col)
+    // of the whole C to the col-major tile c with stride ldc. `first` is set for the first kc
+    // block of k.
+    fn store(
+        &self,
+        ab: &[Simd<i32, 16>; 16],
+        at: (usize, usize),
+        first: bool,
+        c: &mut [Self::Elem],
+        ldc: usize,
+    );
+}
+
+// Plain i32 accumulation, `C += A * B`
+struct Accumulate;
+
+impl Store for Accumulate {
+    type Elem = i32;
+
+    #[inline(always)]
+    fn store(
+        &self,
+        ab: &[Simd<i32, 16>; 16],
+        _: (usize, usize),
+        _: bool,
+        c: &mut [i32],
+        ldc: usize,
+    ) {
+        for (i, ab_i) in ab.iter().enumerate() {
+            let c_i = &mut c[at(0, i, ldc)..(at(0, i, ldc) + 16)];
+            c_i.copy_from_slice(&(Simd::from_slice(c_i) + ab_i).to_array());
+        }
+    }
+}
+
+/// Computes the i32 dot products of a 16x16 block of C from a packed 16-row panel of
+/// compressed A, `k` bytes deep, and a packed 16-col panel of int8 B, `8 * k` rows deep. Returns
+/// one vector of 16 rows per col, the store step writes them into C.
+#[inline(always)]
+pub fn dot16x16_int8(k: usize, a_vals: &[u8], a_signs: &[u8], b: &[i8]) -> [Simd<i32, 16>; 16] {
+    let mut ab = [Simd::<i32, 16>::splat(0); 16];
+
+    for k_start in (0..k).step_by(I16_STEPS) {
+        let mut ab16 = [Simd::<i16, 16>::splat(0); 16];
+
+        for ki in k_start..min(k, k_start + I16_STEPS) {
+            // Load one col of 16 rows of A (=> 8*16=128 ternary values )
+            let a_val = Simd::<u8, 16>::from_slice(&a_vals[(ki * 16)..(ki * 16 + 16)]);
+            let a_sign = Simd::<u8, 16>::from_slice(&a_signs[(ki * 16)..(ki * 16 + 16)]);
+
+            for bit in 0..8 {
+                // Expand the bit of each row into a lane mask (vtstq_u8 in aarch64 asm)
+                let shifter = Simd::splat(1 << bit);
+                let zero = Simd::splat(0);
+                // -1 where the weight is non-zero / negative, 0 otherwise
+                let keep = (a_val & shifter).simd_ne(zero).cast::<i16>().to_simd();
+                let neg = (a_sign & shifter).simd_ne(zero).cast::<i16>().to_simd();
+
+                // Load one row of 16 cols of B
+                let row = ki * 8 + bit;
+                let b_row = &b[(row * 16)..(row * 16 + 16)];
+
+                macro_rules! set_c {
+                    ($i:expr) => {
+                        // Broadcast ith col of B, then negate (two's complement) and mask it
+                        // like dot_shifted_mask_multiplication
+                        let b_i = Simd::splat(b_row[$i] as i16);
+                        ab16[$i] += ((b_i ^ neg) - neg) & keep;
+                    };
+                }
+
+                set_c!(0);
+                set_c!(1);
+                set_c!(2);
+                set_c!(3);
+                set_c!(4);
+                set_c!(5);
+                set_c!(6);
+                set_c!(7);
+                set_c!(8);
+                set_c!(9);
+                set_c!(10);
+                set_c!(11);
+                set_c!(12);
+                set_c!(13);
+                set_c!(14);
+                set_c!(15);
+            }
+        }
+
+        for (ab_i, ab16_i) in ab.iter_mut().zip(ab16) {
+            *ab_i += ab16_i.cast::<i32>();
+        }
+    }
+
+    ab
+}
+
+// Expects a and b to be packed, c is col-major with stride ldc and starts at (row, col) of the
+// whole C. `first` is set for the first kc block of k.
+#[allow(clippy::too_many_arguments)]
+fn inner_kernel<S: Store>(
+    store: &S,
+    (row, col): (usize, usize),
+    first: bool,
+    m: usize,
+    k: usize,
+    n: usize,
+    packed_a_vals: &[u8],
+    packed_a_signs: &[u8],
+    packed_b: &[i8],
+    c: &mut [S::Elem],
+    ldc: usize,
+) {
+    for ni in (0..n).step_by(NR) {
+        for mi in (0..m).step_by(MR) {
+            let a_vals = &packed_a_vals[(mi * k)..];
+            let a_signs = &packed_a_signs[(mi * k)..];
+            let b = &packed_b[(ni * 8 * k)..];
+
+            let ab = dot16x16_int8(k, a_vals, a_signs, b);
+            let at_c = (row + mi, col + ni);
+
+            let rows = min(m - mi, MR);
+            let cols = min(n - ni, NR);
+            if rows == MR && cols == NR {
+                store.store(&ab, at_c, first, &mut c[at(mi, ni, ldc)..], ldc);
+                continue;
+            }
+
+            // Edge tile: run the full microkernel on a padded copy and only write back the
+            // part that lies inside C
+            let mut tile = [S::Elem::default(); MR * NR];
+            for j in 0..cols {
+                let src = at(mi, ni + j, ldc);
+                tile[at(0, j, MR)..(at(0, j, MR) + rows)].copy_from_slice(&c[src..src + rows]);
+            }
+            store.store(&ab, at_c, first, &mut tile, MR);
+            for j in 0..cols {
+                let dst = at(mi, ni + j, ldc);
+                c[dst..dst + rows].copy_from_slice(&tile[at(0, j, MR)..(at(0, j, MR) + rows)]);
+            }
+        }
+    }
+}
+
+/// Computes `C += A * B` for compressed ternary A (`m x k`, leading dimension lda in
+/// compressed bytes) and int8 B (`k x n`, leading dimension ldb), writing into col-major i32
+/// C with leading dimension ldc.
+#[allow(clippy::too_many_arguments)]
+pub fn gemm_int8(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b: &[i8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) {
+    let kb = k.div_ceil(8);
+    a_layout.check(m, kb, a_vals.len().min(a_signs.len()), lda);
+    b_layout.check(k, n, b.len(), ldb);
+    Layout::ColMajor.check(m, n, c.len(), ldc);
+    if m == 0 || n == 0 || kb == 0 {
+        return;
+    }
+
+    if n == 1 {
+        // Matrix-vector product, streams A once without packing
+        let x: Vec<i8> = (0..k).map(|ki| b[b_layout.at(ki, 0, ldb)]).collect();
+        gemv_int8(a_layout, m, k, a_vals, a_signs, lda, &x, &mut c[..m]);
+        return;
+    }
+
+    let b = PanelsB::Raw {
+        layout: b_layout,
+        b,
+        ldb,
+    };
+    gemm_int8_with(
+        &Accumulate,
+        a_layout,
+        m,
+        n,
+        k,
+        a_vals,
+        a_signs,
+        lda,
+        b,
+        c,
+        ldc,
+    );
+}
+
+// Loops 5 to 3 of `gemm_int8` for checked, non-empty operands, the microkernels write C with
+// the given store step
+#[allow(clippy::too_many_arguments)]
+fn gemm_int8_with<S: Store>(
+    store: &S,
+    a_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b: PanelsB,
+    c: &mut [S::Elem],
+    ldc: usize,
+) {
+    let kb = k.div_ceil(8);
+    let mut packed_a_vals = vec![0_u8; MC * KC];
+    let mut packed_a_signs = vec![0_u8; MC * KC];
+    let buffer_size = match b {
+        PanelsB::Raw { .. } => 8 * KC * NC,
+        PanelsB::Packed(_) => 0,
+    };
+    let mut packed_b_buffer = vec![0_i8; buffer_size];
+    // Start of the current block in packed B
+    let mut offset = 0;
+
+    // LOOP 5: Split B and C on the n-dimension into parts of nc size
+    for ni in (0..n).step_by(NC) {
+        let tile_n = min(n - ni, NC);
+
+        // LOOP 4: Split A and B on the k-dimension into parts of kc size
+        for ki in (0..kb).step_by(KC) {
+            let tile_k = min(kb - ki, KC);
+            let size = 8 * tile_k * tile_n.next_multiple_of(NR);
+            let packed_b = match b {
+                PanelsB::Raw { layout, b, ldb } => {
+                    // Rows of B in this block, the last block can end before 8 * tile_k
+                    let rows_b = min(k - 8 * ki, 8 * tile_k);
+                    pack_b_int8(
+                        tile_k,
+                        8,
+                        rows_b,
+                        tile_n,
+                        &b[layout.at(8 * ki, ni, ldb)..],
+                        layout,
+                        ldb,
+                        &mut packed_b_buffer,
+                    );
+                    &packed_b_buffer[..]
+                }
+                PanelsB::Packed(packed) => &packed[offset..(offset + size)],
+            };
+            offset += size;
+
+            // LOOP 3: Split A and C on the m-dimension into parts of mc
+            for mi in (0..m).step_by(MC) {
+                let tile_m = min(m - mi, MC);
+
+                let a_start = a_layout.at(mi, ki, lda);
+                pack_a(
+                    tile_m,
+                    tile_k,
+                    &a_vals[a_start..],
+                    a_layout,
+                    lda,
+                    MR,
+                    &mut packed_a_vals,
+                );
+                pack_a(
+                    tile_m,
+                    tile_k,
+                    &a_signs[a_start..],
+                    a_layout,
+                    lda,
+                    MR,
+                    &mut packed_a_signs,
+                );
+
+                inner_kernel(
+                    store,
+                    (mi, ni),
+                    ki == 0,
+                    tile_m,
+                    tile_k,
+                    tile_n,
+                    &packed_a_vals,
+                    &packed_a_signs,
+                    packed_b,
+                    &mut c[at(mi, ni, ldc)..],
+                    ldc,
+                );
+            }
+        }
+    }
+}
+
+// Checks the operands of a gemm of compressed A (`m x k`) and int8 B (`k x n`) into
+// col-major C
+#[allow(clippy::too_many_arguments)]
+fn validate_int8(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    (a_vals, a_signs): (usize, usize),
+    lda: usize,
+    b: usize,
+    ldb: usize,
+    c: usize,
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    let kb = k.div_ceil(8);
+    a_layout.validate(("a_vals", "lda"), m, kb, a_vals, lda)?;
+    a_layout.validate(("a_signs", "lda"), m, kb, a_signs, lda)?;
+    b_layout.validate(("b", "ldb"), k, n, b, ldb)?;
+    Layout::ColMajor.validate(("c", "ldc"), m, n, c, ldc)
+}
+
+/// Same as [`gemm_int8`], but returns an error instead of panicking if a buffer or leading
+/// dimension doesn't fit the shapes.
+#[allow(clippy::too_many_arguments)]
+pub fn try_gemm_int8(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b: &[i8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    validate_int8(
+        a_layout,
+        b_layout,
+        m,
+        n,
+        k,
+        (a_vals.len(), a_signs.len()),
+        lda,
+        b.len(),
+        ldb,
+        c.len(),
+        ldc,
+    )?;
+    gemm_int8(
+        a_layout, b_layout, m, n, k, a_vals, a_signs, lda, b, ldb, c, ldc,
+    );
+    Ok(())
+}
+
+/// Computes `C += (A * B) * (w_scale * x_scale) + bias` like [`gemm_int8`], but the
+/// microkernels apply the scales and bias of the [`Epilogue`] when they store the i32 dot
+/// products, writing f32 C in a single pass.
+#[allow(clippy::too_many_arguments)]
+pub fn gemm_int8_f32(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b: &[i8],
+    ldb: usize,
+    epilogue: &Epilogue,
+    c: &mut [f32],
+    ldc: usize,
+) {
+    try_gemm_int8_f32(
+        a_layout, b_layout, m, n, k, a_vals, a_signs, lda, b, ldb, epilogue, c, ldc,
+    )
+    .unwrap_or_else(|err| panic!("{}", err));
+}
+
+/// Same as [`gemm_int8_f32`], but returns an error instead of panicking if a buffer, leading
+/// dimension, scale or bias doesn't fit the shapes.
+#[allow(clippy::too_many_arguments)]
+pub fn try_gemm_int8_f32(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b: &[i8],
+    ldb: usize,
+    epilogue: &Epilogue,
+    c: &mut [f32],
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    validate_int8(
+        a_layout,
+        b_layout,
+        m,
+        n,
+        k,
+        (a_vals.len(), a_signs.len()),
+        lda,
+        b.len(),
+        ldb,
+        c.len(),
+        ldc,
+    )?;
+    epilogue.validate(m, n)?;
+    if m == 0 || n == 0 {
+        return Ok(());
+    }
+
+    if n == 1 || k == 0 {
+        // The gemv (and an empty k) only produce a vector of dot products per column, which
+        // is dequantized afterwards
+        for ni in 0..n {
+            let mut y = vec![0; m];
+            if k > 0 {
+                let x: Vec<i8> = (0..k).map(|ki| b[b_layout.at(ki, ni, ldb)]).collect();
+                gemv_int8(a_layout, m, k, a_vals, a_signs, lda, &x, &mut y);
+            }
+            for (r, dot) in y.into_iter().enumerate() {
+                c[at(r, ni, ldc)] += epilogue.apply(r, ni, dot);
+            }
+        }
+        return Ok(());
+    }
+
+    let b = PanelsB::Raw {
+        layout: b_layout,
+        b,
+        ldb,
+    };
+    gemm_int8_with(epilogue, a_layout, m, n, k, a_vals, a_signs, lda, b, c, ldc);
+    Ok(())
+}
+
+/// Computes `C += (A * B) * (w_scale * x_scale) + bias` like [`gemm_int8_f32`] for
+/// activations quantized into packed panels by [`super::quantize_activations_packed`], so B
+/// isn't packed again. A is `m x b.k()`, C is col-major `m x b.n()` with leading dimension ldc.
+#[allow(clippy::too_many_arguments)]
+pub fn gemm_int8_f32_packed(
+    a_layout: Layout,
+    m: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b: &PackedActivations,
+    epilogue: &Epilogue,
+    c: &mut [f32],
+    ldc: usize,
+) {
+    try_gemm_int8_f32_packed(a_layout, m, a_vals, a_signs, lda, b, epilogue, c, ldc)
+        .unwrap_or_else(|err| panic!("{}", err));
+}
+
+/// Same as [`gemm_int8_f32_packed`], but returns an error instead of panicking if a buffer,
+/// leading dimension, scale or bias doesn't fit the shapes.
+#[allow(clippy::too_many_arguments)]
+pub fn try_gemm_int8_f32_packed(
+    a_layout: Layout,
+    m: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b: &PackedActivations,
+    epilogue: &Epilogue,
+    c: &mut [f32],
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    let (k, n) = (b.k(), b.n());
+    let kb = k.div_ceil(8);
+    a_layout.validate(("a_vals", "lda"), m, kb, a_vals.len(), lda)?;
+    a_layout.validate(("a_signs", "lda"), m, kb, a_signs.len(), lda)?;
+    Layout::ColMajor.validate(("c", "ldc"), m, n, c.len(), ldc)?;
+    epilogue.validate(m, n)?;
+    if m == 0 || n == 0 {
+        return Ok(());
+    }
+
+    if k == 0 {
+        // All dot products are 0, only the bias is added
+        for ni in 0..n {
+            for r in 0..m {
+                c[at(r, ni, ldc)] += epilogue.apply(r, ni, 0);
+            }
+        }
+        return Ok(());
+    }
+
+    let b = PanelsB::Packed(b.panels());
+    gemm_int8_with(epilogue, a_layout, m, n, k, a_vals, a_signs, lda, b, c, ldc);
+    Ok(())
+}
+
+/// Multiplies compressed, contiguous col-major A (`m x k`, see [`super::compress_a`]) with a
+/// contiguous col-major int8 B (`k x n`) into a new col-major `m x n` C.
+pub fn matmul_int8(
+    m: usize,
+    k: usize,
+    n: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b: &[i8],
+) -> Vec<i32> {
+    let mut c = vec![0; m * n];
+    gemm_int8(
+        Layout::ColMajor,
+        Layout::ColMajor,
+        m,
+        n,
+        k,
+        a_vals,
+        a_signs,
+        m.max(1),
+        b,
+        k.max(1),
+        &mut c,
+        m.max(1),
+    );
+    c
+}
+
+/// Same as [`matmul_int8`], but dequantizes C to f32 with the [`Epilogue`], see
+/// [`gemm_int8_f32`].
+pub fn matmul_int8_f32(
+    m: usize,
+    k: usize,
+    n: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b: &[i8],
+    epilogue: &Epilogue,
+) -> Vec<f32> {
+    let mut c = vec![0.; m * n];
+    gemm_int8_f32(
+        Layout::ColMajor,
+        Layout::ColMajor,
+        m,
+        n,
+        k,
+        a_vals,
+        a_signs,
+        m.max(1),
+        b,
+        k.max(1),
+        epilogue,
+        &mut c,
+        m.max(1),
+    );
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array2, ShapeBuilder};
+
+    use crate::{
+        constants::SIZE,
+        gemm::{compress_a, Epilogue, Layout, Scale, TernaryError},
+        test_util::test_util::{rand_int8_vecs, test_matmul_int8_shape},
+    };
+
+    use super::{gemm_int8, matmul_int8, matmul_int8_f32, try_gemm_int8_f32};
+
+    fn test_shape(m: usize, k: usize, n: usize) {
+        test_matmul_int8_shape(m, k, n, |a, b| {
+            let (av, asi) = compress_a(m, k, a, Layout::ColMajor, m);
+            matmul_int8(m, k, n, &av, &asi, b)
+        })
+    }
+
+    #[test]
+    fn test() {
+        test_shape(SIZE, SIZE, SIZE);
+    }
+
+    #[test]
+    fn test_edge_tiles() {
+        test_shape(37, 13, 21);
+        for n in [1, 7, 33] {
+            test_shape(96, 250, n);
+        }
+    }
+
+    #[test]
+    fn test_multiple_blocks() {
+        // Larger than MC, KC (in compressed bytes) and NC
+        test_shape(270, 4100, 260);
+    }
+
+    #[test]
+    fn test_extremes() {
+        // -128 can't be negated in i8, and every i16 accumulator has to be flushed
+        let (m, k, n) = (16, 8 * super::I16_STEPS * 3 + 5, 16);
+        let a: Vec<i8> = (0..m * k).map(|i| if i % m < 8 { 1 } else { -1 }).collect();
+        let b = vec![-128_i8; k * n];
+
+        let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+        let c = matmul_int8(m, k, n, &av, &asi, &b);
+        for (i, e) in c.iter().enumerate() {
+            let sign = if i % m < 8 { -1 } else { 1 };
+            assert_eq!(*e, sign * 128 * k as i32);
+        }
+    }
+
+    #[test]
+    fn test_row_major_activations() {
+        let (m, k, n) = (40, 75, 9);
+        let (a, b) = rand_int8_vecs(m * k, k * n);
+        let a_array =
+            Array2::from_shape_vec((m, k).f(), a.iter().map(|e| *e as i32).collect()).unwrap();
+        let b_array =
+            Array2::from_shape_vec((k, n), b.iter().map(|e| *e as i32).collect()).unwrap();
+
+        let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+        let mut c = vec![0; m * n];
+        gemm_int8(
+            Layout::ColMajor,
+            Layout::RowMajor,
+            m,
+            n,
+            k,
+            &av,
+            &asi,
+            m,
+            &b,
+            n,
+            &mut c,
+            m,
+        );
+
+        let res = Array2::from_shape_vec((m, n).f(), c).unwrap();
+        assert_eq!(res, a_array.dot(&b_array));
+    }
+
+    #[test]
+    fn test_f32_epilogue() {
+        // Edge tiles, the gemv path (n = 1) and more than one kc block
+        for (m, k, n) in [(37, 13, 21), (96, 250, 1), (270, 4100, 20)] {
+            let (a, b) = rand_int8_vecs(m * k, k * n);
+            let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+            let exact = matmul_int8(m, k, n, &av, &asi, &b);
+
+            let w_scales: Vec<f32> = (0..m).map(|i| 0.5 + i as f32 / 64.).collect();
+            let x_scales: Vec<f32> = (0..n).map(|i| 1. / (i + 1) as f32).collect();
+            let bias: Vec<f32> = (0..m).map(|i| i as f32 - 10.).collect();
+            let epilogues = [
+                Epilogue::IDENTITY,
+                Epilogue {
+                    w_scale: Scale::PerChannel(&w_scales),
+                    x_scale: Scale::PerChannel(&x_scales),
+                    bias: Some(&bias),
+                },
+                Epilogue {
+                    w_scale: Scale::PerTensor(0.25),
+                    x_scale: Scale::PerChannel(&x_scales),
+                    bias: None,
+                },
+            ];
+
+            for epilogue in epilogues {
+                let c = matmul_int8_f32(m, k, n, &av, &asi, &b, &epilogue);
+                for (i, (e, dot)) in c.iter().zip(&exact).enumerate() {
+                    let expected = epilogue.apply(i % m, i / m, *dot);
+                    // The kc blocks are dequantized one at a time
+                    let tolerance = 1e-5 * expected.abs().max(1.);
+                    assert!(
+                        (e - expected).abs() <= tolerance,
+                        "{} != {} at {}, m = {}, k = {}, n = {}",
+                        e,
+                        expected,
+                        i,
+                        m,
+                        k,
+                        n
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_f32_epilogue_errors() {
+        let (m, k, n) = (20, 16, 4);
+        let (a, b) = rand_int8_vecs(m * k, k * n);
+        let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+        let bias = vec![0.; m - 1];
+        let epilogue = Epilogue {
+            bias: Some(&bias),
+            ..Epilogue::IDENTITY
+        };
+
+        let mut c = vec![0.; m * n];
+        let col = Layout::ColMajor;
+        assert_eq!(
+            try_gemm_int8_f32(col, col, m, n, k, &av, &asi, m, &b, k, &epilogue, &mut c, m),
+            Err(TernaryError::BufferTooSmall {
+                what: "bias",
+                len: m - 1,
+                required: m
+            })
+        );
+        assert!(c.iter().all(|e| *e == 0.));
+    }
+}
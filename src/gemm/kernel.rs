@@ -0,0 +1,376 @@
+use std::{
+    cmp::min,
+    simd::{
+        num::{SimdInt, SimdUint},
+        Simd,
+    },
+};
+
+fn at(r: usize, c: usize, cstride: usize) -> usize {
+    r + cstride * c
+}
+
+/// Counts the bits in every u8 lane. The count (0-8) fits both unsigned and signed ints, so
+/// it is returned as i8.
+pub trait Popcount {
+    fn popcount(input: Simd<u8, 16>) -> Simd<i8, 16>;
+}
+
+/// The classic SWAR bit count in portable SIMD, works on every target.
+pub struct Portable;
+
+impl Popcount for Portable {
+    #[inline(always)]
+    fn popcount(input: Simd<u8, 16>) -> Simd<i8, 16> {
+        let x = input - ((input >> 1) & Simd::splat(0x55));
+        let x = (x & Simd::splat(0x33)) + ((x >> 2) & Simd::splat(0x33));
+        ((x + (x >> 4)) & Simd::splat(0x0f)).cast::<i8>()
+    }
+}
+
+/// NEON's per-byte popcount, `vcntq_u8`.
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+pub struct Neon;
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+impl Popcount for Neon {
+    #[inline(always)]
+    fn popcount(input: Simd<u8, 16>) -> Simd<i8, 16> {
+        use core::arch::aarch64::*;
+        // Safety: NEON is enabled at compile time
+        #[allow(unused_unsafe)]
+        let count = unsafe { vcntq_u8(uint8x16_t::from(input)) };
+        Simd::from(count).cast::<i8>()
+    }
+}
+
+// Fastest popcount known at compile time, used by the i8 and small-batch kernels
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+type Native = Neon;
+#[cfg(not(all(target_arch = "aarch64", target_feature = "neon")))]
+type Native = Portable;
+
+/// Counts the bits in every u8 lane with the fastest popcount known at compile time: NEON's
+/// `vcntq_u8` on aarch64, the portable SWAR bit count everywhere else.
+#[inline(always)]
+pub(crate) fn popcount(input: Simd<u8, 16>) -> Simd<i8, 16> {
+    Native::popcount(input)
+}
+
+#[inline(always)]
+fn dot<P: Popcount>(
+    a_val: Simd<u8, 16>,
+    a_sign: Simd<u8, 16>,
+    b_val: Simd<u8, 16>,
+    b_sign: Simd<u8, 16>,
+) -> Simd<i8, 16> {
+    // Calc val bits between all A1 rows and first B[i] col
+    let val = b_val & a_val;
+    let or = b_sign ^ a_sign;
+    let sign = val & or;
+
+    // Add vals and counts
+    let val_count = P::popcount(val);
+    let sign_count = P::popcount(sign);
+    let sign_countx2 = sign_count << 1;
+
+    val_count - sign_countx2
+}
+
+// Number of k steps an i16 lane can accumulate before it has to be flushed into i32,
+// every step adds a value in [-8, 8]
+const I16_STEPS: usize = i16::MAX as usize / 8;
+
+/// Computes a 16x16 block of C (col-major with stride ldc) from a packed 16-row panel of A
+/// and a packed 16-col panel of B, both k compressed bytes deep.
+pub fn dot16x16(
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+    c: &mut [i8],
+    ldc: usize,
+) {
+    // 16 rows of ab/c, with each 16 items
+    // Load initial values from c
+    let mut ab = [Simd::<i8, 16>::splat(0); 16];
+
+    ab[0] = Simd::from_slice(&c[at(0, 0, ldc)..(at(0, 0, ldc) + 16)]);
+    ab[1] = Simd::from_slice(&c[at(0, 1, ldc)..(at(0, 1, ldc) + 16)]);
+    ab[2] = Simd::from_slice(&c[at(0, 2, ldc)..(at(0, 2, ldc) + 16)]);
+    ab[3] = Simd::from_slice(&c[at(0, 3, ldc)..(at(0, 3, ldc) + 16)]);
+    ab[4] = Simd::from_slice(&c[at(0, 4, ldc)..(at(0, 4, ldc) + 16)]);
+    ab[5] = Simd::from_slice(&c[at(0, 5, ldc)..(at(0, 5, ldc) + 16)]);
+    ab[6] = Simd::from_slice(&c[at(0, 6, ldc)..(at(0, 6, ldc) + 16)]);
+    ab[7] = Simd::from_slice(&c[at(0, 7, ldc)..(at(0, 7, ldc) + 16)]);
+    ab[8] = Simd::from_slice(&c[at(0, 8, ldc)..(at(0, 8, ldc) + 16)]);
+    ab[9] = Simd::from_slice(&c[at(0, 9, ldc)..(at(0, 9, ldc) + 16)]);
+    ab[10] = Simd::from_slice(&c[at(0, 10, ldc)..(at(0, 10, ldc) + 16)]);
+    ab[11] = Simd::from_slice(&c[at(0, 11, ldc)..(at(0, 11, ldc) + 16)]);
+    ab[12] = Simd::from_slice(&c[at(0, 12, ldc)..(at(0, 12, ldc) + 16)]);
+    ab[13] = Simd::from_slice(&c[at(0, 13, ldc)..(at(0, 13, ldc) + 16)]);
+    ab[14] = Simd::from_slice(&c[at(0, 14, ldc)..(at(0, 14, ldc) + 16)]);
+    ab[15] = Simd::from_slice(&c[at(0, 15, ldc)..(at(0, 15, ldc) + 16)]);
+
+    for ki in 0..k {
+        // Load one col of 16 rows of A (=> 8*16=128 ternary values )
+        let a_val = Simd::<u8, 16>::from_slice(&a_vals[(ki * 16)..(ki * 16 + 16)]);
+        let a_sign = Simd::<u8, 16>::from_slice(&a_signs[(ki * 16)..(ki * 16 + 16)]);
+        // Load one row of 16 cols of B
+        let b_val = Simd::<u8, 16>::from_slice(&b_vals[(ki * 16)..(ki * 16 + 16)]);
+        let b_sign = Simd::<u8, 16>::from_slice(&b_signs[(ki * 16)..(ki * 16 + 16)]);
+
+        // Compute
+        macro_rules! set_c {
+            ($i:expr) => {
+                // Broadcast ith lane (col) in B to a full 16-col simd
+                let b_val_i = Simd::splat(b_val[$i]); // vdupq_laneq_u8 in aarch64 asm
+                let b_sign_i = Simd::splat(b_sign[$i]);
+
+                // output 16 rows to C (16 rows of A x ith col of B)
+                ab[$i] += dot::<Native>(a_val, a_sign, b_val_i, b_sign_i);
+            };
+        }
+
+        set_c!(0);
+        set_c!(1);
+        set_c!(2);
+        set_c!(3);
+        set_c!(4);
+        set_c!(5);
+        set_c!(6);
+        set_c!(7);
+        set_c!(8);
+        set_c!(9);
+        set_c!(10);
+        set_c!(11);
+        set_c!(12);
+        set_c!(13);
+        set_c!(14);
+        set_c!(15);
+    }
+
+    c[at(0, 0, ldc)..(at(0, 0, ldc) + 16)].copy_from_slice(&ab[0].to_array());
+    c[at(0, 1, ldc)..(at(0, 1, ldc) + 16)].copy_from_slice(&ab[1].to_array());
+    c[at(0, 2, ldc)..(at(0, 2, ldc) + 16)].copy_from_slice(&ab[2].to_array());
+    c[at(0, 3, ldc)..(at(0, 3, ldc) + 16)].copy_from_slice(&ab[3].to_array());
+    c[at(0, 4, ldc)..(at(0, 4, ldc) + 16)].copy_from_slice(&ab[4].to_array());
+    c[at(0, 5, ldc)..(at(0, 5, ldc) + 16)].copy_from_slice(&ab[5].to_array());
+    c[at(0, 6, ldc)..(at(0, 6, ldc) + 16)].copy_from_slice(&ab[6].to_array());
+    c[at(0, 7, ldc)..(at(0, 7, ldc) + 16)].copy_from_slice(&ab[7].to_array());
+
+    c[at(0, 8, ldc)..(at(0, 8, ldc) + 16)].copy_from_slice(&ab[8].to_array());
+    c[at(0, 9, ldc)..(at(0, 9, ldc) + 16)].copy_from_slice(&ab[9].to_array());
+    c[at(0, 10, ldc)..(at(0, 10, ldc) + 16)].copy_from_slice(&ab[10].to_array());
+    c[at(0, 11, ldc)..(at(0, 11, ldc) + 16)].copy_from_slice(&ab[11].to_array());
+    c[at(0, 12, ldc)..(at(0, 12, ldc) + 16)].copy_from_slice(&ab[12].to_array());
+    c[at(0, 13, ldc)..(at(0, 13, ldc) + 16)].copy_from_slice(&ab[13].to_array());
+    c[at(0, 14, ldc)..(at(0, 14, ldc) + 16)].copy_from_slice(&ab[14].to_array());
+    c[at(0, 15, ldc)..(at(0, 15, ldc) + 16)].copy_from_slice(&ab[15].to_array());
+}
+
+/// Same as [`dot16x16`], but for an i32 C. Every step is widened into i16 accumulators, which
+/// are added to the i32 accumulators every `I16_STEPS` steps of k.
+pub fn dot16x16_i32<P: Popcount>(
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+    c: &mut [i32],
+    ldc: usize,
+) {
+    // Load initial values from c
+    let mut ab = [Simd::<i32, 16>::splat(0); 16];
+    for (i, ab_i) in ab.iter_mut().enumerate() {
+        *ab_i = Simd::from_slice(&c[at(0, i, ldc)..(at(0, i, ldc) + 16)]);
+    }
+
+    for k_start in (0..k).step_by(I16_STEPS) {
+        let mut ab16 = [Simd::<i16, 16>::splat(0); 16];
+
+        for ki in k_start..min(k, k_start + I16_STEPS) {
+            // Load one col of 16 rows of A (=> 8*16=128 ternary values )
+            let a_val = Simd::<u8, 16>::from_slice(&a_vals[(ki * 16)..(ki * 16 + 16)]);
+            let a_sign = Simd::<u8, 16>::from_slice(&a_signs[(ki * 16)..(ki * 16 + 16)]);
+            // Load one row of 16 cols of B
+            let b_val = Simd::<u8, 16>::from_slice(&b_vals[(ki * 16)..(ki * 16 + 16)]);
+            let b_sign = Simd::<u8, 16>::from_slice(&b_signs[(ki * 16)..(ki * 16 + 16)]);
+
+            macro_rules! set_c {
+                ($i:expr) => {
+                    let b_val_i = Simd::splat(b_val[$i]);
+                    let b_sign_i = Simd::splat(b_sign[$i]);
+
+                    // Widen the 16 rows of [-8, 8] before accumulating
+                    ab16[$i] += dot::<P>(a_val, a_sign, b_val_i, b_sign_i).cast::<i16>();
+                };
+            }
+
+            set_c!(0);
+            set_c!(1);
+            set_c!(2);
+            set_c!(3);
+            set_c!(4);
+            set_c!(5);
+            set_c!(6);
+            set_c!(7);
+            set_c!(8);
+            set_c!(9);
+            set_c!(10);
+            set_c!(11);
+            set_c!(12);
+            set_c!(13);
+            set_c!(14);
+            set_c!(15);
+        }
+
+        for (ab_i, ab16_i) in ab.iter_mut().zip(ab16) {
+            *ab_i += ab16_i.cast::<i32>();
+        }
+    }
+
+    for (i, ab_i) in ab.iter().enumerate() {
+        c[at(0, i, ldc)..(at(0, i, ldc) + 16)].copy_from_slice(&ab_i.to_array());
+    }
+}
+
+/// Computes a `16 * V x NR` block of C (col-major with stride ldc) for small batches, where
+/// most of a 16x16 tile would be padding. Like [`dot16x16`] it keeps 16 accumulators for
+/// e.g. 64x4 (V = 4, NR = 4) or 32x8 (V = 2, NR = 8) tiles. A is packed in `16 * V`-row panels
+/// and B in NR-col panels, both k compressed bytes deep.
+pub fn dot_small<const V: usize, const NR: usize>(
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+    c: &mut [i8],
+    ldc: usize,
+) {
+    let mr = 16 * V;
+
+    // Load initial values from c, NR cols of V vectors of 16 rows
+    let mut ab = [[Simd::<i8, 16>::splat(0); V]; NR];
+    for (j, ab_j) in ab.iter_mut().enumerate() {
+        for (v, ab_jv) in ab_j.iter_mut().enumerate() {
+            *ab_jv = Simd::from_slice(&c[at(16 * v, j, ldc)..(at(16 * v, j, ldc) + 16)]);
+        }
+    }
+
+    for ki in 0..k {
+        // Load one col of 16 * V rows of A
+        let a_val: [Simd<u8, 16>; V] =
+            std::array::from_fn(|v| Simd::from_slice(&a_vals[(ki * mr + 16 * v)..]));
+        let a_sign: [Simd<u8, 16>; V] =
+            std::array::from_fn(|v| Simd::from_slice(&a_signs[(ki * mr + 16 * v)..]));
+
+        for (j, ab_j) in ab.iter_mut().enumerate() {
+            // Broadcast the jth col of B
+            let b_val_j = Simd::splat(b_vals[ki * NR + j]);
+            let b_sign_j = Simd::splat(b_signs[ki * NR + j]);
+
+            for (ab_jv, (a_val_v, a_sign_v)) in ab_j.iter_mut().zip(a_val.iter().zip(&a_sign)) {
+                *ab_jv += dot::<Native>(*a_val_v, *a_sign_v, b_val_j, b_sign_j);
+            }
+        }
+    }
+
+    for (j, ab_j) in ab.iter().enumerate() {
+        for (v, ab_jv) in ab_j.iter().enumerate() {
+            c[at(16 * v, j, ldc)..(at(16 * v, j, ldc) + 16)].copy_from_slice(&ab_jv.to_array());
+        }
+    }
+}
+
+/// Same as [`dot_small`], but for an i32 C, widening into i16 accumulators like
+/// [`dot16x16_i32`].
+pub fn dot_small_i32<P: Popcount, const V: usize, const NR: usize>(
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+    c: &mut [i32],
+    ldc: usize,
+) {
+    let mr = 16 * V;
+
+    let mut ab = [[Simd::<i32, 16>::splat(0); V]; NR];
+    for (j, ab_j) in ab.iter_mut().enumerate() {
+        for (v, ab_jv) in ab_j.iter_mut().enumerate() {
+            *ab_jv = Simd::from_slice(&c[at(16 * v, j, ldc)..(at(16 * v, j, ldc) + 16)]);
+        }
+    }
+
+    for k_start in (0..k).step_by(I16_STEPS) {
+        let mut ab16 = [[Simd::<i16, 16>::splat(0); V]; NR];
+
+        for ki in k_start..min(k, k_start + I16_STEPS) {
+            let a_val: [Simd<u8, 16>; V] =
+                std::array::from_fn(|v| Simd::from_slice(&a_vals[(ki * mr + 16 * v)..]));
+            let a_sign: [Simd<u8, 16>; V] =
+                std::array::from_fn(|v| Simd::from_slice(&a_signs[(ki * mr + 16 * v)..]));
+
+            for (j, ab16_j) in ab16.iter_mut().enumerate() {
+                let b_val_j = Simd::splat(b_vals[ki * NR + j]);
+                let b_sign_j = Simd::splat(b_signs[ki * NR + j]);
+
+                for (ab16_jv, (a_val_v, a_sign_v)) in
+                    ab16_j.iter_mut().zip(a_val.iter().zip(&a_sign))
+                {
+                    *ab16_jv += dot::<P>(*a_val_v, *a_sign_v, b_val_j, b_sign_j).cast::<i16>();
+                }
+            }
+        }
+
+        for (ab_j, ab16_j) in ab.iter_mut().zip(ab16) {
+            for (ab_jv, ab16_jv) in ab_j.iter_mut().zip(ab16_j) {
+                *ab_jv += ab16_jv.cast::<i32>();
+            }
+        }
+    }
+
+    for (j, ab_j) in ab.iter().enumerate() {
+        for (v, ab_jv) in ab_j.iter().enumerate() {
+            c[at(16 * v, j, ldc)..(at(16 * v, j, ldc) + 16)].copy_from_slice(&ab_jv.to_array());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dot16x16_i32, dot_small_i32, Portable, I16_STEPS};
+
+    #[test]
+    fn test_i16_flush() {
+        // All ones, so every element of C is 8 * k, far outside of i16
+        let k = 3 * I16_STEPS + 5;
+        let ones = vec![0xff_u8; 16 * k];
+        let zeros = vec![0_u8; 16 * k];
+        let mut c = vec![1_i32; 16 * 16];
+
+        dot16x16_i32::<Portable>(k, &ones, &zeros, &ones, &zeros, &mut c, 16);
+        assert!(c.iter().all(|e| *e == 8 * k as i32 + 1));
+
+        // Flip the sign of A
+        dot16x16_i32::<Portable>(k, &ones, &ones, &ones, &zeros, &mut c, 16);
+        assert!(c.iter().all(|e| *e == 1));
+    }
+
+    #[test]
+    fn test_small_i16_flush() {
+        let k = 3 * I16_STEPS + 5;
+        let ones = vec![0xff_u8; 64 * k];
+        let zeros = vec![0_u8; 64 * k];
+
+        let mut c = vec![1_i32; 64 * 4];
+        dot_small_i32::<Portable, 4, 4>(k, &ones, &ones, &ones, &zeros, &mut c, 64);
+        assert!(c.iter().all(|e| *e == 1 - 8 * k as i32));
+
+        let mut c = vec![1_i32; 32 * 8];
+        dot_small_i32::<Portable, 2, 8>(k, &ones, &zeros, &ones, &zeros, &mut c, 32);
+        assert!(c.iter().all(|e| *e == 1 + 8 * k as i32));
+    }
+}
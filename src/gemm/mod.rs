@@ -0,0 +1,1153 @@
+//! General-shape driver for the compressed (val/sign) ternary matmul.
+//!
+//! Same approach as `muls::mm9`, but m, k and n are runtime values. Edge tiles where m or n
+//! is not a multiple of the microkernel tile are computed into a zero padded tile and copied
+//! back, and k is padded with zero trits up to a multiple of 8 during compression.
+//!
+//! Compressed matrices are compressed along k, so compressed A is `m x ceil(k / 8)` and
+//! compressed B is `ceil(k / 8) x n`. [`gemm`] takes either layout plus a leading dimension
+//! for A and B, like BLAS `sgemm`, and C is always col-major.
+//!
+//! The driver packs B once and splits C into a grid of blocks over n and m, one per thread
+//! (see [`set_threads`]). Every thread packs its own panels of A and writes straight into its
+//! cols of C.
+//!
+//! Small batches (n up to 8) use tall and narrow 64x4 or 32x8 tiles instead of 16x16 ones,
+//! which would mostly compute padding.
+//!
+//! Fixed weights can be compressed and packed once into a [`PackedTernaryMatrix`], which
+//! [`ternary_gemm_packed`] multiplies without packing A again.
+//!
+//! [`ternary_gemm`] accumulates into i32 with the best microkernel for the CPU, see
+//! [`Kernel`]. [`gemm_int8`] multiplies compressed ternary weights with int8 activations instead
+//! and [`gemm_base3`] does the same for weights packed 5 trits per byte. [`gemv_int8`] is the
+//! matrix-vector product for a single col of activations (batch size 1), `gemm_int8` uses it
+//! for n = 1.
+use std::{cmp::min, mem, ops::Range};
+
+#[cfg(target_arch = "x86_64")]
+mod avx2;
+#[cfg(target_arch = "x86_64")]
+mod avx512;
+mod base3;
+mod compress;
+mod dispatch;
+mod epilogue;
+mod error;
+mod gemv;
+mod int8;
+mod kernel;
+mod pack;
+mod packed;
+mod quantize;
+mod threads;
+
+pub use base3::{
+    decode_base3, encode_base3, gemm_base3, matmul_base3, try_decode_base3, try_encode_base3,
+    try_gemm_base3,
+};
+pub use compress::{compress_a, compress_b, try_compress_a, try_compress_b, validate_trits};
+pub use dispatch::{selected_kernel, set_kernel, Kernel, KERNEL_ENV};
+pub use epilogue::{Epilogue, Scale};
+pub use error::TernaryError;
+pub use gemv::{gemv_int8, matvec_int8, try_gemv_int8};
+pub use int8::{
+    gemm_int8, gemm_int8_f32, gemm_int8_f32_packed, matmul_int8, matmul_int8_f32, try_gemm_int8,
+    try_gemm_int8_f32, try_gemm_int8_f32_packed,
+};
+pub(crate) use kernel::popcount;
+use kernel::{dot16x16, dot16x16_i32, dot_small, dot_small_i32, Portable};
+use pack::{pack_a, pack_b};
+pub use packed::{
+    matmul_packed, ternary_gemm_packed, try_ternary_gemm_packed, PackedTernaryMatrix,
+};
+pub use quantize::{
+    quantize_activations, quantize_activations_packed, quantize_weights, try_quantize_activations,
+    try_quantize_activations_packed, try_quantize_weights, Bf16, Float, Granularity,
+    PackedActivations, QuantizedActivations, TernaryTensor, TernaryWeights,
+};
+use threads::{grid, split};
+pub use threads::{set_threads, threads, THREADS_ENV};
+
+// Tile size of the 16x16 microkernels
+const MR: usize = 16;
+const NR: usize = 16;
+
+// Blocking parameters, k is in compressed bytes (8 ternary values each)
+const MC: usize = 256;
+const KC: usize = 512;
+const NC: usize = 256;
+
+fn at(r: usize, c: usize, cstride: usize) -> usize {
+    r + cstride * c
+}
+
+/// Memory order of a matrix with a leading dimension `ld`, the stride between columns
+/// (col-major) or rows (row-major).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    RowMajor,
+    ColMajor,
+}
+
+impl Layout {
+    // Index of element (r, c)
+    fn at(self, r: usize, c: usize, ld: usize) -> usize {
+        match self {
+            Layout::ColMajor => r + ld * c,
+            Layout::RowMajor => r * ld + c,
+        }
+    }
+
+    // Checks that a `rows x cols` matrix with leading dimension ld fits in len elements. `names`
+    // are the names of the buffer and of its leading dimension for the error.
+    fn validate(
+        self,
+        (buffer, ld_name): (&'static str, &'static str),
+        rows: usize,
+        cols: usize,
+        len: usize,
+        ld: usize,
+    ) -> Result<(), TernaryError> {
+        let min_ld = match self {
+            Layout::ColMajor => rows,
+            Layout::RowMajor => cols,
+        };
+        if ld < min_ld.max(1) {
+            return Err(TernaryError::ShapeMismatch {
+                what: ld_name,
+                expected: min_ld.max(1),
+                found: ld,
+            });
+        }
+        if rows > 0 && cols > 0 && len <= self.at(rows - 1, cols - 1, ld) {
+            return Err(TernaryError::BufferTooSmall {
+                what: buffer,
+                len,
+                required: self.at(rows - 1, cols - 1, ld) + 1,
+            });
+        }
+        Ok(())
+    }
+
+    // Panicking version of `validate`
+    fn check(self, rows: usize, cols: usize, len: usize, ld: usize) {
+        let names = ("matrix", "leading dimension");
+        if let Err(err) = self.validate(names, rows, cols, len, ld) {
+            panic!("{}", err);
+        }
+    }
+}
+
+// run(k, a_vals, a_signs, b_vals, b_signs, c, ldc)
+type MicrokernelFn<T> = unsafe fn(usize, &[u8], &[u8], &[u8], &[u8], &mut [T], usize);
+
+// A microkernel that computes an mr x nr block of C from an mr-row panel of A and an nr-col
+// panel of B. `run` is unsafe as it may rely on CPU features, only construct kernels the CPU
+// supports.
+#[derive(Clone, Copy)]
+struct Microkernel<T> {
+    mr: usize,
+    nr: usize,
+    run: MicrokernelFn<T>,
+}
+
+const DOT16X16: Microkernel<i8> = Microkernel {
+    mr: MR,
+    nr: NR,
+    run: dot16x16,
+};
+
+const DOT16X16_I32: Microkernel<i32> = Microkernel {
+    mr: MR,
+    nr: NR,
+    run: dot16x16_i32::<Portable>,
+};
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+const NEON16X16_I32: Microkernel<i32> = Microkernel {
+    mr: MR,
+    nr: NR,
+    run: dot16x16_i32::<kernel::Neon>,
+};
+
+// Tall and narrow tiles for small batches (speculative decoding, small-batch serving), where
+// most of a 16x16 tile would be padding
+const DOT64X4: Microkernel<i8> = Microkernel {
+    mr: 64,
+    nr: 4,
+    run: dot_small::<4, 4>,
+};
+
+const DOT32X8: Microkernel<i8> = Microkernel {
+    mr: 32,
+    nr: 8,
+    run: dot_small::<2, 8>,
+};
+
+const DOT64X4_I32: Microkernel<i32> = Microkernel {
+    mr: 64,
+    nr: 4,
+    run: dot_small_i32::<Portable, 4, 4>,
+};
+
+const DOT32X8_I32: Microkernel<i32> = Microkernel {
+    mr: 32,
+    nr: 8,
+    run: dot_small_i32::<Portable, 2, 8>,
+};
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+const NEON64X4_I32: Microkernel<i32> = Microkernel {
+    mr: 64,
+    nr: 4,
+    run: dot_small_i32::<kernel::Neon, 4, 4>,
+};
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+const NEON32X8_I32: Microkernel<i32> = Microkernel {
+    mr: 32,
+    nr: 8,
+    run: dot_small_i32::<kernel::Neon, 2, 8>,
+};
+
+// Picks the tile shape from the number of cols of B, the 64x4 or 32x8 kernel in small for
+// n <= 8 and the given (16x16) kernel otherwise
+fn pick<T: Copy>(n: usize, kernel: Microkernel<T>, small: [Microkernel<T>; 2]) -> Microkernel<T> {
+    match n {
+        0..=4 => small[0],
+        // NEON can broadcast cols of B straight from memory, which favours the wider 32x8 tile.
+        // x86_64 has no such load, so it keeps the 64x4 tile that loads more rows of A per col
+        // instead. Neither choice has been benchmarked yet.
+        5..=8 if cfg!(target_arch = "aarch64") => small[1],
+        5..=8 => small[0],
+        _ => kernel,
+    }
+}
+
+// Expects a and b to be packed, c is col-major with stride ldc
+#[allow(clippy::too_many_arguments)]
+fn inner_kernel<T: Copy + Default>(
+    kernel: Microkernel<T>,
+    m: usize,
+    k: usize,
+    n: usize,
+    packed_a_vals: &[u8],
+    packed_a_signs: &[u8],
+    packed_b_vals: &[u8],
+    packed_b_signs: &[u8],
+    c: &mut [T],
+    ldc: usize,
+) {
+    let (mr, nr) = (kernel.mr, kernel.nr);
+    let mut tile = vec![T::default(); mr * nr];
+
+    for ni in (0..n).step_by(nr) {
+        for mi in (0..m).step_by(mr) {
+            let a_vals = &packed_a_vals[(mi * k)..];
+            let a_signs = &packed_a_signs[(mi * k)..];
+            let b_vals = &packed_b_vals[(ni * k)..];
+            let b_signs = &packed_b_signs[(ni * k)..];
+
+            let rows = min(m - mi, mr);
+            let cols = min(n - ni, nr);
+            if rows == mr && cols == nr {
+                // Safety: kernels are only constructed for supported CPUs
+                unsafe {
+                    (kernel.run)(
+                        k,
+                        a_vals,
+                        a_signs,
+                        b_vals,
+                        b_signs,
+                        &mut c[at(mi, ni, ldc)..],
+                        ldc,
+                    );
+                }
+                continue;
+            }
+
+            // Edge tile: run the full microkernel on a padded copy and only write back the
+            // part that lies inside C
+            tile.fill(T::default());
+            for j in 0..cols {
+                let src = at(mi, ni + j, ldc);
+                tile[at(0, j, mr)..(at(0, j, mr) + rows)].copy_from_slice(&c[src..src + rows]);
+            }
+            unsafe {
+                (kernel.run)(k, a_vals, a_signs, b_vals, b_signs, &mut tile, mr);
+            }
+            for j in 0..cols {
+                let dst = at(mi, ni + j, ldc);
+                c[dst..dst + rows].copy_from_slice(&tile[at(0, j, mr)..(at(0, j, mr) + rows)]);
+            }
+        }
+    }
+}
+
+/// Compresses a col-major `m x k` matrix A and a col-major `k x n` matrix B.
+/// Returns `(a_vals, a_signs, b_vals, b_signs)` in the layout [`matmul`] expects.
+pub fn prep(
+    m: usize,
+    k: usize,
+    n: usize,
+    a: &[i8],
+    b: &[i8],
+) -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) {
+    try_prep(m, k, n, a, b).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`prep`], but returns an error for invalid values or buffers that don't fit the
+/// shapes.
+#[allow(clippy::type_complexity)]
+pub fn try_prep(
+    m: usize,
+    k: usize,
+    n: usize,
+    a: &[i8],
+    b: &[i8],
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>), TernaryError> {
+    let (a_vals, a_signs) = try_compress_a(m, k, a, Layout::ColMajor, m)?;
+    let (b_vals, b_signs) = try_compress_b(k, n, b, Layout::ColMajor, k)?;
+    Ok((a_vals, a_signs, b_vals, b_signs))
+}
+
+// Checks the operands of a gemm of compressed A (`m x k`) and B (`k x n`) into col-major C
+#[allow(clippy::too_many_arguments)]
+fn validate_gemm(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    (a_vals, a_signs): (usize, usize),
+    lda: usize,
+    (b_vals, b_signs): (usize, usize),
+    ldb: usize,
+    c: usize,
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    let kb = k.div_ceil(8);
+    a_layout.validate(("a_vals", "lda"), m, kb, a_vals, lda)?;
+    a_layout.validate(("a_signs", "lda"), m, kb, a_signs, lda)?;
+    b_layout.validate(("b_vals", "ldb"), kb, n, b_vals, ldb)?;
+    b_layout.validate(("b_signs", "ldb"), kb, n, b_signs, ldb)?;
+    Layout::ColMajor.validate(("c", "ldc"), m, n, c, ldc)
+}
+
+// B packed into nr-col panels once for the whole gemm, one (vals, signs) pair per kc block
+type PackedB = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Where `gemm_block` gets the mr-row panels of A from
+#[derive(Clone, Copy)]
+enum PanelsA<'a> {
+    // Compressed A, packed into the panels of every mc x kc block on the fly
+    Compressed {
+        layout: Layout,
+        vals: &'a [u8],
+        signs: &'a [u8],
+        lda: usize,
+    },
+    // Packed once up front, loop 3 only looks up the panels
+    Packed(&'a PackedTernaryMatrix<'a>),
+}
+
+// Runs loops 5, 4 and 3 for the rows and cols of C in a block, with c starting at
+// (rows.start, cols.start). Every call packs compressed A into its own buffers.
+#[allow(clippy::too_many_arguments)]
+fn gemm_block<T: Copy + Default>(
+    kernel: Microkernel<T>,
+    rows: Range<usize>,
+    cols: Range<usize>,
+    kb: usize,
+    a: PanelsA,
+    packed_b: &PackedB,
+    c: &mut [T],
+    ldc: usize,
+) {
+    let buffer_size = match a {
+        PanelsA::Compressed { .. } => MC * KC,
+        PanelsA::Packed(_) => 0,
+    };
+    let mut packed_a_vals = vec![0_u8; buffer_size];
+    let mut packed_a_signs = vec![0_u8; buffer_size];
+
+    // LOOP 5: Split B and C on the n-dimension into parts of nc size
+    for ni in cols.clone().step_by(NC) {
+        let tile_n = min(cols.end - ni, NC);
+
+        // LOOP 4: Split A and B on the k-dimension into parts of kc size
+        for (ki, (packed_b_vals, packed_b_signs)) in (0..kb).step_by(KC).zip(packed_b) {
+            let tile_k = min(kb - ki, KC);
+
+            // LOOP 3: Split A and C on the m-dimension into parts of mc
+            for mi in rows.clone().step_by(MC) {
+                let tile_m = min(rows.end - mi, MC);
+
+                let (panels_vals, panels_signs) = match a {
+                    PanelsA::Compressed {
+                        layout,
+                        vals,
+                        signs,
+                        lda,
+                    } => {
+                        let a_start = layout.at(mi, ki, lda);
+                        pack_a(
+                            tile_m,
+                            tile_k,
+                            &vals[a_start..],
+                            layout,
+                            lda,
+                            kernel.mr,
+                            &mut packed_a_vals,
+                        );
+                        pack_a(
+                            tile_m,
+                            tile_k,
+                            &signs[a_start..],
+                            layout,
+                            lda,
+                            kernel.mr,
+                            &mut packed_a_signs,
+                        );
+                        (&packed_a_vals[..], &packed_a_signs[..])
+                    }
+                    PanelsA::Packed(packed) => packed.panels(mi, ki),
+                };
+
+                inner_kernel(
+                    kernel,
+                    tile_m,
+                    tile_k,
+                    tile_n,
+                    panels_vals,
+                    panels_signs,
+                    &packed_b_vals[(ni * tile_k)..],
+                    &packed_b_signs[(ni * tile_k)..],
+                    &mut c[at(mi - rows.start, ni - cols.start, ldc)..],
+                    ldc,
+                );
+            }
+        }
+    }
+}
+
+// Multithreaded driver for compressed A, see `gemm_panels`
+#[allow(clippy::too_many_arguments)]
+fn gemm_with<T: Copy + Default + Send + Sync>(
+    kernel: Microkernel<T>,
+    threads: usize,
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [T],
+    ldc: usize,
+) {
+    a_layout.check(m, k.div_ceil(8), a_vals.len().min(a_signs.len()), lda);
+    let a = PanelsA::Compressed {
+        layout: a_layout,
+        vals: a_vals,
+        signs: a_signs,
+        lda,
+    };
+    gemm_panels(
+        kernel, threads, a, b_layout, m, n, k, b_vals, b_signs, ldb, c, ldc,
+    );
+}
+
+// Multithreaded driver. B is packed once and shared, C is split into a grid of blocks (see
+// `threads::grid`) and `gemm_block` runs on every block in the rayon thread pool.
+#[allow(clippy::too_many_arguments)]
+fn gemm_panels<T: Copy + Default + Send + Sync>(
+    kernel: Microkernel<T>,
+    threads: usize,
+    a: PanelsA,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [T],
+    ldc: usize,
+) {
+    let kb = k.div_ceil(8);
+    b_layout.check(kb, n, b_vals.len().min(b_signs.len()), ldb);
+    Layout::ColMajor.check(m, n, c.len(), ldc);
+    if m == 0 || n == 0 || kb == 0 {
+        return;
+    }
+    // Panels can't straddle the mc/nc blocks
+    debug_assert!(MC.is_multiple_of(kernel.mr) && NC.is_multiple_of(kernel.nr));
+
+    // Pack all of B once, panels of the kc blocks are shared by all threads
+    let packed_b: PackedB = (0..kb)
+        .step_by(KC)
+        .map(|ki| {
+            let tile_k = min(kb - ki, KC);
+            let b_start = b_layout.at(ki, 0, ldb);
+            let size = tile_k * n.next_multiple_of(kernel.nr);
+            let (mut packed_vals, mut packed_signs) = (vec![0_u8; size], vec![0_u8; size]);
+            pack_b(
+                tile_k,
+                n,
+                &b_vals[b_start..],
+                b_layout,
+                ldb,
+                kernel.nr,
+                &mut packed_vals,
+            );
+            pack_b(
+                tile_k,
+                n,
+                &b_signs[b_start..],
+                b_layout,
+                ldb,
+                kernel.nr,
+                &mut packed_signs,
+            );
+            (packed_vals, packed_signs)
+        })
+        .collect();
+
+    let (threads_m, threads_n) = grid(threads, m.div_ceil(kernel.mr), n.div_ceil(kernel.nr));
+    if threads_m * threads_n == 1 {
+        gemm_block(kernel, 0..m, 0..n, kb, a, &packed_b, c, ldc);
+        return;
+    }
+
+    let rows = split(m, kernel.mr, threads_m);
+    let cols = split(n, kernel.nr, threads_n);
+    let packed_b = &packed_b;
+    rayon::scope(|s| {
+        let mut rest = c;
+        for cols in cols {
+            // The cols of C are contiguous, every col range gets its own slice of C
+            let len = if cols.end == n {
+                rest.len()
+            } else {
+                cols.len() * ldc
+            };
+            let (c_cols, tail) = mem::take(&mut rest).split_at_mut(len);
+            rest = tail;
+            if threads_m == 1 {
+                s.spawn(move |_| gemm_block(kernel, 0..m, cols, kb, a, packed_b, c_cols, ldc));
+                continue;
+            }
+
+            // Blocks of rows aren't contiguous in col-major C, so threads that share cols
+            // compute into a copy of their block. `grid` only splits m if there are fewer col
+            // panels than threads, so the blocks are at most a few panels wide.
+            let mut blocks: Vec<Vec<&mut [T]>> = rows.iter().map(|_| vec![]).collect();
+            for col in c_cols.chunks_mut(ldc).take(cols.len()) {
+                let mut col = &mut col[..m];
+                for (block, range) in blocks.iter_mut().zip(&rows) {
+                    let (part, tail) = col.split_at_mut(range.len());
+                    block.push(part);
+                    col = tail;
+                }
+            }
+            for (block, rows) in blocks.into_iter().zip(rows.iter().cloned()) {
+                let cols = cols.clone();
+                s.spawn(move |_| {
+                    let mut c_block: Vec<T> =
+                        block.iter().flat_map(|col| col.iter().copied()).collect();
+                    let ldc_block = rows.len();
+                    gemm_block(kernel, rows, cols, kb, a, packed_b, &mut c_block, ldc_block);
+                    for (col, c_col) in block.into_iter().zip(c_block.chunks(ldc_block)) {
+                        col.copy_from_slice(c_col);
+                    }
+                });
+            }
+        }
+    });
+}
+
+/// Computes `C += A * B` for compressed A (`m x k`) and B (`k x n`) with leading dimensions
+/// lda and ldb (in compressed bytes), writing into col-major C with leading dimension ldc.
+/// Mirrors BLAS `sgemm` with `alpha = beta = 1`. Accumulates in i8, so results wrap like
+/// `matmul9`. Splits the work between [`threads`] threads.
+#[allow(clippy::too_many_arguments)]
+pub fn gemm(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [i8],
+    ldc: usize,
+) {
+    gemm_with(
+        pick(n, DOT16X16, [DOT64X4, DOT32X8]),
+        threads(),
+        a_layout,
+        b_layout,
+        m,
+        n,
+        k,
+        a_vals,
+        a_signs,
+        lda,
+        b_vals,
+        b_signs,
+        ldb,
+        c,
+        ldc,
+    );
+}
+
+/// Same as [`gemm`], but accumulates into i32. The microkernels widen their i8 dot products
+/// into wider lanes before they can overflow, so any k fitting in i32 is exact. Runs the
+/// microkernel picked by [`selected_kernel`], the fastest one the CPU supports by default.
+#[allow(clippy::too_many_arguments)]
+pub fn ternary_gemm(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) {
+    gemm_with(
+        selected_kernel().microkernel_for(n),
+        threads(),
+        a_layout,
+        b_layout,
+        m,
+        n,
+        k,
+        a_vals,
+        a_signs,
+        lda,
+        b_vals,
+        b_signs,
+        ldb,
+        c,
+        ldc,
+    );
+}
+
+/// Same as [`gemm`], but returns an error instead of panicking if a buffer or leading
+/// dimension doesn't fit the shapes.
+#[allow(clippy::too_many_arguments)]
+pub fn try_gemm(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [i8],
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    validate_gemm(
+        a_layout,
+        b_layout,
+        m,
+        n,
+        k,
+        (a_vals.len(), a_signs.len()),
+        lda,
+        (b_vals.len(), b_signs.len()),
+        ldb,
+        c.len(),
+        ldc,
+    )?;
+    gemm(
+        a_layout, b_layout, m, n, k, a_vals, a_signs, lda, b_vals, b_signs, ldb, c, ldc,
+    );
+    Ok(())
+}
+
+/// Same as [`ternary_gemm`], but returns an error instead of panicking if a buffer or leading
+/// dimension doesn't fit the shapes.
+#[allow(clippy::too_many_arguments)]
+pub fn try_ternary_gemm(
+    a_layout: Layout,
+    b_layout: Layout,
+    m: usize,
+    n: usize,
+    k: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    lda: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    validate_gemm(
+        a_layout,
+        b_layout,
+        m,
+        n,
+        k,
+        (a_vals.len(), a_signs.len()),
+        lda,
+        (b_vals.len(), b_signs.len()),
+        ldb,
+        c.len(),
+        ldc,
+    )?;
+    ternary_gemm(
+        a_layout, b_layout, m, n, k, a_vals, a_signs, lda, b_vals, b_signs, ldb, c, ldc,
+    );
+    Ok(())
+}
+
+// Multiplies contiguous col-major A and B with the given microkernel into a new C
+#[allow(clippy::too_many_arguments)]
+fn matmul_with<T: Copy + Default + Send + Sync>(
+    kernel: Microkernel<T>,
+    m: usize,
+    k: usize,
+    n: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+) -> Vec<T> {
+    let kb = k.div_ceil(8);
+    let mut c = vec![T::default(); m * n];
+    gemm_with(
+        kernel,
+        threads(),
+        Layout::ColMajor,
+        Layout::ColMajor,
+        m,
+        n,
+        k,
+        a_vals,
+        a_signs,
+        m.max(1),
+        b_vals,
+        b_signs,
+        kb.max(1),
+        &mut c,
+        m.max(1),
+    );
+    c
+}
+
+/// Multiplies compressed, contiguous col-major A (`m x k`) with B (`k x n`) as produced by
+/// [`prep`] into a new col-major `m x n` C.
+pub fn matmul(
+    m: usize,
+    k: usize,
+    n: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+) -> Vec<i8> {
+    matmul_with(
+        pick(n, DOT16X16, [DOT64X4, DOT32X8]),
+        m,
+        k,
+        n,
+        a_vals,
+        a_signs,
+        b_vals,
+        b_signs,
+    )
+}
+
+/// Same as [`matmul`], but with exact i32 results.
+pub fn matmul_i32(
+    m: usize,
+    k: usize,
+    n: usize,
+    a_vals: &[u8],
+    a_signs: &[u8],
+    b_vals: &[u8],
+    b_signs: &[u8],
+) -> Vec<i32> {
+    matmul_with(
+        selected_kernel().microkernel_for(n),
+        m,
+        k,
+        n,
+        a_vals,
+        a_signs,
+        b_vals,
+        b_signs,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2, ShapeBuilder};
+
+    use crate::{
+        constants::SIZE,
+        muls::mm9::{matmul9, prep9},
+        test_util::test_util::{
+            rand_vecs, rand_vecs_sized, test_matmul_i32_shape, test_matmul_shape,
+        },
+    };
+
+    use super::{
+        compress_a, compress_b, gemm, gemm_with, matmul, matmul_i32, matmul_with, prep,
+        selected_kernel, try_gemm, try_gemm_int8, try_prep, try_ternary_gemm, Layout, Microkernel,
+        TernaryError, DOT16X16, DOT16X16_I32, DOT32X8, DOT32X8_I32, DOT64X4, DOT64X4_I32,
+    };
+
+    fn test_shape(m: usize, k: usize, n: usize) {
+        test_matmul_shape(m, k, n, |a, b| {
+            let (av, asi, bv, bs) = prep(m, k, n, a, b);
+            matmul(m, k, n, &av, &asi, &bv, &bs)
+        })
+    }
+
+    #[test]
+    fn test_square() {
+        test_shape(SIZE, SIZE, SIZE);
+    }
+
+    #[test]
+    fn test_batch_sizes() {
+        for n in [1, 7, 33] {
+            test_shape(96, 256, n);
+        }
+    }
+
+    #[test]
+    fn test_edge_tiles() {
+        test_shape(37, 13, 21);
+        test_shape(1, 1, 1);
+    }
+
+    #[test]
+    fn test_small_batches() {
+        for n in 2..=8 {
+            test_shape(100, 300, n);
+            test_shape_i32(100, 300, n);
+        }
+    }
+
+    #[test]
+    fn test_small_batch_tiles() {
+        // Every tile, whether or not the driver picks it on this target, with edge tiles
+        let (m, k) = (70, 2100);
+        for n in [1, 4, 5, 8, 11] {
+            for kernel in [DOT64X4, DOT32X8] {
+                test_matmul_shape(m, k, n, |a, b| {
+                    let (av, asi, bv, bs) = prep(m, k, n, a, b);
+                    matmul_with(kernel, m, k, n, &av, &asi, &bv, &bs)
+                });
+            }
+            for kernel in [DOT64X4_I32, DOT32X8_I32] {
+                test_matmul_i32_shape(m, k, n, |a, b| {
+                    let (av, asi, bv, bs) = prep(m, k, n, a, b);
+                    matmul_with(kernel, m, k, n, &av, &asi, &bv, &bs)
+                });
+            }
+        }
+    }
+
+    fn test_shape_i32(m: usize, k: usize, n: usize) {
+        test_matmul_i32_shape(m, k, n, |a, b| {
+            let (av, asi, bv, bs) = prep(m, k, n, a, b);
+            matmul_i32(m, k, n, &av, &asi, &bv, &bs)
+        })
+    }
+
+    #[test]
+    fn test_i32() {
+        test_shape_i32(SIZE, SIZE, SIZE);
+        test_shape_i32(37, 13, 21);
+        test_shape_i32(20, 16384, 5);
+    }
+
+    #[test]
+    fn test_i32_portable() {
+        test_matmul_i32_shape(37, 5000, 21, |a, b| {
+            let (av, asi, bv, bs) = prep(37, 5000, 21, a, b);
+            matmul_with(DOT16X16_I32, 37, 5000, 21, &av, &asi, &bv, &bs)
+        });
+    }
+
+    // Multiplies col-major A and B with a fixed number of threads
+    fn matmul_threads<T: Copy + Default + Send + Sync>(
+        kernel: Microkernel<T>,
+        threads: usize,
+        (m, k, n): (usize, usize, usize),
+        a: &[i8],
+        b: &[i8],
+    ) -> Vec<T> {
+        let (av, asi, bv, bs) = prep(m, k, n, a, b);
+        let mut c = vec![T::default(); m * n];
+        gemm_with(
+            kernel,
+            threads,
+            Layout::ColMajor,
+            Layout::ColMajor,
+            m,
+            n,
+            k,
+            &av,
+            &asi,
+            m,
+            &bv,
+            &bs,
+            k.div_ceil(8),
+            &mut c,
+            m,
+        );
+        c
+    }
+
+    #[test]
+    fn test_threads_match_mm9() {
+        let (a, b) = rand_vecs(SIZE * SIZE);
+        let (av, asi, bv, bs) = prep9(&a, &b);
+        let expected = matmul9(&av, &asi, &bv, &bs);
+
+        for threads in [1, 2, 3, 8] {
+            let c = matmul_threads(DOT16X16, threads, (SIZE, SIZE, SIZE), &a, &b);
+            assert_eq!(c, expected, "threads = {}", threads);
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        // Splits m, n or both, with edge tiles and more threads than panels
+        for (m, k, n) in [(300, 4100, 7), (37, 13, 270), (270, 600, 260), (1, 9, 1)] {
+            let (a, b) = rand_vecs_sized(m * k, k * n);
+            let kernel = selected_kernel().microkernel();
+            let expected = matmul_threads(kernel, 1, (m, k, n), &a, &b);
+            for threads in [2, 5, 16] {
+                let c = matmul_threads(kernel, threads, (m, k, n), &a, &b);
+                assert_eq!(c, expected, "m = {}, k = {}, n = {}", m, k, n);
+            }
+        }
+        test_matmul_i32_shape(270, 600, 260, |a, b| {
+            matmul_threads(DOT16X16_I32, 4, (270, 600, 260), a, b)
+        });
+    }
+
+    #[test]
+    fn test_i32_extremes() {
+        // Rows of A and cols of B that are all the same sign hit the full [-k, k] range
+        let (m, k, n) = (17, 16384, 3);
+        let mut a = vec![1; m * k];
+        for ki in 0..k {
+            a[1 + m * ki] = -1;
+            a[2 + m * ki] = 0;
+        }
+        let b = vec![1; k * n];
+
+        let (av, asi, bv, bs) = prep(m, k, n, &a, &b);
+        let c = matmul_i32(m, k, n, &av, &asi, &bv, &bs);
+
+        for ni in 0..n {
+            assert_eq!(c[m * ni], k as i32);
+            assert_eq!(c[1 + m * ni], -(k as i32));
+            assert_eq!(c[2 + m * ni], 0);
+        }
+    }
+
+    #[test]
+    fn test_multiple_blocks() {
+        // Larger than MC, KC (in compressed bytes) and NC
+        test_shape(270, 4100, 260);
+    }
+
+    // Copies a contiguous col-major matrix into a buffer with the given layout and leading
+    // dimension, filling the padding with zeros
+    fn strided(rows: usize, cols: usize, x: &[i8], layout: Layout, ld: usize) -> Vec<i8> {
+        let len = match layout {
+            Layout::ColMajor => ld * cols,
+            Layout::RowMajor => rows * ld,
+        };
+        let mut out = vec![0; len];
+        for c in 0..cols {
+            for r in 0..rows {
+                out[layout.at(r, c, ld)] = x[r + rows * c];
+            }
+        }
+        out
+    }
+
+    fn to_array(rows: usize, cols: usize, x: &[i8]) -> Array2<i32> {
+        Array2::from_shape_vec((rows, cols).f(), x.iter().map(|e| *e as i32).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_layouts() {
+        let (m, k, n) = (37, 100, 21);
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        let res_true = to_array(m, k, &a).dot(&to_array(k, n, &b));
+
+        for a_layout in [Layout::ColMajor, Layout::RowMajor] {
+            for b_layout in [Layout::ColMajor, Layout::RowMajor] {
+                // Raw inputs with padding between rows/cols
+                let lda = match a_layout {
+                    Layout::ColMajor => m + 3,
+                    Layout::RowMajor => k + 5,
+                };
+                let ldb = match b_layout {
+                    Layout::ColMajor => k + 2,
+                    Layout::RowMajor => n + 7,
+                };
+                let a_raw = strided(m, k, &a, a_layout, lda);
+                let b_raw = strided(k, n, &b, b_layout, ldb);
+
+                let (av, asi) = compress_a(m, k, &a_raw, a_layout, lda);
+                let (bv, bs) = compress_b(k, n, &b_raw, b_layout, ldb);
+                let kb = k.div_ceil(8);
+                let lda = if a_layout == Layout::ColMajor { m } else { kb };
+                let ldb = if b_layout == Layout::ColMajor { kb } else { n };
+
+                let mut c = vec![0; m * n];
+                gemm(
+                    a_layout, b_layout, m, n, k, &av, &asi, lda, &bv, &bs, ldb, &mut c, m,
+                );
+
+                assert_eq!(
+                    to_array(m, n, &c),
+                    res_true,
+                    "{:?} x {:?}",
+                    a_layout,
+                    b_layout
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sub_views() {
+        // One head out of a fused row-major QKV weight, multiplied into a window of a larger C
+        let (heads, h, k, n) = (3, 24, 90, 10);
+        let (qkv, b) = rand_vecs_sized(heads * h * k, k * n);
+        let qkv = strided(heads * h, k, &qkv, Layout::RowMajor, k);
+        let (av, asi) = compress_a(heads * h, k, &qkv, Layout::RowMajor, k);
+        let (bv, bs) = compress_b(k, n, &b, Layout::ColMajor, k);
+        let kb = k.div_ceil(8);
+
+        let ldc = h + 5;
+        let mut c = vec![1_i8; ldc * (n + 1)];
+        let head = h * kb;
+        gemm(
+            Layout::RowMajor,
+            Layout::ColMajor,
+            h,
+            n,
+            k,
+            &av[head..],
+            &asi[head..],
+            kb,
+            &bv,
+            &bs,
+            kb,
+            &mut c[ldc..],
+            ldc,
+        );
+
+        let a_head =
+            Array2::from_shape_vec((heads * h, k), qkv.iter().map(|e| *e as i32).collect())
+                .unwrap()
+                .slice(s![h..2 * h, ..])
+                .to_owned();
+        let res_true = a_head.dot(&to_array(k, n, &b)) + 1;
+
+        let c_array = to_array(ldc, n + 1, &c);
+        assert_eq!(c_array.slice(s![..h, 1..]), res_true);
+        // Everything outside the view is untouched
+        assert!(c_array.slice(s![h.., ..]).iter().all(|e| *e == 1));
+        assert!(c_array.slice(s![.., 0]).iter().all(|e| *e == 1));
+    }
+
+    #[test]
+    fn test_try_gemm() {
+        let (m, k, n) = (20, 30, 10);
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        let (av, asi, bv, bs) = try_prep(m, k, n, &a, &b).unwrap();
+        let kb = k.div_ceil(8);
+
+        let mut c = vec![0; m * n];
+        let col = Layout::ColMajor;
+        try_ternary_gemm(col, col, m, n, k, &av, &asi, m, &bv, &bs, kb, &mut c, m).unwrap();
+        assert_eq!(c, matmul_i32(m, k, n, &av, &asi, &bv, &bs));
+
+        let mut c = vec![0; m * n];
+        try_gemm(col, col, m, n, k, &av, &asi, m, &bv, &bs, kb, &mut c, m).unwrap();
+        assert_eq!(c, matmul(m, k, n, &av, &asi, &bv, &bs));
+
+        // Truncated weights, a too small leading dimension and a too small C
+        let mut c = vec![0; m * n];
+        assert_eq!(
+            try_ternary_gemm(
+                col,
+                col,
+                m,
+                n,
+                k,
+                &av,
+                &asi[1..],
+                m,
+                &bv,
+                &bs,
+                kb,
+                &mut c,
+                m
+            ),
+            Err(TernaryError::BufferTooSmall {
+                what: "a_signs",
+                len: m * kb - 1,
+                required: m * kb
+            })
+        );
+        assert_eq!(
+            try_ternary_gemm(col, col, m, n, k, &av, &asi, m, &bv, &bs, kb - 1, &mut c, m),
+            Err(TernaryError::ShapeMismatch {
+                what: "ldb",
+                expected: kb,
+                found: kb - 1
+            })
+        );
+        assert!(matches!(
+            try_gemm_int8(col, col, m, n, k, &av, &asi, m, &b, k, &mut c[1..], m),
+            Err(TernaryError::BufferTooSmall { what: "c", .. })
+        ));
+        assert!(c.iter().all(|e| *e == 0));
+
+        let mut a = a;
+        a[5] = i8::MIN;
+        assert_eq!(
+            try_prep(m, k, n, &a, &b),
+            Err(TernaryError::InvalidTrit {
+                index: 5,
+                value: i8::MIN
+            })
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use super::Layout;
+
+/// Packs an `m x k` block of compressed A (leading dimension lda) into mr-row panels.
+/// Each panel stores mr contiguous bytes per k, rows past m are zero padded.
+pub fn pack_a(
+    m: usize,
+    k: usize,
+    a: &[u8],
+    layout: Layout,
+    lda: usize,
+    mr: usize,
+    packed: &mut [u8],
+) {
+    let mut offset = 0;
+    // Loop over all mr row horizontal sections
+    for mi in (0..m).step_by(mr) {
+        let rows = mr.min(m - mi);
+        let panel = &mut packed[offset..(offset + mr * k)];
+        match layout {
+            Layout::ColMajor => {
+                // loop over all cols in section, a col of the section is contiguous in a
+                for ki in 0..k {
+                    let start = mi + lda * ki;
+                    panel[(ki * mr)..(ki * mr + rows)].copy_from_slice(&a[start..start + rows]);
+                    panel[(ki * mr + rows)..(ki * mr + mr)].fill(0);
+                }
+            }
+            Layout::RowMajor => {
+                // A row of the section is contiguous in a, scatter it into the panel
+                panel.fill(0);
+                for r in 0..rows {
+                    let row = &a[((mi + r) * lda)..((mi + r) * lda + k)];
+                    for ki in 0..k {
+                        panel[ki * mr + r] = row[ki];
+                    }
+                }
+            }
+        }
+
+        offset += mr * k;
+    }
+}
+
+/// Packs a `k x n` block of compressed B (leading dimension ldb) into nr-col panels.
+/// Each panel stores nr contiguous bytes per k, cols past n are zero padded.
+pub fn pack_b(
+    k: usize,
+    n: usize,
+    b: &[u8],
+    layout: Layout,
+    ldb: usize,
+    nr: usize,
+    packed: &mut [u8],
+) {
+    let mut offset = 0;
+    // Loop over all nr col vertical sections
+    for ni in (0..n).step_by(nr) {
+        let cols = nr.min(n - ni);
+        // Loop over all rows in the section, ensure the cols are contiguous in memory
+        for ki in 0..k {
+            match layout {
+                Layout::ColMajor => {
+                    for j in 0..cols {
+                        packed[offset + j] = b[ki + ldb * (ni + j)];
+                    }
+                }
+                Layout::RowMajor => {
+                    let start = ki * ldb + ni;
+                    packed[offset..(offset + cols)].copy_from_slice(&b[start..start + cols]);
+                }
+            }
+            packed[(offset + cols)..(offset + nr)].fill(0);
+
+            offset += nr;
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! Ternary weights that are compressed and packed once and reused across gemm calls.
+//!
+//! [`super::ternary_gemm`] packs the mc x kc blocks of A into mr-row panels in loop 3 of every
+//! call. For inference the weights are fixed, so [`PackedTernaryMatrix`] does the compression
+//! and packing up front and [`ternary_gemm_packed`] only looks up the panels.
+use std::{borrow::Cow, cmp::min};
+
+use super::{
+    gemm_panels, pack_a, selected_kernel, threads, try_compress_a, Kernel, Layout, PanelsA,
+    TernaryError, KC, MC,
+};
+
+/// A ternary `m x k` matrix A, compressed and packed into the mr-row panels of a microkernel.
+///
+/// The kc blocks of k are stored one after another. Every block holds the mr-row panels of all
+/// m rows (zero padded to a multiple of mr), each `tile_k` compressed bytes deep and storing mr
+/// contiguous bytes per k, exactly the panels `pack_a` creates for the mc x kc blocks.
+///
+/// The panels are owned, or borrowed from a memory-mapped file (see
+/// [`PackedTernaryMatrix::try_from_panels`]), so loading packed weights doesn't copy them.
+pub struct PackedTernaryMatrix<'a> {
+    m: usize,
+    k: usize,
+    kernel: Kernel,
+    mr: usize,
+    mc: usize,
+    kc: usize,
+    vals: Cow<'a, [u8]>,
+    signs: Cow<'a, [u8]>,
+}
+
+impl PackedTernaryMatrix<'static> {
+    /// Compresses and packs an `m x k` ternary matrix with leading dimension lda for the kernel
+    /// picked by [`selected_kernel`].
+    pub fn new(m: usize, k: usize, a: &[i8], layout: Layout, lda: usize) -> Self {
+        Self::with_kernel(selected_kernel(), m, k, a, layout, lda)
+    }
+
+    /// Same as [`PackedTernaryMatrix::new`], but returns an error for invalid values or a
+    /// buffer that doesn't fit the shape.
+    pub fn try_new(
+        m: usize,
+        k: usize,
+        a: &[i8],
+        layout: Layout,
+        lda: usize,
+    ) -> Result<Self, TernaryError> {
+        Self::try_with_kernel(selected_kernel(), m, k, a, layout, lda)
+    }
+
+    /// Same as [`PackedTernaryMatrix::new`], but for the given kernel. Panics if the CPU doesn't
+    /// support the kernel.
+    pub fn with_kernel(
+        kernel: Kernel,
+        m: usize,
+        k: usize,
+        a: &[i8],
+        layout: Layout,
+        lda: usize,
+    ) -> Self {
+        Self::try_with_kernel(kernel, m, k, a, layout, lda).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [`PackedTernaryMatrix::with_kernel`], but returns an error for an unsupported
+    /// kernel, invalid values or a buffer that doesn't fit the shape.
+    pub fn try_with_kernel(
+        kernel: Kernel,
+        m: usize,
+        k: usize,
+        a: &[i8],
+        layout: Layout,
+        lda: usize,
+    ) -> Result<Self, TernaryError> {
+        if !kernel.is_supported() {
+            return Err(TernaryError::UnsupportedKernel { kernel });
+        }
+        let mr = kernel.microkernel().mr;
+        let (vals, signs) = try_compress_a(m, k, a, layout, lda)?;
+
+        let kb = k.div_ceil(8);
+        let m_padded = m.next_multiple_of(mr);
+        let lda = match layout {
+            Layout::ColMajor => m.max(1),
+            Layout::RowMajor => kb.max(1),
+        };
+        let mut packed = Self {
+            m,
+            k,
+            kernel,
+            mr,
+            mc: MC,
+            kc: KC,
+            vals: Cow::Owned(vec![0; m_padded * kb]),
+            signs: Cow::Owned(vec![0; m_padded * kb]),
+        };
+        if m == 0 {
+            return Ok(packed);
+        }
+
+        for ki in (0..kb).step_by(KC) {
+            let tile_k = min(kb - ki, KC);
+            let start = layout.at(0, ki, lda);
+            let block = (ki * m_padded)..((ki + tile_k) * m_padded);
+            pack_a(
+                m,
+                tile_k,
+                &vals[start..],
+                layout,
+                lda,
+                mr,
+                &mut packed.vals.to_mut()[block.clone()],
+            );
+            pack_a(
+                m,
+                tile_k,
+                &signs[start..],
+                layout,
+                lda,
+                mr,
+                &mut packed.signs.to_mut()[block],
+            );
+        }
+        Ok(packed)
+    }
+}
+
+impl<'a> PackedTernaryMatrix<'a> {
+    /// Wraps panels packed by [`PackedTernaryMatrix::new`] for the kernel and the mc/kc
+    /// blocking, see [`PackedTernaryMatrix::panel_bytes`], without copying them. Returns an
+    /// error if the CPU doesn't support the kernel or the panels don't fit the shape.
+    #[allow(clippy::too_many_arguments)]
+    pub fn try_from_panels(
+        kernel: Kernel,
+        m: usize,
+        k: usize,
+        mc: usize,
+        kc: usize,
+        vals: &'a [u8],
+        signs: &'a [u8],
+    ) -> Result<Self, TernaryError> {
+        if !kernel.is_supported() {
+            return Err(TernaryError::UnsupportedKernel { kernel });
+        }
+        let mr = kernel.microkernel().mr;
+        let required = m.next_multiple_of(mr) * k.div_ceil(8);
+        for (what, len) in [("vals", vals.len()), ("signs", signs.len())] {
+            if len != required {
+                return Err(TernaryError::ShapeMismatch {
+                    what,
+                    expected: required,
+                    found: len,
+                });
+            }
+        }
+        Ok(Self {
+            m,
+            k,
+            kernel,
+            mr,
+            mc,
+            kc,
+            vals: Cow::Borrowed(vals),
+            signs: Cow::Borrowed(signs),
+        })
+    }
+
+    /// The packed val and sign panels: the kc blocks one after another, each holding the
+    /// mr-row panels of all rows
+    pub fn panel_bytes(&self) -> (&[u8], &[u8]) {
+        (&self.vals, &self.signs)
+    }
+
+    /// Number of rows
+    pub fn m(&self) -> usize {
+        self.m
+    }
+
+    /// Number of (uncompressed) cols
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// The kernel the panels were packed for
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    /// Rows per panel, the mr of the kernel
+    pub fn mr(&self) -> usize {
+        self.mr
+    }
+
+    /// The mc blocking the matrix was packed for
+    pub fn mc(&self) -> usize {
+        self.mc
+    }
+
+    /// The kc blocking (in compressed bytes) the matrix was packed for
+    pub fn kc(&self) -> usize {
+        self.kc
+    }
+
+    // Panels of the rows from mi (a multiple of mr) in the kc block starting at ki
+    pub(super) fn panels(&self, mi: usize, ki: usize) -> (&[u8], &[u8]) {
+        let kb = self.k.div_ceil(8);
+        let tile_k = min(kb - ki, self.kc);
+        let start = ki * self.m.next_multiple_of(self.mr) + mi * tile_k;
+        (&self.vals[start..], &self.signs[start..])
+    }
+}
+
+/// Computes `C += A * B` like [`super::ternary_gemm`], but for pre-packed A. B (`k x n`,
+/// compressed) has leading dimension ldb and C is col-major with leading dimension ldc.
+/// Uses the kernel A was packed for, small batches only switch tiles if the small-batch tile
+/// has the same mr.
+#[allow(clippy::too_many_arguments)]
+pub fn ternary_gemm_packed(
+    a: &PackedTernaryMatrix<'_>,
+    b_layout: Layout,
+    n: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) {
+    assert!(
+        a.mc == MC && a.kc == KC,
+        "matrix was packed for mc = {}, kc = {}",
+        a.mc,
+        a.kc
+    );
+    let mut kernel = a.kernel.microkernel_for(n);
+    if kernel.mr != a.mr {
+        kernel = a.kernel.microkernel();
+    }
+
+    gemm_panels(
+        kernel,
+        threads(),
+        PanelsA::Packed(a),
+        b_layout,
+        a.m,
+        n,
+        a.k,
+        b_vals,
+        b_signs,
+        ldb,
+        c,
+        ldc,
+    );
+}
+
+/// Same as [`ternary_gemm_packed`], but returns an error instead of panicking if a buffer or
+/// leading dimension doesn't fit the shapes or A was packed for another blocking.
+#[allow(clippy::too_many_arguments)]
+pub fn try_ternary_gemm_packed(
+    a: &PackedTernaryMatrix<'_>,
+    b_layout: Layout,
+    n: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+    ldb: usize,
+    c: &mut [i32],
+    ldc: usize,
+) -> Result<(), TernaryError> {
+    for (what, expected, found) in [("mc", MC, a.mc), ("kc", KC, a.kc)] {
+        if found != expected {
+            return Err(TernaryError::ShapeMismatch {
+                what,
+                expected,
+                found,
+            });
+        }
+    }
+    let kb = a.k.div_ceil(8);
+    b_layout.validate(("b_vals", "ldb"), kb, n, b_vals.len(), ldb)?;
+    b_layout.validate(("b_signs", "ldb"), kb, n, b_signs.len(), ldb)?;
+    Layout::ColMajor.validate(("c", "ldc"), a.m, n, c.len(), ldc)?;
+    ternary_gemm_packed(a, b_layout, n, b_vals, b_signs, ldb, c, ldc);
+    Ok(())
+}
+
+/// Multiplies pre-packed A with compressed, contiguous col-major B (`k x n`) into a new
+/// col-major `m x n` C.
+pub fn matmul_packed(
+    a: &PackedTernaryMatrix<'_>,
+    n: usize,
+    b_vals: &[u8],
+    b_signs: &[u8],
+) -> Vec<i32> {
+    let mut c = vec![0; a.m * n];
+    ternary_gemm_packed(
+        a,
+        Layout::ColMajor,
+        n,
+        b_vals,
+        b_signs,
+        a.k.div_ceil(8).max(1),
+        &mut c,
+        a.m.max(1),
+    );
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gemm::{compress_b, gemm_panels, matmul_i32, prep, Kernel, Layout, PanelsA, TernaryError},
+        test_util::test_util::{rand_vecs_sized, test_matmul_i32_shape},
+    };
+
+    use super::{matmul_packed, try_ternary_gemm_packed, PackedTernaryMatrix};
+
+    #[test]
+    fn test() {
+        // Edge tiles, more than one mc and kc block and small batches
+        for (m, k, n) in [(37, 13, 21), (1, 1, 1), (270, 4100, 260), (300, 600, 3)] {
+            test_matmul_i32_shape(m, k, n, |a, b| {
+                let packed = PackedTernaryMatrix::new(m, k, a, Layout::ColMajor, m);
+                let (b_vals, b_signs) = compress_b(k, n, b, Layout::ColMajor, k);
+                matmul_packed(&packed, n, &b_vals, &b_signs)
+            });
+        }
+    }
+
+    #[test]
+    fn test_kernels() {
+        let (m, k, n) = (100, 700, 40);
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        let (av, asi, bv, bs) = prep(m, k, n, &a, &b);
+        let expected = matmul_i32(m, k, n, &av, &asi, &bv, &bs);
+
+        // Row-major weights, packed once and reused across calls and thread counts
+        let a_row: Vec<i8> = (0..m * k).map(|i| a[i / k + m * (i % k)]).collect();
+        for kernel in Kernel::ALL
+            .into_iter()
+            .filter(|kernel| kernel.is_supported())
+        {
+            let packed =
+                PackedTernaryMatrix::with_kernel(kernel, m, k, &a_row, Layout::RowMajor, k);
+            assert_eq!((packed.m(), packed.k()), (m, k));
+            assert_eq!(packed.mr(), kernel.microkernel().mr);
+
+            assert_eq!(matmul_packed(&packed, n, &bv, &bs), expected, "{}", kernel);
+
+            // Threads split the rows at multiples of mr, not of mc
+            for threads in [2, 3] {
+                let mut c = vec![0; m * n];
+                gemm_panels(
+                    kernel.microkernel(),
+                    threads,
+                    PanelsA::Packed(&packed),
+                    Layout::ColMajor,
+                    m,
+                    n,
+                    k,
+                    &bv,
+                    &bs,
+                    k.div_ceil(8),
+                    &mut c,
+                    m,
+                );
+                assert_eq!(c, expected, "{}, threads = {}", kernel, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn test_try() {
+        let (m, k, n) = (40, 20, 5);
+        let (mut a, b) = rand_vecs_sized(m * k, k * n);
+        let (b_vals, b_signs) = compress_b(k, n, &b, Layout::ColMajor, k);
+        let kb = k.div_ceil(8);
+
+        let packed = PackedTernaryMatrix::try_new(m, k, &a, Layout::ColMajor, m).unwrap();
+        let mut c = vec![0; m * n];
+        let col = Layout::ColMajor;
+        try_ternary_gemm_packed(&packed, col, n, &b_vals, &b_signs, kb, &mut c, m).unwrap();
+        assert_eq!(c, matmul_packed(&packed, n, &b_vals, &b_signs));
+
+        assert_eq!(
+            try_ternary_gemm_packed(&packed, col, n, &b_vals, &b_signs, kb - 1, &mut c, m),
+            Err(TernaryError::ShapeMismatch {
+                what: "ldb",
+                expected: kb,
+                found: kb - 1
+            })
+        );
+
+        if let Some(kernel) = Kernel::ALL
+            .into_iter()
+            .find(|kernel| !kernel.is_supported())
+        {
+            assert_eq!(
+                PackedTernaryMatrix::try_with_kernel(kernel, m, k, &a, Layout::ColMajor, m).err(),
+                Some(TernaryError::UnsupportedKernel { kernel })
+            );
+        }
+
+        a[m * k - 1] = 2;
+        assert_eq!(
+            PackedTernaryMatrix::try_new(m, k, &a, Layout::ColMajor, m).err(),
+            Some(TernaryError::InvalidTrit {
+                index: m * k - 1,
+                value: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,666 @@
+//! Quantization of f32/f16 activations to int8 like BitNet's BitLinear: every token (column
+//! of B) is scaled by `127 / absmax` of the token, rounded and clamped to i8. The per-token
+//! scales plug into the [`Epilogue`] of [`super::gemm_int8_f32`], so a layer goes from f32
+//! activations to f32 outputs. [`quantize_activations_packed`] writes the int8 activations in
+//! the packed layout of B instead, for [`super::gemm_int8_f32_packed`].
+//!
+//! Weights are quantized to ternary with BitNet b1.58's absmean: every group of weights (the
+//! whole tensor, a row of A or a group of consecutive k) is divided by its mean absolute value
+//! and rounded and clipped to {-1, 0, 1}.
+use std::{
+    cmp::min,
+    simd::{num::SimdFloat, Simd, StdFloat},
+};
+
+use super::{compress_a, Epilogue, Layout, PackedTernaryMatrix, Scale, TernaryError, KC, NC, NR};
+
+// Activations quantized at once
+const LANES: usize = 16;
+// Lower bound of absmax and absmean, so all-zero tokens and groups don't divide by zero
+const MIN_ABSMAX: f32 = 1e-5;
+
+/// A float type that can be quantized, converted to f32 first.
+pub trait Float: Copy + Default {
+    fn to_f32(self) -> f32;
+
+    /// Loads up to 16 values as f32 lanes, zero padded
+    #[inline(always)]
+    fn load(x: &[Self]) -> Simd<f32, LANES> {
+        let mut lanes = [0.; LANES];
+        for (lane, e) in lanes.iter_mut().zip(x) {
+            *lane = e.to_f32();
+        }
+        Simd::from_array(lanes)
+    }
+}
+
+impl Float for f32 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    #[inline(always)]
+    fn load(x: &[f32]) -> Simd<f32, LANES> {
+        Simd::load_or_default(x)
+    }
+}
+
+impl Float for f16 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+}
+
+/// The bits of a bfloat16, the upper half of an f32.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bf16(pub u16);
+
+impl Float for Bf16 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        f32::from_bits((self.0 as u32) << 16)
+    }
+}
+
+/// int8 activations and their per-token scales, see [`quantize_activations`].
+pub struct QuantizedActivations {
+    /// Contiguous col-major `k x n` int8 B, the layout [`super::matmul_int8`] expects
+    pub values: Vec<i8>,
+    /// Per token (column), multiplying with it dequantizes the token: `absmax / 127`
+    pub scales: Vec<f32>,
+}
+
+impl QuantizedActivations {
+    /// The per-token scales as the activation scale of an [`Epilogue`]
+    pub fn x_scale(&self) -> Scale<'_> {
+        Scale::PerChannel(&self.scales)
+    }
+
+    /// An [`Epilogue`] that dequantizes the product of ternary weights (with scale w_scale)
+    /// and these activations, and adds the optional bias
+    pub fn epilogue<'a>(&'a self, w_scale: Scale<'a>, bias: Option<&'a [f32]>) -> Epilogue<'a> {
+        Epilogue {
+            w_scale,
+            x_scale: self.x_scale(),
+            bias,
+        }
+    }
+}
+
+// Quantizes one token, passing every chunk of up to 16 int8 values to store with the index
+// of its first value. Returns the dequantization scale.
+#[inline(always)]
+fn quantize_token_with<T: Float>(x: &[T], mut store: impl FnMut(usize, &[i8])) -> f32 {
+    let mut absmax = Simd::splat(0.);
+    for chunk in x.chunks(LANES) {
+        absmax = absmax.simd_max(T::load(chunk).abs());
+    }
+    let absmax = absmax.reduce_max().max(MIN_ABSMAX);
+
+    let scale = Simd::splat(127. / absmax);
+    for (i, chunk) in x.chunks(LANES).enumerate() {
+        // |x| <= absmax, so the values round into [-127, 127] (and the cast saturates)
+        let q = (T::load(chunk) * scale).round().cast::<i8>();
+        store(i * LANES, &q.as_array()[..chunk.len()]);
+    }
+    absmax / 127.
+}
+
+// Quantizes one token of length k into out, returns its dequantization scale
+fn quantize_token<T: Float>(x: &[T], out: &mut [i8]) -> f32 {
+    quantize_token_with(x, |i, q| out[i..(i + q.len())].copy_from_slice(q))
+}
+
+/// Quantizes `n` tokens of `k` activations each (a col-major `k x n` B with leading dimension
+/// ldx, i.e. row-major `[tokens, hidden]`) to int8 with an absmax scale per token.
+///
+/// Activations should be finite. NaNs are ignored by the absmax and quantize to 0, an
+/// infinite activation gives its token an infinite scale, so the outputs of the token are NaN.
+pub fn quantize_activations<T: Float>(
+    k: usize,
+    n: usize,
+    x: &[T],
+    ldx: usize,
+) -> QuantizedActivations {
+    try_quantize_activations(k, n, x, ldx).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`quantize_activations`], but returns an error if the buffer or leading dimension
+/// doesn't fit the shape.
+pub fn try_quantize_activations<T: Float>(
+    k: usize,
+    n: usize,
+    x: &[T],
+    ldx: usize,
+) -> Result<QuantizedActivations, TernaryError> {
+    Layout::ColMajor.validate(("x", "ldx"), k, n, x.len(), ldx)?;
+
+    let mut values = vec![0; k * n];
+    let mut scales = vec![0.; n];
+    for (ni, scale) in scales.iter_mut().enumerate() {
+        // x can be empty for k = 0
+        let token = x.get((ni * ldx)..(ni * ldx + k)).unwrap_or_default();
+        *scale = quantize_token(token, &mut values[(ni * k)..(ni * k + k)]);
+    }
+    Ok(QuantizedActivations { values, scales })
+}
+
+/// int8 activations packed into the panels the int8 microkernels read, with their per-token
+/// scales, see [`quantize_activations_packed`].
+pub struct PackedActivations {
+    k: usize,
+    n: usize,
+    panels: Vec<i8>,
+    /// Per token (column), multiplying with it dequantizes the token: `absmax / 127`
+    pub scales: Vec<f32>,
+}
+
+impl PackedActivations {
+    /// Number of activations per token
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Number of tokens
+    pub fn n(&self) -> usize {
+        self.n
+    }
+
+    pub(super) fn panels(&self) -> &[i8] {
+        &self.panels
+    }
+
+    /// The per-token scales as the activation scale of an [`Epilogue`]
+    pub fn x_scale(&self) -> Scale<'_> {
+        Scale::PerChannel(&self.scales)
+    }
+
+    /// An [`Epilogue`] that dequantizes the product of ternary weights (with scale w_scale)
+    /// and these activations, and adds the optional bias
+    pub fn epilogue<'a>(&'a self, w_scale: Scale<'a>, bias: Option<&'a [f32]>) -> Epilogue<'a> {
+        Epilogue {
+            w_scale,
+            x_scale: self.x_scale(),
+            bias,
+        }
+    }
+}
+
+/// Same as [`quantize_activations`], but writes the int8 activations straight into the panels
+/// of B that [`super::gemm_int8_f32_packed`] multiplies without packing them again, there is
+/// no col-major copy of B in between.
+pub fn quantize_activations_packed<T: Float>(
+    k: usize,
+    n: usize,
+    x: &[T],
+    ldx: usize,
+) -> PackedActivations {
+    try_quantize_activations_packed(k, n, x, ldx).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`quantize_activations_packed`], but returns an error if the buffer or leading
+/// dimension doesn't fit the shape.
+pub fn try_quantize_activations_packed<T: Float>(
+    k: usize,
+    n: usize,
+    x: &[T],
+    ldx: usize,
+) -> Result<PackedActivations, TernaryError> {
+    Layout::ColMajor.validate(("x", "ldx"), k, n, x.len(), ldx)?;
+
+    // The layout of `pack_b_blocks`: kc x nc blocks (n blocks outside, k blocks inside) of
+    // NR-col panels with NR values per row, zero padded
+    let kb = k.div_ceil(8);
+    let mut panels = vec![0; 8 * kb * n.next_multiple_of(NR)];
+    let mut scales = vec![0.; n];
+    for (ni, scale) in scales.iter_mut().enumerate() {
+        // The n block of the token, its width in whole panels, and the token's panel and col
+        let block_n = ni / NC * NC;
+        let width = min(n - block_n, NC).next_multiple_of(NR);
+        let (panel, col) = ((ni - block_n) / NR, ni % NR);
+        // x can be empty for k = 0
+        let token = x.get((ni * ldx)..(ni * ldx + k)).unwrap_or_default();
+        *scale = quantize_token_with(token, |start, q| {
+            for (row, q) in (start..).zip(q) {
+                // The k block of the row, its panels are as deep as the block
+                let (block_k, r) = (row / (8 * KC) * KC, row % (8 * KC));
+                let depth = 8 * min(kb - block_k, KC);
+                let offset = 8 * kb * block_n + 8 * block_k * width + panel * NR * depth;
+                panels[offset + r * NR + col] = *q;
+            }
+        });
+    }
+    Ok(PackedActivations {
+        k,
+        n,
+        panels,
+        scales,
+    })
+}
+
+/// Which weights of A share an absmean scale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    /// One scale for the whole matrix, like BitNet b1.58
+    PerTensor,
+    /// One scale per row of A (output channel)
+    PerChannel,
+    /// One scale per group of this many consecutive k in a row, the last group of a row can
+    /// be shorter. The kernels can't apply them, so they are only for export, see
+    /// [`TernaryWeights::w_scale`].
+    PerGroup(usize),
+}
+
+impl Granularity {
+    /// Number of scales of an `m x k` matrix
+    pub fn scales(self, m: usize, k: usize) -> usize {
+        match self {
+            Granularity::PerTensor => 1,
+            Granularity::PerChannel => m,
+            Granularity::PerGroup(size) => m * k.div_ceil(size),
+        }
+    }
+
+    // Index of the scale of element (r, c) of a matrix with k cols
+    fn index(self, r: usize, c: usize, k: usize) -> usize {
+        match self {
+            Granularity::PerTensor => 0,
+            Granularity::PerChannel => r,
+            Granularity::PerGroup(size) => r * k.div_ceil(size) + c / size,
+        }
+    }
+}
+
+/// A ternary `m x k` A with its absmean scales, see [`quantize_weights`].
+pub struct TernaryWeights {
+    pub m: usize,
+    pub k: usize,
+    /// Layout of `values`, the layout of the input
+    pub layout: Layout,
+    /// Contiguous ternary A, the leading dimension is [`TernaryWeights::ld`]
+    pub values: Vec<i8>,
+    pub granularity: Granularity,
+    /// Multiplying with it dequantizes a weight. Per group they are stored row by row,
+    /// `ceil(k / size)` per row.
+    pub scales: Vec<f32>,
+    /// Mean squared error between the input and the dequantized weights
+    pub mse: f32,
+    /// Fraction of weights quantized to 0
+    pub sparsity: f32,
+}
+
+impl TernaryWeights {
+    /// Leading dimension of `values`
+    pub fn ld(&self) -> usize {
+        match self.layout {
+            Layout::ColMajor => self.m.max(1),
+            Layout::RowMajor => self.k.max(1),
+        }
+    }
+
+    /// Compresses the ternary values, see [`compress_a`]
+    pub fn compress(&self) -> (Vec<u8>, Vec<u8>) {
+        compress_a(self.m, self.k, &self.values, self.layout, self.ld())
+    }
+
+    /// Compresses and packs the ternary values for the selected kernel
+    pub fn pack(&self) -> PackedTernaryMatrix<'static> {
+        PackedTernaryMatrix::new(self.m, self.k, &self.values, self.layout, self.ld())
+    }
+
+    /// The scales as the weight scale of an [`Epilogue`], `None` per group: no kernel applies
+    /// scales per group, they are only kept for export (GGUF, safetensors).
+    pub fn w_scale(&self) -> Option<Scale<'_>> {
+        match self.granularity {
+            Granularity::PerTensor => Some(Scale::PerTensor(self.scales[0])),
+            Granularity::PerChannel => Some(Scale::PerChannel(&self.scales)),
+            Granularity::PerGroup(_) => None,
+        }
+    }
+}
+
+/// Ternary weights compressed along k: a row-major `m x k` A as val/sign bits with its scales.
+/// GGUF tensors decode to it and safetensors exports store it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TernaryTensor {
+    pub m: usize,
+    pub k: usize,
+    /// Row-major val bits, leading dimension [`TernaryTensor::ld`]
+    pub vals: Vec<u8>,
+    /// Row-major sign bits, leading dimension [`TernaryTensor::ld`]
+    pub signs: Vec<u8>,
+    pub granularity: Granularity,
+    /// The scales, per group stored row by row like [`TernaryWeights::scales`]
+    pub scales: Vec<f32>,
+}
+
+impl TernaryTensor {
+    /// The layout of `vals` and `signs`, the rows are contiguous
+    pub fn layout(&self) -> Layout {
+        Layout::RowMajor
+    }
+
+    /// Leading dimension of `vals` and `signs`, `ceil(k / 8)`
+    pub fn ld(&self) -> usize {
+        self.k.div_ceil(8).max(1)
+    }
+
+    /// The scales as the weight scale of an [`Epilogue`], see [`TernaryWeights::w_scale`]
+    pub fn w_scale(&self) -> Option<Scale<'_>> {
+        match self.granularity {
+            Granularity::PerTensor => Some(Scale::PerTensor(self.scales[0])),
+            Granularity::PerChannel => Some(Scale::PerChannel(&self.scales)),
+            Granularity::PerGroup(_) => None,
+        }
+    }
+}
+
+impl From<&TernaryWeights> for TernaryTensor {
+    fn from(weights: &TernaryWeights) -> Self {
+        let (m, k) = (weights.m, weights.k);
+        let (vals, signs) = match weights.layout {
+            Layout::RowMajor => weights.compress(),
+            Layout::ColMajor => {
+                let rows: Vec<i8> = (0..(m * k))
+                    .map(|i| weights.values[i / k + m * (i % k)])
+                    .collect();
+                compress_a(m, k, &rows, Layout::RowMajor, k.max(1))
+            }
+        };
+        Self {
+            m,
+            k,
+            vals,
+            signs,
+            granularity: weights.granularity,
+            scales: weights.scales.clone(),
+        }
+    }
+}
+
+/// Quantizes an f32/f16/bf16 `m x k` matrix A with leading dimension lda to ternary with
+/// absmean scales: `w_q = clamp(round(w / mean(|w|)), -1, 1)` per group of weights.
+pub fn quantize_weights<T: Float>(
+    m: usize,
+    k: usize,
+    a: &[T],
+    layout: Layout,
+    lda: usize,
+    granularity: Granularity,
+) -> TernaryWeights {
+    try_quantize_weights(m, k, a, layout, lda, granularity).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`quantize_weights`], but returns an error if the buffer or leading dimension
+/// doesn't fit the shape or the group size is 0.
+pub fn try_quantize_weights<T: Float>(
+    m: usize,
+    k: usize,
+    a: &[T],
+    layout: Layout,
+    lda: usize,
+    granularity: Granularity,
+) -> Result<TernaryWeights, TernaryError> {
+    layout.validate(("a", "lda"), m, k, a.len(), lda)?;
+    if granularity == Granularity::PerGroup(0) {
+        return Err(TernaryError::ShapeMismatch {
+            what: "group size",
+            expected: 1,
+            found: 0,
+        });
+    }
+
+    let mut weights = TernaryWeights {
+        m,
+        k,
+        layout,
+        values: vec![0; m * k],
+        granularity,
+        scales: vec![0.; granularity.scales(m, k)],
+        mse: 0.,
+        sparsity: 0.,
+    };
+    let ld = weights.ld();
+    // (row, col) of the elements in memory order
+    let elements = || {
+        (0..m * k).map(move |e| match layout {
+            Layout::ColMajor => (e % m, e / m),
+            Layout::RowMajor => (e / k, e % k),
+        })
+    };
+
+    // absmean of every group, summed in f64 since the sums of large groups lose the low bits
+    // of small weights in f32
+    let mut sums = vec![0_f64; weights.scales.len()];
+    let mut counts = vec![0_usize; weights.scales.len()];
+    for (r, c) in elements() {
+        let g = granularity.index(r, c, k);
+        sums[g] += a[layout.at(r, c, lda)].to_f32().abs() as f64;
+        counts[g] += 1;
+    }
+    for ((scale, sum), count) in weights.scales.iter_mut().zip(sums).zip(counts) {
+        *scale = ((sum / count.max(1) as f64) as f32).max(MIN_ABSMAX);
+    }
+
+    // Round-clip, with the error against the dequantized weights
+    let (mut squared_error, mut zeros) = (0_f64, 0);
+    for (r, c) in elements() {
+        let w = a[layout.at(r, c, lda)].to_f32();
+        let scale = weights.scales[granularity.index(r, c, k)];
+        let q = (w / scale).round().clamp(-1., 1.);
+
+        weights.values[layout.at(r, c, ld)] = q as i8;
+        squared_error += ((w - q * scale) as f64).powi(2);
+        zeros += (q == 0.) as usize;
+    }
+    if m * k > 0 {
+        weights.mse = (squared_error / (m * k) as f64) as f32;
+        weights.sparsity = zeros as f32 / (m * k) as f32;
+    }
+    Ok(weights)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::gemm::{
+        compress_a, compress_b, gemm_int8_f32_packed, int8::pack_b_blocks, matmul_int8_f32,
+        matmul_packed, ternary_gemm, Layout, Scale, TernaryError,
+    };
+
+    use super::{
+        quantize_activations, quantize_activations_packed, quantize_weights,
+        try_quantize_activations, try_quantize_weights, Bf16, Granularity,
+    };
+
+    #[test]
+    fn test() {
+        // Two tokens with a padding row, the second one all zeros
+        let x: [f32; 8] = [0.5, -1., 0.25, 9., 0., 0., 0., 9.];
+        let q = quantize_activations(3, 2, &x, 4);
+        assert_eq!(q.values, [64, -127, 32, 0, 0, 0]);
+        assert_eq!(q.scales[0], 1. / 127.);
+        assert!(q.scales[1] > 0.);
+
+        // NaNs don't affect the absmax and quantize to 0
+        let q_nan = quantize_activations(3, 1, &[0.5, f32::NAN, -1.], 3);
+        assert_eq!(
+            (q_nan.values, q_nan.scales),
+            (vec![64, 0, -127], vec![1. / 127.])
+        );
+
+        // f16 activations give the same result
+        let x_f16: Vec<f16> = x.iter().map(|e| *e as f16).collect();
+        let q_f16 = quantize_activations(3, 2, &x_f16, 4);
+        assert_eq!((q_f16.values, q_f16.scales), (q.values, q.scales));
+
+        assert_eq!(
+            try_quantize_activations(3, 2, &x[..6], 4).err(),
+            Some(TernaryError::BufferTooSmall {
+                what: "x",
+                len: 6,
+                required: 7
+            })
+        );
+    }
+
+    #[test]
+    fn test_end_to_end() {
+        // f32 activations in, f32 outputs out, against an f64 reference
+        let (m, k, n) = (40, 300, 5);
+        let mut rng = StdRng::seed_from_u64(1337);
+        let a: Vec<i8> = (0..m * k).map(|_| rng.gen_range(-1..2)).collect();
+        let x: Vec<f32> = (0..k * n).map(|_| rng.gen_range(-4.0..4.0)).collect();
+        let w_scale = 0.8;
+        let bias: Vec<f32> = (0..m).map(|i| i as f32).collect();
+
+        let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m);
+        let q = quantize_activations(k, n, &x, k);
+        let epilogue = q.epilogue(Scale::PerTensor(w_scale), Some(&bias));
+        let y = matmul_int8_f32(m, k, n, &av, &asi, &q.values, &epilogue);
+
+        for ni in 0..n {
+            let absmax = x[(ni * k)..(ni * k + k)]
+                .iter()
+                .fold(0_f32, |max, e| max.max(e.abs()));
+            for mi in 0..m {
+                let dot: f64 = (0..k)
+                    .map(|ki| a[mi + m * ki] as f64 * x[ki + k * ni] as f64)
+                    .sum();
+                let expected = dot * w_scale as f64 + bias[mi] as f64;
+                // Every activation is off by at most half a quantization step
+                let tolerance = 0.5 * (absmax / 127.) as f64 * k as f64 * w_scale as f64;
+                let e = y[mi + m * ni] as f64;
+                assert!((e - expected).abs() <= tolerance, "{} != {}", e, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_packed() {
+        // Edge tiles, the gemv case (n = 1), an empty k and more than one kc and nc block
+        for (m, k, n) in [(37, 13, 21), (20, 50, 1), (5, 0, 3), (30, 4100, 270)] {
+            let mut rng = StdRng::seed_from_u64(1337);
+            let a: Vec<i8> = (0..m * k).map(|_| rng.gen_range(-1..2)).collect();
+            let x: Vec<f32> = (0..k * n).map(|_| rng.gen_range(-4.0..4.0)).collect();
+            let bias: Vec<f32> = (0..m).map(|i| i as f32).collect();
+            let (av, asi) = compress_a(m, k, &a, Layout::ColMajor, m.max(1));
+
+            let q = quantize_activations(k, n, &x, k.max(1));
+            let epilogue = q.epilogue(Scale::PerTensor(0.8), Some(&bias));
+            let expected = matmul_int8_f32(m, k, n, &av, &asi, &q.values, &epilogue);
+
+            let packed = quantize_activations_packed(k, n, &x, k.max(1));
+            assert_eq!((packed.k(), packed.n(), &packed.scales), (k, n, &q.scales));
+            let panels = pack_b_blocks(k, n, &q.values, Layout::ColMajor, k.max(1));
+            assert_eq!(packed.panels(), panels, "m = {}, k = {}, n = {}", m, k, n);
+            let epilogue = packed.epilogue(Scale::PerTensor(0.8), Some(&bias));
+            let mut y = vec![0.; m * n];
+            let col = Layout::ColMajor;
+            gemm_int8_f32_packed(col, m, &av, &asi, m, &packed, &epilogue, &mut y, m);
+            assert!(
+                y.iter()
+                    .zip(&expected)
+                    .all(|(y, e)| (y - e).abs() <= 1e-4 * e.abs().max(1.)),
+                "m = {}, k = {}, n = {}",
+                m,
+                k,
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn test_weights() {
+        // Row-major 2x4, absmean 0.6875 per tensor
+        let a: [f32; 8] = [0.1, -0.9, 0.5, 0., 2., -0.2, 0.3, -1.5];
+        let per_tensor = quantize_weights(2, 4, &a, Layout::RowMajor, 4, Granularity::PerTensor);
+        assert_eq!(per_tensor.values, [0, -1, 1, 0, 1, 0, 0, -1]);
+        assert_eq!(per_tensor.scales, [0.6875]);
+        assert_eq!(per_tensor.sparsity, 0.5);
+        assert_eq!(per_tensor.w_scale(), Some(Scale::PerTensor(0.6875)));
+
+        // -1.5 rounds to -2 and is clipped
+        let per_channel = quantize_weights(2, 4, &a, Layout::RowMajor, 4, Granularity::PerChannel);
+        assert_eq!(per_channel.values, [0, -1, 1, 0, 1, 0, 0, -1]);
+        assert_eq!(per_channel.scales, [0.375, 1.]);
+
+        let per_group = quantize_weights(2, 4, &a, Layout::RowMajor, 4, Granularity::PerGroup(3));
+        assert_eq!(per_group.values, [0, -1, 1, 0, 1, 0, 0, -1]);
+        assert_eq!(
+            per_group.scales,
+            [0.5, 0., 2.5 / 3., 1.5].map(|s: f32| s.max(1e-5))
+        );
+        assert_eq!(per_group.w_scale(), None);
+
+        // The col-major transpose and bf16/f16 inputs give the same weights
+        let a_col: Vec<f32> = (0..8).map(|i| a[(i % 2) * 4 + i / 2]).collect();
+        let col = quantize_weights(2, 4, &a_col, Layout::ColMajor, 2, Granularity::PerChannel);
+        assert_eq!(col.scales, per_channel.scales);
+        for i in 0..8 {
+            assert_eq!(col.values[i], per_channel.values[(i % 2) * 4 + i / 2]);
+        }
+        let a_bf16: Vec<Bf16> = a.iter().map(|e| Bf16((e.to_bits() >> 16) as u16)).collect();
+        let a_f16: Vec<f16> = a.iter().map(|e| *e as f16).collect();
+        for values in [
+            quantize_weights(2, 4, &a_bf16, Layout::RowMajor, 4, Granularity::PerTensor).values,
+            quantize_weights(2, 4, &a_f16, Layout::RowMajor, 4, Granularity::PerTensor).values,
+        ] {
+            assert_eq!(values, per_tensor.values);
+        }
+
+        assert!(matches!(
+            try_quantize_weights(2, 4, &a, Layout::RowMajor, 4, Granularity::PerGroup(0)),
+            Err(TernaryError::ShapeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_weights_large_tensor() {
+        // Summed in f32, the absmean of 4M weights around 0.02 comes out 0.3% low
+        let (m, k) = (1024, 4096);
+        let a: Vec<f32> = (0..(m * k))
+            .map(|i| (0.015 + (i % 11) as f32 * 0.001) * if i % 2 == 0 { 1. } else { -1. })
+            .collect();
+        let expected = a.iter().map(|w| w.abs() as f64).sum::<f64>() / (m * k) as f64;
+        let weights = quantize_weights(m, k, &a, Layout::RowMajor, k, Granularity::PerTensor);
+        assert!(
+            (weights.scales[0] as f64 - expected).abs() < 1e-6,
+            "{} != {}",
+            weights.scales[0],
+            expected
+        );
+    }
+
+    #[test]
+    fn test_weights_feed_gemm() {
+        let (m, k, n) = (50, 200, 7);
+        let mut rng = StdRng::seed_from_u64(1337);
+        let a: Vec<f32> = (0..m * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let b: Vec<i8> = (0..k * n).map(|_| rng.gen_range(-1..2)).collect();
+        let (b_vals, b_signs) = compress_b(k, n, &b, Layout::ColMajor, k);
+
+        let variance = a.iter().map(|e| e * e).sum::<f32>() / (m * k) as f32;
+        for granularity in [
+            Granularity::PerTensor,
+            Granularity::PerChannel,
+            Granularity::PerGroup(64),
+        ] {
+            let weights = quantize_weights(m, k, &a, Layout::RowMajor, k, granularity);
+            assert!(weights.mse < variance / 2., "{:?}", granularity);
+            assert!(weights.sparsity > 0.1 && weights.sparsity < 0.7);
+
+            // Row-major weights stay row-major
+            let (a_vals, a_signs) = weights.compress();
+            let (kb, col, row) = (k.div_ceil(8), Layout::ColMajor, Layout::RowMajor);
+            let mut c = vec![0; m * n];
+            ternary_gemm(
+                row, col, m, n, k, &a_vals, &a_signs, kb, &b_vals, &b_signs, kb, &mut c, m,
+            );
+            assert_eq!(matmul_packed(&weights.pack(), n, &b_vals, &b_signs), c);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Thread count of the gemm drivers and how C is split between threads.
+//!
+//! Like `OPENBLAS_NUM_THREADS`, the `TERNARY_THREADS` env var or [`set_threads`] configure the
+//! number of threads, by default all available cores are used. The gemm drivers run their
+//! blocks in the rayon thread pool instead of spawning threads on every call.
+use std::{
+    env,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    thread,
+};
+
+/// Env var with the number of threads, see [`threads`].
+pub const THREADS_ENV: &str = "TERNARY_THREADS";
+
+// 0 while the thread count isn't configured
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the number of threads the gemm drivers use, `None` goes back to the env var or the
+/// number of cores.
+pub fn set_threads(threads: Option<usize>) {
+    let threads = threads.map(|threads| threads.max(1)).unwrap_or(0);
+    THREADS.store(threads, Ordering::Relaxed);
+}
+
+// The thread count in `TERNARY_THREADS` or the number of cores, read once
+static DEFAULT: OnceLock<usize> = OnceLock::new();
+
+// The thread count in the env var, warns and falls back to the number of cores if it isn't a
+// number
+fn threads_from_env(threads: Option<&str>) -> usize {
+    let cores = || thread::available_parallelism().map_or(1, |threads| threads.get());
+    match threads.map(|threads| (threads, threads.trim().parse::<usize>())) {
+        Some((_, Ok(threads))) => threads.max(1),
+        Some((threads, Err(_))) => {
+            let cores = cores();
+            eprintln!(
+                "ignoring {}: {:?} is not a number, using {} threads",
+                THREADS_ENV, threads, cores
+            );
+            cores
+        }
+        None => cores(),
+    }
+}
+
+/// Number of threads the gemm drivers use: the one set with [`set_threads`], otherwise
+/// `TERNARY_THREADS`, otherwise the number of cores. The env var is read on first use, a value
+/// that isn't a number is ignored with a warning.
+pub fn threads() -> usize {
+    match THREADS.load(Ordering::Relaxed) {
+        0 => *DEFAULT.get_or_init(|| threads_from_env(env::var(THREADS_ENV).ok().as_deref())),
+        threads => threads,
+    }
+}
+
+/// Splits `threads` into a `threads_m x threads_n` grid over the m_panels row panels and
+/// n_panels col panels of C. Uses as many threads as possible and splits n before m: the cols of
+/// C are contiguous, so threads that only split n write straight into C.
+pub fn grid(threads: usize, m_panels: usize, n_panels: usize) -> (usize, usize) {
+    let mut best = (1, 1);
+    for threads_n in 1..=threads.min(n_panels) {
+        let threads_m = (threads / threads_n).min(m_panels).max(1);
+        if threads_m * threads_n >= best.0 * best.1 {
+            best = (threads_m, threads_n);
+        }
+    }
+    best
+}
+
+/// Splits `0..len` into `parts` ranges of whole `unit` sized panels that differ by at most
+/// one panel. Only the last range can end in a partial panel.
+pub fn split(len: usize, unit: usize, parts: usize) -> Vec<Range<usize>> {
+    let panels = len.div_ceil(unit);
+    let mut start = 0;
+    (0..parts)
+        .map(|part| {
+            let size = panels / parts + usize::from(part < panels % parts);
+            let range = (start * unit).min(len)..((start + size) * unit).min(len);
+            start += size;
+            range
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{grid, split, threads_from_env};
+
+    #[test]
+    fn test_grid() {
+        assert_eq!(grid(1, 10, 10), (1, 1));
+        assert_eq!(grid(8, 64, 64), (1, 8));
+        // Not enough col panels, split the rows as well
+        assert_eq!(grid(8, 64, 1), (8, 1));
+        assert_eq!(grid(8, 64, 2), (4, 2));
+        assert_eq!(grid(8, 1, 1), (1, 1));
+        assert_eq!(grid(6, 4, 4), (2, 3));
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(100, 16, 3), vec![0..48, 48..80, 80..100]);
+        assert_eq!(split(32, 16, 2), vec![0..16, 16..32]);
+        assert_eq!(split(5, 16, 1), vec![0..5]);
+    }
+
+    #[test]
+    fn test_threads_from_env() {
+        assert_eq!(threads_from_env(Some(" 3")), 3);
+        assert_eq!(threads_from_env(Some("0")), 1);
+        // Not a number, falls back to the number of cores
+        assert!(threads_from_env(Some("four")) >= 1);
+        assert_eq!(threads_from_env(Some("four")), threads_from_env(None));
+    }
+}
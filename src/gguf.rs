@@ -0,0 +1,942 @@
+//! Reads ternary weights from GGUF files, the format llama.cpp and bitnet.cpp ship models in.
+//!
+//! [`GgufFile`] parses the header, the metadata and the tensor index. [`GgufFile::ternary`]
+//! decodes the ternary quant types into compressed val/sign bits:
+//!
+//! * `TQ1_0`: blocks of 256 weights along k in 54 bytes, 5 trits per byte in base 3 (plus 4
+//!   per byte for the last 16) and an f16 scale per block
+//! * `TQ2_0`: blocks of 256 weights in 66 bytes, 2 bits per weight and an f16 scale per block
+//! * `I2_S` (bitnet.cpp): blocks of 128 weights in 32 bytes, 2 bits per weight, and a single
+//!   f32 scale after the weights of the tensor
+//!
+//! [`GgufWriter`] writes the same types, which is how the tests get synthetic files.
+use std::{collections::HashMap, error::Error, fmt, fs, io, path::Path};
+
+use crate::gemm::{
+    try_compress_a, validate_trits, Granularity, Layout, TernaryError, TernaryTensor,
+};
+
+const MAGIC: &[u8; 4] = b"GGUF";
+const VERSION: u32 = 3;
+const ALIGNMENT_KEY: &str = "general.alignment";
+const DEFAULT_ALIGNMENT: usize = 32;
+
+// Weights per block of TQ1_0 and TQ2_0 (QK_K in ggml)
+const QK_K: usize = 256;
+// Weights per block of I2_S
+const QK_I2: usize = 128;
+const TQ1_0_BYTES: usize = 54;
+const TQ2_0_BYTES: usize = 66;
+// bytes of 5 trits (qs) and of 4 trits (qh) of a TQ1_0 block
+const TQ1_0_QS: usize = 48;
+const TQ1_0_QH: usize = 4;
+const POW3: [u8; 5] = [1, 3, 9, 27, 81];
+// Nesting limit of metadata arrays, real files nest at most once
+const MAX_DEPTH: usize = 64;
+
+/// Errors of reading and writing GGUF files.
+#[derive(Debug)]
+pub enum GgufError {
+    Io(io::Error),
+    /// Not a GGUF file, an unsupported version or a truncated/corrupt file
+    Format(String),
+    /// The tensor doesn't exist
+    MissingTensor(String),
+    /// The tensor's type isn't one of the ternary types
+    UnsupportedType {
+        name: String,
+        ggml_type: GgmlType,
+    },
+    /// The ternary weights are invalid or don't fit their shape
+    Ternary(TernaryError),
+}
+
+impl fmt::Display for GgufError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GgufError::Io(err) => write!(f, "io error: {}", err),
+            GgufError::Format(msg) => write!(f, "invalid gguf file: {}", msg),
+            GgufError::MissingTensor(name) => write!(f, "no tensor named {}", name),
+            GgufError::UnsupportedType { name, ggml_type } => {
+                write!(f, "tensor {} has unsupported type {:?}", name, ggml_type)
+            }
+            GgufError::Ternary(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for GgufError {}
+
+impl From<io::Error> for GgufError {
+    fn from(err: io::Error) -> Self {
+        GgufError::Io(err)
+    }
+}
+
+impl From<TernaryError> for GgufError {
+    fn from(err: TernaryError) -> Self {
+        GgufError::Ternary(err)
+    }
+}
+
+fn format_error<T>(msg: impl Into<String>) -> Result<T, GgufError> {
+    Err(GgufError::Format(msg.into()))
+}
+
+// m * k, an error if it overflows
+fn elements(m: usize, k: usize) -> Result<usize, GgufError> {
+    match m.checked_mul(k) {
+        Some(len) => Ok(len),
+        None => format_error(format!("{} x {} tensor is too large", m, k)),
+    }
+}
+
+/// The ggml type of a tensor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    Tq1_0,
+    Tq2_0,
+    I2S,
+    /// Any other type, by its id
+    Other(u32),
+}
+
+impl GgmlType {
+    fn from_id(id: u32) -> Self {
+        match id {
+            0 => GgmlType::F32,
+            1 => GgmlType::F16,
+            34 => GgmlType::Tq1_0,
+            35 => GgmlType::Tq2_0,
+            36 => GgmlType::I2S,
+            id => GgmlType::Other(id),
+        }
+    }
+
+    fn id(self) -> u32 {
+        match self {
+            GgmlType::F32 => 0,
+            GgmlType::F16 => 1,
+            GgmlType::Tq1_0 => 34,
+            GgmlType::Tq2_0 => 35,
+            GgmlType::I2S => 36,
+            GgmlType::Other(id) => id,
+        }
+    }
+}
+
+/// A metadata value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetadataValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<MetadataValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl MetadataValue {
+    fn type_id(&self) -> u32 {
+        match self {
+            MetadataValue::U8(_) => 0,
+            MetadataValue::I8(_) => 1,
+            MetadataValue::U16(_) => 2,
+            MetadataValue::I16(_) => 3,
+            MetadataValue::U32(_) => 4,
+            MetadataValue::I32(_) => 5,
+            MetadataValue::F32(_) => 6,
+            MetadataValue::Bool(_) => 7,
+            MetadataValue::String(_) => 8,
+            MetadataValue::Array(_) => 9,
+            MetadataValue::U64(_) => 10,
+            MetadataValue::I64(_) => 11,
+            MetadataValue::F64(_) => 12,
+        }
+    }
+
+    // The value as an integer, for alignment and the like
+    fn as_usize(&self) -> Option<usize> {
+        match *self {
+            MetadataValue::U8(v) => Some(v as usize),
+            MetadataValue::U16(v) => Some(v as usize),
+            MetadataValue::U32(v) => Some(v as usize),
+            MetadataValue::U64(v) => Some(v as usize),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the tensor index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub name: String,
+    /// Dimensions, innermost (contiguous) first like ggml's `ne`. For a weight matrix that is
+    /// `[k, m]`.
+    pub dims: Vec<u64>,
+    pub ggml_type: GgmlType,
+    /// Offset of the data from the start of the data section
+    pub offset: u64,
+}
+
+impl TensorInfo {
+    // (m, k) of a matrix, higher dimensions are folded into the rows. An error if the
+    // dimensions overflow
+    fn shape(&self) -> Result<(usize, usize), GgufError> {
+        let too_large = || GgufError::Format(format!("tensor {} is too large", self.name));
+        let k = self.dims.first().copied().unwrap_or(1);
+        let m = self
+            .dims
+            .iter()
+            .skip(1)
+            .try_fold(1u64, |m, dim| m.checked_mul(*dim))
+            .ok_or_else(too_large)?;
+        let (m, k) = (
+            usize::try_from(m).map_err(|_| too_large())?,
+            usize::try_from(k).map_err(|_| too_large())?,
+        );
+        elements(m, k)?;
+        Ok((m, k))
+    }
+}
+
+// Reads little-endian values from the bytes of a file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], GgufError> {
+        match self.bytes.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => format_error("unexpected end of file"),
+        }
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], GgufError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, GgufError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, GgufError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    // A length or count, checked against the size of the file so corrupt files can't make us
+    // allocate huge buffers
+    fn len(&mut self) -> Result<usize, GgufError> {
+        let len = self.u64()?;
+        if len > self.bytes.len() as u64 {
+            return format_error(format!("length {} larger than the file", len));
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<String, GgufError> {
+        let len = self.len()?;
+        match String::from_utf8(self.take(len)?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => format_error("string is not utf-8"),
+        }
+    }
+
+    // A metadata value, `depth` is the number of arrays it is nested in
+    fn value(&mut self, type_id: u32, depth: usize) -> Result<MetadataValue, GgufError> {
+        Ok(match type_id {
+            0 => MetadataValue::U8(self.array::<1>()?[0]),
+            1 => MetadataValue::I8(self.array::<1>()?[0] as i8),
+            2 => MetadataValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => MetadataValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => MetadataValue::U32(self.u32()?),
+            5 => MetadataValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => MetadataValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => MetadataValue::Bool(self.array::<1>()?[0] != 0),
+            8 => MetadataValue::String(self.string()?),
+            9 => {
+                if depth >= MAX_DEPTH {
+                    return format_error(format!("arrays nested deeper than {}", MAX_DEPTH));
+                }
+                let item_type = self.u32()?;
+                let len = self.len()?;
+                let items = (0..len).map(|_| self.value(item_type, depth + 1));
+                MetadataValue::Array(items.collect::<Result<_, _>>()?)
+            }
+            10 => MetadataValue::U64(self.u64()?),
+            11 => MetadataValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => MetadataValue::F64(f64::from_le_bytes(self.array()?)),
+            id => return format_error(format!("unknown metadata type {}", id)),
+        })
+    }
+}
+
+/// A parsed GGUF file, held in memory.
+pub struct GgufFile {
+    pub version: u32,
+    /// Metadata in file order
+    pub metadata: Vec<(String, MetadataValue)>,
+    /// The tensor index in file order
+    pub tensors: Vec<TensorInfo>,
+    bytes: Vec<u8>,
+    data_start: usize,
+    index: HashMap<String, usize>,
+}
+
+impl GgufFile {
+    /// Reads and parses a GGUF file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GgufError> {
+        Self::parse(fs::read(path)?)
+    }
+
+    /// Parses the bytes of a GGUF file
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, GgufError> {
+        let mut reader = Reader {
+            bytes: &bytes,
+            pos: 0,
+        };
+        if reader.take(4)? != MAGIC {
+            return format_error("missing GGUF magic");
+        }
+        let version = reader.u32()?;
+        if !(2..=VERSION).contains(&version) {
+            return format_error(format!("unsupported version {}", version));
+        }
+        let tensor_count = reader.len()?;
+        let metadata_count = reader.len()?;
+
+        // Nothing is reserved from the counts, a corrupt count could ask for far more memory
+        // than the file has entries
+        let mut metadata = Vec::new();
+        for _ in 0..metadata_count {
+            let key = reader.string()?;
+            let type_id = reader.u32()?;
+            metadata.push((key, reader.value(type_id, 0)?));
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..tensor_count {
+            let name = reader.string()?;
+            let n_dims = reader.u32()?;
+            let dims = (0..n_dims)
+                .map(|_| reader.u64())
+                .collect::<Result<_, _>>()?;
+            let ggml_type = GgmlType::from_id(reader.u32()?);
+            let offset = reader.u64()?;
+            tensors.push(TensorInfo {
+                name,
+                dims,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = match metadata.iter().find(|(key, _)| key == ALIGNMENT_KEY) {
+            Some((_, value)) => match value.as_usize() {
+                Some(alignment) if alignment > 0 => alignment,
+                _ => return format_error("invalid alignment"),
+            },
+            None => DEFAULT_ALIGNMENT,
+        };
+        let data_start = reader.pos.next_multiple_of(alignment);
+        let index = tensors
+            .iter()
+            .enumerate()
+            .map(|(i, info)| (info.name.clone(), i))
+            .collect();
+
+        Ok(Self {
+            version,
+            metadata,
+            tensors,
+            bytes,
+            data_start,
+            index,
+        })
+    }
+
+    /// Looks up a metadata value
+    pub fn metadata(&self, key: &str) -> Option<&MetadataValue> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Looks up a tensor in the index
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.index.get(name).map(|i| &self.tensors[*i])
+    }
+
+    /// The raw data of a tensor, from its offset to the end of the file
+    pub fn tensor_data(&self, info: &TensorInfo) -> Result<&[u8], GgufError> {
+        let start = usize::try_from(info.offset)
+            .ok()
+            .and_then(|offset| offset.checked_add(self.data_start))
+            .filter(|start| *start <= self.bytes.len());
+        match start {
+            Some(start) => Ok(&self.bytes[start..]),
+            None => format_error(format!("tensor {} starts past the end", info.name)),
+        }
+    }
+
+    /// Decodes a TQ1_0, TQ2_0 or I2_S tensor into compressed val/sign bits and its scales,
+    /// `PerGroup(256)` for TQ1_0 and TQ2_0 and `PerTensor` for I2_S.
+    pub fn ternary(&self, name: &str) -> Result<TernaryTensor, GgufError> {
+        let info = self
+            .tensor(name)
+            .ok_or_else(|| GgufError::MissingTensor(name.to_string()))?;
+        let (m, k) = info.shape()?;
+        let data = self.tensor_data(info)?;
+
+        let (values, granularity, scales) = match info.ggml_type {
+            GgmlType::Tq1_0 | GgmlType::Tq2_0 => {
+                let (values, scales) = decode_tq(info.ggml_type, m, k, data)?;
+                (values, Granularity::PerGroup(QK_K), scales)
+            }
+            GgmlType::I2S => {
+                let (values, scale) = decode_i2_s(m, k, data)?;
+                (values, Granularity::PerTensor, vec![scale])
+            }
+            ggml_type => {
+                return Err(GgufError::UnsupportedType {
+                    name: name.to_string(),
+                    ggml_type,
+                })
+            }
+        };
+
+        let (vals, signs) = try_compress_a(m, k, &values, Layout::RowMajor, k.max(1))?;
+        Ok(TernaryTensor {
+            m,
+            k,
+            vals,
+            signs,
+            granularity,
+            scales,
+        })
+    }
+}
+
+// Decodes trit n (from the most significant one) of a base-3 TQ1_0 byte. The bytes store the
+// trits as a fixed-point fraction of 243, multiplying by 3^n shifts trit n to the top.
+fn tq1_0_trit(q: u8, n: usize) -> i8 {
+    ((q.wrapping_mul(POW3[n]) as u16 * 3) >> 8) as i8 - 1
+}
+
+// Encodes up to 5 trits into a TQ1_0 byte, the first one most significant
+fn tq1_0_byte(trits: impl Iterator<Item = i8>) -> u8 {
+    let mut q = 0_u16;
+    let mut count = 0;
+    for trit in trits {
+        q = q * 3 + (trit + 1) as u16;
+        count += 1;
+    }
+    // Fill up to 5 trits, then round up to the fraction of 243
+    q *= 3_u16.pow(5 - count);
+    (q * 256).div_ceil(243) as u8
+}
+
+// Decodes a TQ1_0 block into 256 trits, returns its scale
+fn decode_tq1_0(block: &[u8], out: &mut [i8]) -> f32 {
+    let (qs, qh) = (&block[..TQ1_0_QS], &block[TQ1_0_QS..(TQ1_0_QS + TQ1_0_QH)]);
+    let mut out = out.iter_mut();
+    // 32 bytes of 5 trits, then 16 bytes of 5 trits, every trit is a run of 32 or 16 weights
+    for (start, width) in [(0, 32), (32, 16)] {
+        for n in 0..5 {
+            for q in &qs[start..(start + width)] {
+                *out.next().unwrap() = tq1_0_trit(*q, n);
+            }
+        }
+    }
+    // The last 16 weights, 4 trits per byte
+    for n in 0..4 {
+        for q in qh {
+            *out.next().unwrap() = tq1_0_trit(*q, n);
+        }
+    }
+    f16_scale(&block[(TQ1_0_BYTES - 2)..])
+}
+
+fn encode_tq1_0(trits: &[i8], scale: f32, block: &mut [u8]) {
+    let mut offset = 0;
+    for (start, width) in [(0, 32), (32, 16)] {
+        for m in 0..width {
+            let column = (0..5).map(|n| trits[offset + m + n * width]);
+            block[start + m] = tq1_0_byte(column);
+        }
+        offset += 5 * width;
+    }
+    for j in 0..TQ1_0_QH {
+        let column = (0..4).map(|n| trits[offset + j + n * TQ1_0_QH]);
+        block[TQ1_0_QS + j] = tq1_0_byte(column);
+    }
+    block[(TQ1_0_BYTES - 2)..].copy_from_slice(&(scale as f16).to_bits().to_le_bytes());
+}
+
+// Decodes a TQ2_0 block into 256 trits, returns its scale. Byte j + m of every 32 bytes holds
+// weights 4 * j + 32 * l + m in bits 2l and 2l + 1, 0 to 2 are -1 to 1.
+fn decode_tq2_0(block: &[u8], out: &mut [i8]) -> f32 {
+    for j in (0..(QK_K / 4)).step_by(32) {
+        for l in 0..4 {
+            for m in 0..32 {
+                out[4 * j + 32 * l + m] = ((block[j + m] >> (2 * l)) & 3) as i8 - 1;
+            }
+        }
+    }
+    f16_scale(&block[(TQ2_0_BYTES - 2)..])
+}
+
+fn encode_tq2_0(trits: &[i8], scale: f32, block: &mut [u8]) {
+    for j in (0..(QK_K / 4)).step_by(32) {
+        for m in 0..32 {
+            block[j + m] = (0..4)
+                .map(|l| ((trits[4 * j + 32 * l + m] + 1) as u8) << (2 * l))
+                .sum();
+        }
+    }
+    block[(TQ2_0_BYTES - 2)..].copy_from_slice(&(scale as f16).to_bits().to_le_bytes());
+}
+
+fn f16_scale(bytes: &[u8]) -> f32 {
+    f16::from_bits(u16::from_le_bytes([bytes[0], bytes[1]])) as f32
+}
+
+fn block_bytes(ggml_type: GgmlType) -> usize {
+    match ggml_type {
+        GgmlType::Tq1_0 => TQ1_0_BYTES,
+        _ => TQ2_0_BYTES,
+    }
+}
+
+// Decodes the rows of TQ1_0 or TQ2_0 blocks of an m x k tensor into row-major trits and the
+// scales of the blocks
+fn decode_tq(
+    ggml_type: GgmlType,
+    m: usize,
+    k: usize,
+    data: &[u8],
+) -> Result<(Vec<i8>, Vec<f32>), GgufError> {
+    if !k.is_multiple_of(QK_K) {
+        return Err(TernaryError::MisalignedK { k, multiple: QK_K }.into());
+    }
+    let len = elements(m, k)?;
+    let blocks = len / QK_K;
+    let size = block_bytes(ggml_type);
+    if data.len() < blocks * size {
+        return Err(TernaryError::BufferTooSmall {
+            what: "tensor data",
+            len: data.len(),
+            required: blocks * size,
+        }
+        .into());
+    }
+
+    let mut values = vec![0; len];
+    let mut scales = Vec::with_capacity(blocks);
+    for (block, out) in data.chunks_exact(size).zip(values.chunks_exact_mut(QK_K)) {
+        scales.push(match ggml_type {
+            GgmlType::Tq1_0 => decode_tq1_0(block, out),
+            _ => decode_tq2_0(block, out),
+        });
+    }
+    Ok((values, scales))
+}
+
+// Decodes an I2_S tensor into row-major trits and its scale. Byte j % 32 of every 32 bytes
+// holds weight j of a block of 128 in bits 6 - 2 * (j / 32) and 7 - 2 * (j / 32).
+fn decode_i2_s(m: usize, k: usize, data: &[u8]) -> Result<(Vec<i8>, f32), GgufError> {
+    if !k.is_multiple_of(QK_I2) {
+        return Err(TernaryError::MisalignedK { k, multiple: QK_I2 }.into());
+    }
+    let len = elements(m, k)?;
+    let bytes = len / 4;
+    if data.len() < bytes + 4 {
+        return Err(TernaryError::BufferTooSmall {
+            what: "tensor data",
+            len: data.len(),
+            required: bytes + 4,
+        }
+        .into());
+    }
+
+    let mut values = vec![0; len];
+    for (block, out) in data[..bytes]
+        .chunks_exact(32)
+        .zip(values.chunks_exact_mut(QK_I2))
+    {
+        for (j, value) in out.iter_mut().enumerate() {
+            *value = ((block[j % 32] >> (6 - 2 * (j / 32))) & 3) as i8 - 1;
+        }
+    }
+    let scale = f32::from_le_bytes(data[bytes..(bytes + 4)].try_into().unwrap());
+    Ok((values, scale))
+}
+
+fn encode_i2_s(trits: &[i8], scale: f32) -> Vec<u8> {
+    let mut data = vec![0; trits.len() / 4];
+    for (block, trits) in data.chunks_exact_mut(32).zip(trits.chunks_exact(QK_I2)) {
+        for (j, trit) in trits.iter().enumerate() {
+            block[j % 32] |= ((trit + 1) as u8) << (6 - 2 * (j / 32));
+        }
+    }
+    data.extend_from_slice(&scale.to_le_bytes());
+    // bitnet.cpp pads the scale to 32 bytes
+    data.resize(data.len() + 28, 0);
+    data
+}
+
+/// Writes GGUF files with ternary (and raw) tensors.
+#[derive(Default)]
+pub struct GgufWriter {
+    metadata: Vec<(String, MetadataValue)>,
+    tensors: Vec<(String, Vec<u64>, GgmlType, Vec<u8>)>,
+}
+
+impl GgufWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a metadata entry
+    pub fn metadata(&mut self, key: &str, value: MetadataValue) {
+        self.metadata.push((key.to_string(), value));
+    }
+
+    /// Adds a tensor with already encoded data, dims innermost first
+    pub fn raw_tensor(&mut self, name: &str, dims: &[u64], ggml_type: GgmlType, data: Vec<u8>) {
+        self.tensors
+            .push((name.to_string(), dims.to_vec(), ggml_type, data));
+    }
+
+    /// Encodes a row-major ternary `m x k` matrix as TQ1_0, TQ2_0 (one scale per block of 256
+    /// weights of a row, so `m * k / 256` scales) or I2_S (a single scale).
+    pub fn ternary_tensor(
+        &mut self,
+        name: &str,
+        m: usize,
+        k: usize,
+        values: &[i8],
+        ggml_type: GgmlType,
+        scales: &[f32],
+    ) -> Result<(), GgufError> {
+        let len = elements(m, k)?;
+        if values.len() < len {
+            return Err(TernaryError::BufferTooSmall {
+                what: "values",
+                len: values.len(),
+                required: len,
+            }
+            .into());
+        }
+        let values = &values[..len];
+        validate_trits(values)?;
+
+        let (block, scale_count) = match ggml_type {
+            GgmlType::Tq1_0 | GgmlType::Tq2_0 => (QK_K, len / QK_K),
+            GgmlType::I2S => (QK_I2, 1),
+            ggml_type => {
+                return Err(GgufError::UnsupportedType {
+                    name: name.to_string(),
+                    ggml_type,
+                })
+            }
+        };
+        if !k.is_multiple_of(block) {
+            return Err(TernaryError::MisalignedK { k, multiple: block }.into());
+        }
+        if scales.len() < scale_count {
+            return Err(TernaryError::BufferTooSmall {
+                what: "scales",
+                len: scales.len(),
+                required: scale_count,
+            }
+            .into());
+        }
+
+        let data = match ggml_type {
+            GgmlType::I2S => encode_i2_s(values, scales[0]),
+            _ => {
+                let size = block_bytes(ggml_type);
+                let mut data = vec![0; scale_count * size];
+                let blocks = data.chunks_exact_mut(size).zip(values.chunks_exact(QK_K));
+                for ((block, trits), scale) in blocks.zip(scales) {
+                    match ggml_type {
+                        GgmlType::Tq1_0 => encode_tq1_0(trits, *scale, block),
+                        _ => encode_tq2_0(trits, *scale, block),
+                    }
+                }
+                data
+            }
+        };
+        self.raw_tensor(name, &[k as u64, m as u64], ggml_type, data);
+        Ok(())
+    }
+
+    /// The bytes of the file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn string(out: &mut Vec<u8>, s: &str) {
+            out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+        fn value(out: &mut Vec<u8>, v: &MetadataValue) {
+            match v {
+                MetadataValue::U8(v) => out.push(*v),
+                MetadataValue::I8(v) => out.push(*v as u8),
+                MetadataValue::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+                MetadataValue::I16(v) => out.extend_from_slice(&v.to_le_bytes()),
+                MetadataValue::U32(v) => out.extend_from_slice(&v.to_le_bytes()),
+                MetadataValue::I32(v) => out.extend_from_slice(&v.to_le_bytes()),
+                MetadataValue::F32(v) => out.extend_from_slice(&v.to_le_bytes()),
+                MetadataValue::Bool(v) => out.push(*v as u8),
+                MetadataValue::String(v) => string(out, v),
+                MetadataValue::Array(items) => {
+                    // Arrays are homogeneous, empty ones are written as u8 arrays
+                    let item_type = items.first().map_or(0, |item| item.type_id());
+                    out.extend_from_slice(&item_type.to_le_bytes());
+                    out.extend_from_slice(&(items.len() as u64).to_le_bytes());
+                    for item in items {
+                        value(out, item);
+                    }
+                }
+                MetadataValue::U64(v) => out.extend_from_slice(&v.to_le_bytes()),
+                MetadataValue::I64(v) => out.extend_from_slice(&v.to_le_bytes()),
+                MetadataValue::F64(v) => out.extend_from_slice(&v.to_le_bytes()),
+            }
+        }
+
+        let alignment = self
+            .metadata
+            .iter()
+            .find(|(key, _)| key == ALIGNMENT_KEY)
+            .and_then(|(_, value)| value.as_usize())
+            .unwrap_or(DEFAULT_ALIGNMENT);
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.tensors.len() as u64).to_le_bytes());
+        out.extend_from_slice(&(self.metadata.len() as u64).to_le_bytes());
+        for (key, v) in &self.metadata {
+            string(&mut out, key);
+            out.extend_from_slice(&v.type_id().to_le_bytes());
+            value(&mut out, v);
+        }
+
+        let mut offset = 0;
+        for (name, dims, ggml_type, data) in &self.tensors {
+            string(&mut out, name);
+            out.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+            for dim in dims {
+                out.extend_from_slice(&dim.to_le_bytes());
+            }
+            out.extend_from_slice(&ggml_type.id().to_le_bytes());
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+            offset = (offset + data.len()).next_multiple_of(alignment);
+        }
+
+        for (_, _, _, data) in &self.tensors {
+            out.resize(out.len().next_multiple_of(alignment), 0);
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    /// Writes the file
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), GgufError> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gemm::{compress_a, Granularity, Layout, Scale, TernaryError},
+        test_util::test_util::rand_vecs_sized,
+    };
+
+    use super::{
+        decode_tq1_0, decode_tq2_0, GgmlType, GgufError, GgufFile, GgufWriter, MetadataValue,
+        MAX_DEPTH, QK_K, TQ1_0_BYTES, TQ2_0_BYTES,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let (m, k) = (6, 512);
+        let (values, _) = rand_vecs_sized(m * k, 0);
+        let scales: Vec<f32> = (0..(m * k / QK_K)).map(|i| 0.5 + i as f32 * 0.25).collect();
+
+        let mut writer = GgufWriter::new();
+        writer.metadata(
+            "general.architecture",
+            MetadataValue::String("bitnet".into()),
+        );
+        writer.metadata(
+            "bitnet.dims",
+            MetadataValue::Array(vec![MetadataValue::U32(1), MetadataValue::U32(2)]),
+        );
+        for (name, ggml_type) in [
+            ("tq1", GgmlType::Tq1_0),
+            ("tq2", GgmlType::Tq2_0),
+            ("i2s", GgmlType::I2S),
+        ] {
+            writer
+                .ternary_tensor(name, m, k, &values, ggml_type, &scales)
+                .unwrap();
+        }
+        writer.raw_tensor("norm", &[3], GgmlType::F32, vec![0; 12]);
+
+        let path = std::env::temp_dir().join(format!("ternary-{}.gguf", std::process::id()));
+        writer.write(&path).unwrap();
+        let file = GgufFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(file.version, 3);
+        assert_eq!(
+            file.metadata("general.architecture"),
+            Some(&MetadataValue::String("bitnet".into()))
+        );
+        assert_eq!(file.metadata.len(), 2);
+        assert_eq!(file.tensors.len(), 4);
+        assert_eq!(file.tensor("tq1").unwrap().dims, [k as u64, m as u64]);
+
+        let expected = compress_a(m, k, &values, Layout::RowMajor, k);
+        for name in ["tq1", "tq2", "i2s"] {
+            let tensor = file.ternary(name).unwrap();
+            assert_eq!((tensor.m, tensor.k, tensor.ld()), (m, k, k / 8));
+            assert_eq!(
+                (tensor.vals.clone(), tensor.signs.clone()),
+                expected,
+                "{}",
+                name
+            );
+            if name == "i2s" {
+                assert_eq!(tensor.granularity, Granularity::PerTensor);
+                assert_eq!(tensor.w_scale(), Some(Scale::PerTensor(scales[0])));
+            } else {
+                // The block scales are f16, these ones are exact
+                assert_eq!(tensor.granularity, Granularity::PerGroup(QK_K));
+                assert_eq!(tensor.scales, scales);
+            }
+        }
+
+        assert!(matches!(
+            file.ternary("norm"),
+            Err(GgufError::UnsupportedType {
+                ggml_type: GgmlType::F32,
+                ..
+            })
+        ));
+        assert!(matches!(
+            file.ternary("missing"),
+            Err(GgufError::MissingTensor(_))
+        ));
+    }
+
+    #[test]
+    fn test_blocks() {
+        // All -1 and all +1 blocks
+        let mut out = [0; QK_K];
+        let mut block = [0; TQ1_0_BYTES];
+        decode_tq1_0(&block, &mut out);
+        assert!(out.iter().all(|e| *e == -1));
+        block[..52].fill(255);
+        decode_tq1_0(&block, &mut out);
+        assert!(out.iter().all(|e| *e == 1));
+
+        // Weight 4 * j + 32 * l + m of TQ2_0 is in bits 2l of byte j + m
+        let mut block = [0b01_01_01_01; TQ2_0_BYTES];
+        block[33] = 0b01_10_01_00;
+        decode_tq2_0(&block, &mut out);
+        let (j, m) = (32, 1);
+        assert_eq!(out[4 * j + m], -1);
+        assert_eq!(out[4 * j + 32 + m], 0);
+        assert_eq!(out[4 * j + 64 + m], 1);
+        assert_eq!(out.iter().filter(|e| **e != 0).count(), 2);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut writer = GgufWriter::new();
+        let values = vec![0; 2 * 100];
+        assert!(matches!(
+            writer.ternary_tensor("w", 2, 100, &values, GgmlType::Tq2_0, &[1.]),
+            Err(GgufError::Ternary(TernaryError::MisalignedK {
+                k: 100,
+                multiple: 256
+            }))
+        ));
+        let mut values = vec![0; 256];
+        values[3] = 2;
+        assert!(matches!(
+            writer.ternary_tensor("w", 1, 256, &values, GgmlType::Tq2_0, &[1.]),
+            Err(GgufError::Ternary(TernaryError::InvalidTrit {
+                index: 3,
+                ..
+            }))
+        ));
+
+        writer
+            .ternary_tensor("w", 1, 256, &[1; 256], GgmlType::Tq2_0, &[1.])
+            .unwrap();
+        let bytes = writer.to_bytes();
+        assert!(matches!(
+            GgufFile::parse(b"GGML".to_vec()),
+            Err(GgufError::Format(_))
+        ));
+        for len in [10, 40] {
+            assert!(matches!(
+                GgufFile::parse(bytes[..len].to_vec()),
+                Err(GgufError::Format(_))
+            ));
+        }
+        // The index parses, the data is cut off
+        let file = GgufFile::parse(bytes[..(bytes.len() - 1)].to_vec()).unwrap();
+        assert!(matches!(
+            file.ternary("w"),
+            Err(GgufError::Ternary(TernaryError::BufferTooSmall { .. }))
+        ));
+
+        // Dimensions whose product overflows
+        let mut writer = GgufWriter::new();
+        writer.raw_tensor("w", &[256, u64::MAX, 2], GgmlType::Tq2_0, vec![]);
+        let file = GgufFile::parse(writer.to_bytes()).unwrap();
+        assert!(matches!(file.ternary("w"), Err(GgufError::Format(_))));
+
+        // Counts up to the size of the file that the entries don't fill
+        for counts in [[0, 1 << 20], [1 << 20, 0], [u64::MAX, 0]] {
+            let mut header = b"GGUF".to_vec();
+            header.extend_from_slice(&3u32.to_le_bytes());
+            for count in counts {
+                header.extend_from_slice(&count.to_le_bytes());
+            }
+            header.resize(2 << 20, 0);
+            assert!(matches!(GgufFile::parse(header), Err(GgufError::Format(_))));
+        }
+
+        // Arrays nested too deep
+        let mut value = MetadataValue::U8(0);
+        for _ in 0..=MAX_DEPTH {
+            value = MetadataValue::Array(vec![value]);
+        }
+        let mut writer = GgufWriter::new();
+        writer.metadata("nested", value);
+        assert!(matches!(
+            GgufFile::parse(writer.to_bytes()),
+            Err(GgufError::Format(_))
+        ));
+    }
+}
@@ -1,8 +1,12 @@
 #![feature(portable_simd)]
-#![feature(stdarch_aarch64_prefetch)]
-#![feature(core_intrinsics)]
+#![feature(f16)]
+pub mod array;
+pub mod container;
 pub mod dots;
+pub mod gemm;
+pub mod gguf;
 pub mod muls;
+pub mod safetensors;
 
 pub mod constants;
 pub mod test_util;
@@ -1,3 +1,54 @@
+//! Runs kernels once on random `SIZE` matrices and prints how long they took.
+//!
+//! `cargo run --release` runs all kernels, `cargo run --release -- mm9 gemm` only the given
+//! ones and `cargo run -- --list` lists their names.
+use std::{env, process, time::Instant};
+
+use matmul::{
+    constants::SIZE,
+    muls::{find, MatmulKernel, KERNELS},
+    test_util::test_util::rand_vecs,
+};
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.iter().any(|arg| arg == "--list") {
+        for kernel in KERNELS {
+            println!("{}", kernel.name());
+        }
+        return;
+    }
+
+    let kernels: Vec<&dyn MatmulKernel> = if args.is_empty() {
+        KERNELS.to_vec()
+    } else {
+        args.iter()
+            .map(|name| {
+                find(name).unwrap_or_else(|| {
+                    eprintln!("unknown kernel {}, see --list", name);
+                    process::exit(1);
+                })
+            })
+            .collect()
+    };
+
+    let (a, b) = rand_vecs(SIZE * SIZE);
+    println!("m = k = n = {}", SIZE);
+    for kernel in kernels {
+        let prepared = kernel.prepare(SIZE, SIZE, SIZE, &a, &b);
+
+        let start = Instant::now();
+        let c = kernel.execute(SIZE, SIZE, SIZE, &prepared);
+        let elapsed = start.elapsed();
+
+        // Print a checksum so kernels can be compared at a glance
+        let checksum: i64 = c.iter().map(|e| *e as i64).sum();
+        println!(
+            "{: <6} {: >12.3?}  checksum {}",
+            kernel.name(),
+            elapsed,
+            checksum
+        );
+    }
 }
@@ -0,0 +1,305 @@
+//! A common interface over the attempts, so tests, benches and the CLI can iterate over all of
+//! them instead of calling each differently-shaped `matmulN` by hand.
+use crate::{
+    constants::SIZE,
+    gemm::{self, validate_trits, TernaryError},
+};
+
+use super::{mm1, mm10, mm11, mm2, mm3, mm4, mm5, mm6, mm7, mm8, mm9};
+
+/// Operands of a matmul after [`MatmulKernel::prepare`].
+pub enum Prepared {
+    /// Col-major ternary A and B, unchanged
+    Raw { a: Vec<i8>, b: Vec<i8> },
+    /// Compressed val/sign bits of A and B, in the layout the kernel expects
+    Compressed {
+        a_vals: Vec<u8>,
+        a_signs: Vec<u8>,
+        b_vals: Vec<u8>,
+        b_signs: Vec<u8>,
+    },
+    /// Packed weight-pair indices of A (see [`mm11::pack11`]) and unchanged B
+    Lut { a: Vec<u8>, b: Vec<i8> },
+}
+
+/// A matmul of a col-major ternary `m x k` A and `k x n` B into col-major `m x n` C.
+/// Results are i8 and wrap on overflow.
+pub trait MatmulKernel: Sync {
+    /// Name of the kernel, e.g. `mm9`
+    fn name(&self) -> &'static str;
+
+    /// Why the kernel can't multiply these shapes, if it can't. The attempts in `muls/` only
+    /// support square `SIZE` matrices.
+    fn check_shape(&self, m: usize, k: usize, n: usize) -> Result<(), TernaryError> {
+        for (what, found) in [("m", m), ("k", k), ("n", n)] {
+            if found != SIZE {
+                return Err(TernaryError::ShapeMismatch {
+                    what,
+                    expected: SIZE,
+                    found,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the kernel can multiply these shapes
+    fn supports(&self, m: usize, k: usize, n: usize) -> bool {
+        self.check_shape(m, k, n).is_ok()
+    }
+
+    /// Compresses/packs A and B once, outside of the timed part of a benchmark
+    fn prepare(&self, m: usize, k: usize, n: usize, a: &[i8], b: &[i8]) -> Prepared;
+
+    /// Same as [`MatmulKernel::prepare`], but checks the shapes, buffer sizes and values first
+    /// and returns an error instead of panicking in a `matmulN`
+    fn try_prepare(
+        &self,
+        m: usize,
+        k: usize,
+        n: usize,
+        a: &[i8],
+        b: &[i8],
+    ) -> Result<Prepared, TernaryError> {
+        self.check_shape(m, k, n)?;
+        for (what, buffer, required) in [("a", a, m * k), ("b", b, k * n)] {
+            if buffer.len() < required {
+                return Err(TernaryError::BufferTooSmall {
+                    what,
+                    len: buffer.len(),
+                    required,
+                });
+            }
+            validate_trits(&buffer[..required])?;
+        }
+        Ok(self.prepare(m, k, n, &a[..(m * k)], &b[..(k * n)]))
+    }
+
+    /// Computes C from the output of [`MatmulKernel::prepare`]
+    fn execute(&self, m: usize, k: usize, n: usize, prepared: &Prepared) -> Vec<i8>;
+}
+
+// Kernel for an attempt with a `matmulN(a, b)` function
+macro_rules! raw_kernel {
+    ($num: literal) => {
+        paste::item! {
+            pub struct [< Mm $num >];
+
+            impl MatmulKernel for [< Mm $num >] {
+                fn name(&self) -> &'static str {
+                    concat!("mm", $num)
+                }
+
+                fn prepare(&self, m: usize, k: usize, n: usize, a: &[i8], b: &[i8]) -> Prepared {
+                    assert!(self.supports(m, k, n));
+                    Prepared::Raw {
+                        a: a.to_vec(),
+                        b: b.to_vec(),
+                    }
+                }
+
+                fn execute(&self, _: usize, _: usize, _: usize, prepared: &Prepared) -> Vec<i8> {
+                    match prepared {
+                        Prepared::Raw { a, b } => [< mm $num >]::[< matmul $num >](a, b),
+                        _ => panic!("{} expects raw operands", self.name()),
+                    }
+                }
+            }
+        }
+    };
+}
+
+// Kernel for an attempt with `prepN(a, b)` and `matmulN(a_vals, a_signs, b_vals, b_signs)`
+macro_rules! compressed_kernel {
+    ($num: literal) => {
+        paste::item! {
+            pub struct [< Mm $num >];
+
+            impl MatmulKernel for [< Mm $num >] {
+                fn name(&self) -> &'static str {
+                    concat!("mm", $num)
+                }
+
+                fn prepare(&self, m: usize, k: usize, n: usize, a: &[i8], b: &[i8]) -> Prepared {
+                    assert!(self.supports(m, k, n));
+                    let (a_vals, a_signs, b_vals, b_signs) = [< mm $num >]::[< prep $num >](a, b);
+                    Prepared::Compressed {
+                        a_vals,
+                        a_signs,
+                        b_vals,
+                        b_signs,
+                    }
+                }
+
+                fn execute(&self, _: usize, _: usize, _: usize, prepared: &Prepared) -> Vec<i8> {
+                    match prepared {
+                        Prepared::Compressed {
+                            a_vals,
+                            a_signs,
+                            b_vals,
+                            b_signs,
+                        } => [< mm $num >]::[< matmul $num >](a_vals, a_signs, b_vals, b_signs),
+                        _ => panic!("{} expects compressed operands", self.name()),
+                    }
+                }
+            }
+        }
+    };
+}
+
+raw_kernel!(1);
+raw_kernel!(2);
+raw_kernel!(3);
+raw_kernel!(4);
+raw_kernel!(5);
+raw_kernel!(6);
+
+// From now on, we compress ternary matrices
+compressed_kernel!(7);
+compressed_kernel!(8);
+compressed_kernel!(9);
+compressed_kernel!(10);
+
+/// The lookup-table kernel, A is packed into table indices and B stays int8
+pub struct Mm11;
+
+impl MatmulKernel for Mm11 {
+    fn name(&self) -> &'static str {
+        "mm11"
+    }
+
+    fn prepare(&self, m: usize, k: usize, n: usize, a: &[i8], b: &[i8]) -> Prepared {
+        assert!(self.supports(m, k, n));
+        let (a, b) = mm11::prep11(a, b);
+        Prepared::Lut { a, b }
+    }
+
+    fn execute(&self, _: usize, _: usize, _: usize, prepared: &Prepared) -> Vec<i8> {
+        match prepared {
+            Prepared::Lut { a, b } => mm11::matmul11(a, b),
+            _ => panic!("mm11 expects lookup-table operands"),
+        }
+    }
+}
+
+/// The general-shape driver in `gemm/`, for comparison with the attempts
+pub struct Gemm;
+
+impl MatmulKernel for Gemm {
+    fn name(&self) -> &'static str {
+        "gemm"
+    }
+
+    fn check_shape(&self, _: usize, _: usize, _: usize) -> Result<(), TernaryError> {
+        Ok(())
+    }
+
+    fn prepare(&self, m: usize, k: usize, n: usize, a: &[i8], b: &[i8]) -> Prepared {
+        let (a_vals, a_signs, b_vals, b_signs) = gemm::prep(m, k, n, a, b);
+        Prepared::Compressed {
+            a_vals,
+            a_signs,
+            b_vals,
+            b_signs,
+        }
+    }
+
+    fn execute(&self, m: usize, k: usize, n: usize, prepared: &Prepared) -> Vec<i8> {
+        match prepared {
+            Prepared::Compressed {
+                a_vals,
+                a_signs,
+                b_vals,
+                b_signs,
+            } => gemm::matmul(m, k, n, a_vals, a_signs, b_vals, b_signs),
+            _ => panic!("gemm expects compressed operands"),
+        }
+    }
+}
+
+/// All kernels, in the order they were written
+pub static KERNELS: [&dyn MatmulKernel; 12] = [
+    &Mm1, &Mm2, &Mm3, &Mm4, &Mm5, &Mm6, &Mm7, &Mm8, &Mm9, &Mm10, &Gemm, &Mm11,
+];
+
+/// Looks up a kernel by name
+pub fn find(name: &str) -> Option<&'static dyn MatmulKernel> {
+    KERNELS.iter().copied().find(|kernel| kernel.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{
+        constants::SIZE,
+        test_util::test_util::{test_matmul, test_matmul_shape},
+    };
+
+    use crate::gemm::TernaryError;
+
+    use super::{find, KERNELS};
+
+    #[test]
+    fn test_registry() {
+        let names: HashSet<_> = KERNELS.iter().map(|kernel| kernel.name()).collect();
+        assert_eq!(names.len(), KERNELS.len());
+        assert_eq!(find("mm9").unwrap().name(), "mm9");
+        assert!(find("mm12").is_none());
+    }
+
+    #[test]
+    fn test_all_kernels() {
+        for kernel in KERNELS {
+            test_matmul(|a, b| {
+                let prepared = kernel.prepare(SIZE, SIZE, SIZE, a, b);
+                kernel.execute(SIZE, SIZE, SIZE, &prepared)
+            });
+        }
+    }
+
+    #[test]
+    fn test_supported_shapes() {
+        let (m, k, n) = (37, 13, 21);
+        for kernel in KERNELS.iter().filter(|kernel| kernel.supports(m, k, n)) {
+            test_matmul_shape(m, k, n, |a, b| {
+                let prepared = kernel.prepare(m, k, n, a, b);
+                kernel.execute(m, k, n, &prepared)
+            });
+        }
+        assert!(!find("mm9").unwrap().supports(m, k, n));
+    }
+
+    #[test]
+    fn test_try_prepare() {
+        let (a, b) = (vec![0; SIZE * SIZE], vec![1; SIZE * SIZE]);
+        let mm9 = find("mm9").unwrap();
+        assert_eq!(
+            mm9.try_prepare(SIZE, 8, SIZE, &a, &b).err(),
+            Some(TernaryError::ShapeMismatch {
+                what: "k",
+                expected: SIZE,
+                found: 8
+            })
+        );
+        assert_eq!(
+            mm9.try_prepare(SIZE, SIZE, SIZE, &a[1..], &b).err(),
+            Some(TernaryError::BufferTooSmall {
+                what: "a",
+                len: SIZE * SIZE - 1,
+                required: SIZE * SIZE
+            })
+        );
+
+        let mut b = b;
+        b[7] = 5;
+        for kernel in KERNELS {
+            assert_eq!(
+                kernel.try_prepare(SIZE, SIZE, SIZE, &a, &b).err(),
+                Some(TernaryError::InvalidTrit { index: 7, value: 5 }),
+                "{}",
+                kernel.name()
+            );
+        }
+    }
+}
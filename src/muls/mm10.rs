@@ -1,11 +1,10 @@
 use std::{cmp::min, simd::Simd, thread};
 
-use crate::{constants::SIZE, test_util::print_matrix};
+use crate::{
+    constants::SIZE,
+    gemm::{compress_a, compress_b, popcount, Layout},
+};
 
-// col-major order with stride SIZE
-fn c_at(r: usize, c: usize) -> usize {
-    r + SIZE * c
-}
 // col-major with stride SIZE / 8 (compression factor) (compressed in k/rows)
 fn b_at(r: usize, c: usize) -> usize {
     r + SIZE / 8 * c
@@ -19,20 +18,6 @@ fn at(r: usize, c: usize, cstride: usize) -> usize {
     r + cstride * c
 }
 
-/// Counts bits in SIMD lanes.
-/// Had to go unsafe because popcount is missing in portable SIMD
-/// # Safety
-/// The bit count of a u8 can never be outside the range of 0-8
-/// which is safe for both unsigned and signed ints so we can safely cast
-#[cfg(target_arch = "aarch64")]
-#[cfg(target_feature = "neon")]
-unsafe fn popcount(input: Simd<u8, 16>) -> Simd<i8, 16> {
-    use core::arch::aarch64::*;
-    use std::simd::num::SimdUint;
-    let count = vcntq_u8(uint8x16_t::from(input));
-    Simd::from(count).cast::<i8>()
-}
-
 #[inline(always)]
 fn dot(
     a_val: Simd<u8, 16>,
@@ -47,13 +32,11 @@ fn dot(
     let sign = val & or;
 
     // Add vals and counts
-    unsafe {
-        let val_count = popcount(val);
-        let sign_count = popcount(sign);
-        let sign_countx2 = sign_count << 1;
+    let val_count = popcount(val);
+    let sign_count = popcount(sign);
+    let sign_countx2 = sign_count << 1;
 
-        val_count - sign_countx2
-    }
+    val_count - sign_countx2
 }
 
 // Computes an 16x16 block of C
@@ -217,71 +200,13 @@ fn inner_kernel(
     }
 }
 
-// Compresses a vec of ternary i8s into val and sign u8s
-fn compress(input: &[i8]) -> (Vec<u8>, Vec<u8>) {
-    assert_eq!(input.len() % 8, 0);
-
-    let mut vals = Vec::with_capacity(input.len() / 8);
-    let mut signs = Vec::with_capacity(input.len() / 8);
-
-    for i in (0..input.len()).step_by(8) {
-        let mut val = 0_u8;
-        let mut sign = 0_u8;
-        for j in 0..8 {
-            let (v, s) = match &input[i + j] {
-                -1 => (1, 1),
-                1 => (1, 0),
-                0 => (0, 0),
-                _ => unreachable!(),
-            };
-            val |= v << j;
-            sign |= s << j;
-        }
-        vals.push(val);
-        signs.push(sign);
-    }
-
-    (vals, signs)
-}
-
-fn col_major_to_row_major<T>(size: (usize, usize), input: &[T]) -> Vec<T>
-where
-    T: Copy,
-{
-    let (rows, cols) = size;
-    let mut out = Vec::with_capacity(input.len());
-    for ri in 0..rows {
-        for ci in 0..cols {
-            out.push(input[ci * rows + ri]);
-        }
-    }
-    out
-}
-fn row_major_to_col_major<T>(size: (usize, usize), input: &[T]) -> Vec<T>
-where
-    T: Copy,
-{
-    let (rows, cols) = size;
-    let mut out = Vec::with_capacity(input.len());
-    for ci in 0..cols {
-        for ri in 0..rows {
-            out.push(input[ri * cols + ci]);
-        }
-    }
-    out
-}
-
 pub fn prep10(a: &[i8], b: &[i8]) -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) {
     // one u16 contains 8 ternary values (u8 vals + u8 signs)
     // So the resulting matrix is 4x smaller than the original
 
-    // transform a from col-major to row-major, compress and transform back to col-major
-    let a_row = col_major_to_row_major((SIZE, SIZE), &a);
-    let (a_vals_row, a_signs_row) = compress(&a_row);
-    let a_vals = row_major_to_col_major((SIZE, SIZE / 8), &a_vals_row);
-    let a_signs = row_major_to_col_major((SIZE, SIZE / 8), &a_signs_row);
-
-    let (b_vals, b_signs) = compress(&b);
+    // compress a and b along k in a single pass, both stay col-major
+    let (a_vals, a_signs) = compress_a(SIZE, SIZE, a, Layout::ColMajor, SIZE);
+    let (b_vals, b_signs) = compress_b(SIZE, SIZE, b, Layout::ColMajor, SIZE);
     (a_vals, a_signs, b_vals, b_signs)
 }
 
@@ -350,8 +275,6 @@ pub fn matmul10(a_vals: &[u8], a_signs: &[u8], b_vals: &[u8], b_signs: &[u8]) ->
                             &mut c_part[mi..],
                             m,
                         );
-                        println!("mi {}, tile m {}", mi, tile_m);
-                        print_matrix(c_part, m, 16, m, 1);
                     }
                 }
             });
@@ -369,7 +292,7 @@ mod tests {
     #[test]
     fn test() {
         test_matmul(|a, b| {
-            let (av, asi, bv, bs) = prep10(&a, &b);
+            let (av, asi, bv, bs) = prep10(a, b);
             matmul10(&av, &asi, &bv, &bs)
         })
     }
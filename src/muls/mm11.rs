@@ -0,0 +1,292 @@
+//! Lookup-table kernel in the style of T-MAC / bitnet.cpp's TL1: no multiplies and no
+//! popcounts. Weights are grouped in pairs along k, each pair is one of 3^2 = 9 combinations,
+//! so it is stored as a 4-bit index. For every activation column we precompute the 9 signed
+//! partial sums `w0 * x0 + w1 * x1` of each pair of activations, and a 16-entry table lookup
+//! (`tbl` on NEON, `pshufb` on x86) then gives the contribution of 16 weight pairs at once.
+//!
+//! With int8 activations the partial sums lie in [-256, 254] and don't fit a byte, so every
+//! table is split into a table of low bytes and one of high bytes (like T-MAC does) and both
+//! are summed separately.
+use std::simd::{
+    num::{SimdInt, SimdUint},
+    Simd,
+};
+
+use crate::{
+    constants::SIZE,
+    gemm::{validate_trits, TernaryError},
+};
+
+// Weights per table index, 3^2 = 9 combinations fit the 16 entries of a tbl/pshufb table
+const G: usize = 2;
+// Rows per block of A, every byte holds the indices of two rows (low and high nibble)
+const MR: usize = 32;
+// Number of k groups the u16 low-byte sums can take before they have to be flushed into i32
+const U16_STEPS: usize = u16::MAX as usize / u8::MAX as usize;
+
+// Weights of table entry i, (i % 3 - 1, i / 3 - 1), entries 9 to 15 are never looked up
+const W0: [i16; 16] = [-1, 0, 1, -1, 0, 1, -1, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+const W1: [i16; 16] = [-1, -1, -1, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+
+/// Looks up 16 bytes of a 16-entry table. Indices are always < 16.
+pub trait Lookup {
+    /// # Safety
+    /// The CPU has to support the instructions of the implementation
+    unsafe fn lookup(table: Simd<u8, 16>, idx: Simd<u8, 16>) -> Simd<u8, 16>;
+}
+
+/// `std::simd`'s dynamic swizzle, works on every target.
+pub struct Portable;
+
+impl Lookup for Portable {
+    #[inline(always)]
+    unsafe fn lookup(table: Simd<u8, 16>, idx: Simd<u8, 16>) -> Simd<u8, 16> {
+        table.swizzle_dyn(idx)
+    }
+}
+
+/// NEON's table lookup, `vqtbl1q_u8`.
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+pub struct Neon;
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+impl Lookup for Neon {
+    #[inline(always)]
+    unsafe fn lookup(table: Simd<u8, 16>, idx: Simd<u8, 16>) -> Simd<u8, 16> {
+        use core::arch::aarch64::*;
+        Simd::from(vqtbl1q_u8(uint8x16_t::from(table), uint8x16_t::from(idx)))
+    }
+}
+
+/// SSSE3's byte shuffle, `pshufb`.
+#[cfg(target_arch = "x86_64")]
+pub struct Ssse3;
+
+#[cfg(target_arch = "x86_64")]
+impl Lookup for Ssse3 {
+    #[inline(always)]
+    unsafe fn lookup(table: Simd<u8, 16>, idx: Simd<u8, 16>) -> Simd<u8, 16> {
+        use core::arch::x86_64::*;
+        Simd::from(_mm_shuffle_epi8(__m128i::from(table), __m128i::from(idx)))
+    }
+}
+
+// Index of a pair of ternary weights into the tables
+fn index(w0: i8, w1: i8) -> u8 {
+    ((w0 + 1) + 3 * (w1 + 1)) as u8
+}
+
+/// Packs a col-major ternary `m x k` A into blocks of 32 rows. Each block holds k / 2 groups
+/// of 16 bytes, byte i has the index of the weight pair of row i in the low nibble and of row
+/// i + 16 in the high nibble.
+///
+/// Panics if m isn't a multiple of 32, k isn't a multiple of 2, a is shorter than `m * k` or
+/// holds values other than -1, 0 and 1, see [`try_pack11`].
+pub fn pack11(m: usize, k: usize, a: &[i8]) -> Vec<u8> {
+    try_pack11(m, k, a).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as [`pack11`], but returns an error for invalid values or shapes.
+pub fn try_pack11(m: usize, k: usize, a: &[i8]) -> Result<Vec<u8>, TernaryError> {
+    if !k.is_multiple_of(G) {
+        return Err(TernaryError::MisalignedK { k, multiple: G });
+    }
+    if !m.is_multiple_of(MR) {
+        return Err(TernaryError::ShapeMismatch {
+            what: "m",
+            expected: m.next_multiple_of(MR),
+            found: m,
+        });
+    }
+    if a.len() < m * k {
+        return Err(TernaryError::BufferTooSmall {
+            what: "a",
+            len: a.len(),
+            required: m * k,
+        });
+    }
+    validate_trits(&a[..(m * k)])?;
+
+    let mut packed = Vec::with_capacity(m * k / 4);
+    for mi in (0..m).step_by(MR) {
+        for ki in (0..k).step_by(G) {
+            for ri in mi..(mi + 16) {
+                let low = index(a[ri + m * ki], a[ri + m * (ki + 1)]);
+                let high = index(a[ri + 16 + m * ki], a[ri + 16 + m * (ki + 1)]);
+                packed.push(low | high << 4);
+            }
+        }
+    }
+    Ok(packed)
+}
+
+// Builds the low-byte and high-byte tables of one activation column, 32 bytes per pair
+fn build_tables(x: &[i8], tables: &mut [u8]) {
+    let w0 = Simd::from_array(W0);
+    let w1 = Simd::from_array(W1);
+
+    for (pair, table) in x.chunks_exact(G).zip(tables.chunks_exact_mut(32)) {
+        let sums = w0 * Simd::splat(pair[0] as i16) + w1 * Simd::splat(pair[1] as i16);
+        table[..16].copy_from_slice(&sums.cast::<u8>().to_array());
+        table[16..].copy_from_slice(&(sums >> 8).cast::<u8>().to_array());
+    }
+}
+
+// Computes 32 rows of one column of C from a packed block of A and the tables of the column
+#[inline(always)]
+unsafe fn dot32<L: Lookup>(groups: usize, a: &[u8], tables: &[u8], c: &mut [i32]) {
+    let nibble = Simd::splat(0x0f);
+
+    for start in (0..groups).step_by(U16_STEPS) {
+        let mut low = [Simd::<u16, 16>::splat(0); 2];
+        let mut high = [Simd::<i16, 16>::splat(0); 2];
+
+        for gi in start..(start + U16_STEPS).min(groups) {
+            let idx = Simd::<u8, 16>::from_slice(&a[(gi * 16)..(gi * 16 + 16)]);
+            let table_low = Simd::from_slice(&tables[(gi * 32)..(gi * 32 + 16)]);
+            let table_high = Simd::from_slice(&tables[(gi * 32 + 16)..(gi * 32 + 32)]);
+
+            // Rows 0-15 and 16-31
+            let idx = [idx & nibble, idx >> 4];
+            for h in 0..2 {
+                low[h] += L::lookup(table_low, idx[h]).cast::<u16>();
+                high[h] += L::lookup(table_high, idx[h]).cast::<i8>().cast::<i16>();
+            }
+        }
+
+        for h in 0..2 {
+            let sums = low[h].cast::<i32>() + (high[h].cast::<i32>() << 8);
+            let out = &mut c[(h * 16)..(h * 16 + 16)];
+            out.copy_from_slice(&(Simd::from_slice(out) + sums).to_array());
+        }
+    }
+}
+
+#[inline(always)]
+unsafe fn lut<L: Lookup>(m: usize, k: usize, n: usize, a: &[u8], b: &[i8], c: &mut [i32]) {
+    let groups = k / G;
+    let mut tables = vec![0_u8; groups * 32];
+
+    for ni in 0..n {
+        // The tables are built once per column and shared by all rows
+        build_tables(&b[(ni * k)..(ni * k + k)], &mut tables);
+
+        for mi in (0..m).step_by(MR) {
+            dot32::<L>(groups, &a[(mi * k / 4)..], &tables, &mut c[(ni * m + mi)..]);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "ssse3")]
+unsafe fn lut_ssse3(m: usize, k: usize, n: usize, a: &[u8], b: &[i8], c: &mut [i32]) {
+    lut::<Ssse3>(m, k, n, a, b, c)
+}
+
+/// Multiplies A packed with [`pack11`] with a col-major int8 `k x n` B into a col-major
+/// `m x n` C. Use n = 1 for a matrix-vector product. Panics if a or b don't fit the shapes.
+pub fn matmul11_int8(m: usize, k: usize, n: usize, a: &[u8], b: &[i8]) -> Vec<i32> {
+    assert_eq!(a.len(), m * k / 4);
+    assert_eq!(b.len(), k * n);
+    let mut c = vec![0; m * n];
+
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    unsafe {
+        lut::<Neon>(m, k, n, a, b, &mut c)
+    };
+
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("ssse3") {
+        unsafe { lut_ssse3(m, k, n, a, b, &mut c) };
+    } else {
+        unsafe { lut::<Portable>(m, k, n, a, b, &mut c) };
+    }
+
+    #[cfg(not(any(
+        all(target_arch = "aarch64", target_feature = "neon"),
+        target_arch = "x86_64"
+    )))]
+    unsafe {
+        lut::<Portable>(m, k, n, a, b, &mut c)
+    };
+
+    c
+}
+
+pub fn prep11(a: &[i8], b: &[i8]) -> (Vec<u8>, Vec<i8>) {
+    (pack11(SIZE, SIZE, a), b.to_vec())
+}
+
+pub fn matmul11(a: &[u8], b: &[i8]) -> Vec<i8> {
+    // Wrap to i8 like the other attempts
+    matmul11_int8(SIZE, SIZE, SIZE, a, b)
+        .into_iter()
+        .map(|e| e as i8)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::simd::Simd;
+
+    use crate::test_util::test_util::{test_matmul, test_matmul_int8_shape};
+
+    use crate::gemm::TernaryError;
+
+    use super::{matmul11, matmul11_int8, pack11, prep11, try_pack11, Lookup, Portable};
+
+    #[test]
+    fn test() {
+        test_matmul(|a, b| {
+            let (a_packed, b) = prep11(a, b);
+            matmul11(&a_packed, &b)
+        })
+    }
+
+    #[test]
+    fn test_int8() {
+        // n = 1 is the matrix-vector product, k covers more than one flush of the u16 sums
+        for (m, k, n) in [(32, 2, 1), (64, 300, 3), (96, 1100, 1), (32, 64, 17)] {
+            test_matmul_int8_shape(m, k, n, |a, b| matmul11_int8(m, k, n, &pack11(m, k, a), b));
+        }
+    }
+
+    #[test]
+    fn test_lookups() {
+        let table = Simd::from_array(std::array::from_fn(|i| (i * 17) as u8));
+        let idx = Simd::from_array(std::array::from_fn(|i| (i * 7 % 16) as u8));
+        let expected = idx.to_array().map(|i| table[i as usize]);
+
+        assert_eq!(unsafe { Portable::lookup(table, idx) }.to_array(), expected);
+        #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+        assert_eq!(
+            unsafe { super::Neon::lookup(table, idx) }.to_array(),
+            expected
+        );
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("ssse3") {
+            assert_eq!(
+                unsafe { super::Ssse3::lookup(table, idx) }.to_array(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_try_pack() {
+        let a = vec![1; 64 * 5];
+        assert_eq!(
+            try_pack11(64, 5, &a),
+            Err(TernaryError::MisalignedK { k: 5, multiple: 2 })
+        );
+        assert!(matches!(
+            try_pack11(40, 8, &a),
+            Err(TernaryError::ShapeMismatch { what: "m", .. })
+        ));
+        assert!(matches!(
+            try_pack11(64, 6, &a),
+            Err(TernaryError::BufferTooSmall { .. })
+        ));
+        assert_eq!(try_pack11(32, 4, &a), Ok(pack11(32, 4, &a)));
+    }
+}
@@ -10,7 +10,7 @@ fn at(r: usize, c: usize) -> usize {
 // Computes an 8x8 block of C
 fn dot8x8(k: usize, a: &[i8], b: &[i8], c: &mut [i8]) {
     // 8 rows of ab/c, with each 8 items
-    let mut ab = vec![Simd::<i8, 8>::splat(0); 8];
+    let mut ab = [Simd::<i8, 8>::splat(0); 8];
 
     for ki in 0..k {
         // Load 8 columns of b (b0-b7) and duplicate the value on that row to all lanes
@@ -11,7 +11,7 @@ fn at(r: usize, c: usize) -> usize {
 fn dot8x8(k: usize, a: &[i8], b: &[i8], c: &mut [i8]) {
     // 8 rows of ab/c, with each 8 items
     // Load initial values from c
-    let mut ab = vec![Simd::<i8, 8>::splat(0); 8];
+    let mut ab = [Simd::<i8, 8>::splat(0); 8];
 
     ab[0] = Simd::from_slice(&c[at(0, 0)..(at(0, 0) + 8)]);
     ab[1] = Simd::from_slice(&c[at(0, 1)..(at(0, 1) + 8)]);
@@ -175,8 +175,8 @@ pub fn matmul6(a: &[i8], b: &[i8]) -> Vec<i8> {
     let mut packed_b = vec![0_i8; k * n];
 
     // pack both matrices optimally once
-    pack_b(k, n, kc, nc, &b, &mut packed_b);
-    pack_a(k, n, kc, mc, &a, &mut packed_a);
+    pack_b(k, n, kc, nc, b, &mut packed_b);
+    pack_a(k, n, kc, mc, a, &mut packed_a);
 
     // LOOP 5: Split B and C on the n-dimension into parts of nc size
     for ni in (0..n).step_by(nc) {
@@ -190,12 +190,16 @@ pub fn matmul6(a: &[i8], b: &[i8]) -> Vec<i8> {
             for mi in (0..m).step_by(mc) {
                 let tile_m = min(m - mi, mc);
 
+                // The packed matrices are stored block by block, in the order they were packed
+                let a_offset = ki * m + mi * tile_k;
+                let b_offset = ni * k + ki * tile_n;
+
                 inner_kernel(
                     tile_m,
                     tile_k,
                     tile_n,
-                    &packed_a[at(mi, ki)..],
-                    &packed_b[at(ki, ni)..],
+                    &packed_a[a_offset..],
+                    &packed_b[b_offset..],
                     &mut c[(at(mi, ni))..],
                 );
             }
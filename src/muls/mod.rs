@@ -1,5 +1,10 @@
+// The attempts are purposefully verbose and unrolled, e.g. `b[ki * 16 + 0]`
+#![allow(clippy::identity_op, clippy::too_many_arguments)]
+
+pub mod kernel;
 pub mod mm1;
 pub mod mm10;
+pub mod mm11;
 pub mod mm2;
 pub mod mm3;
 pub mod mm4;
@@ -8,3 +13,5 @@ pub mod mm6;
 pub mod mm7;
 pub mod mm8;
 pub mod mm9;
+
+pub use kernel::{find, MatmulKernel, Prepared, KERNELS};
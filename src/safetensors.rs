@@ -0,0 +1,906 @@
+//! Reads and writes ternary weights in safetensors files, the format training exports.
+//!
+//! A safetensors file is an 8 byte little-endian header size, a JSON header mapping tensor names
+//! to their dtype, shape and data offsets (plus a `__metadata__` map of strings) and the data.
+//!
+//! [`SafeTensors::weights`] reads f32, f16, bf16 and int8 tensors as ternary weights: floats are
+//! quantized with absmean, int8 has to be ternary already. [`SafeTensorsWriter::ternary`] writes
+//! the val/sign bits and scales of a [`TernaryTensor`] with its shape, layout and packing version
+//! in the metadata, which [`SafeTensors::ternary`] reads back, so the conversion only happens
+//! once, offline.
+use std::{collections::BTreeMap, error::Error, fmt, fs, io, path::Path};
+
+use crate::gemm::{
+    try_quantize_weights, validate_trits, Bf16, Float, Granularity, Layout, TernaryError,
+    TernaryTensor, TernaryWeights,
+};
+
+const METADATA_KEY: &str = "__metadata__";
+/// Version of the val/sign layout written by [`SafeTensorsWriter::ternary`]
+pub const PACKING_VERSION: u32 = 1;
+const FORMAT: &str = "ternary-val-sign";
+// Nesting limit of header values, real headers nest three deep
+const MAX_DEPTH: usize = 64;
+
+/// Errors of reading and writing safetensors files.
+#[derive(Debug)]
+pub enum SafeTensorsError {
+    Io(io::Error),
+    /// Not a safetensors file, a malformed header or data outside the file
+    Format(String),
+    /// The tensor doesn't exist
+    MissingTensor(String),
+    /// The tensor's dtype can't be converted to ternary
+    UnsupportedType {
+        name: String,
+        dtype: Dtype,
+    },
+    /// The ternary weights are invalid or don't fit their shape
+    Ternary(TernaryError),
+}
+
+impl fmt::Display for SafeTensorsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafeTensorsError::Io(err) => write!(f, "io error: {}", err),
+            SafeTensorsError::Format(msg) => write!(f, "invalid safetensors file: {}", msg),
+            SafeTensorsError::MissingTensor(name) => write!(f, "no tensor named {}", name),
+            SafeTensorsError::UnsupportedType { name, dtype } => {
+                write!(f, "tensor {} has unsupported dtype {:?}", name, dtype)
+            }
+            SafeTensorsError::Ternary(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for SafeTensorsError {}
+
+impl From<io::Error> for SafeTensorsError {
+    fn from(err: io::Error) -> Self {
+        SafeTensorsError::Io(err)
+    }
+}
+
+impl From<TernaryError> for SafeTensorsError {
+    fn from(err: TernaryError) -> Self {
+        SafeTensorsError::Ternary(err)
+    }
+}
+
+fn format_error<T>(msg: impl Into<String>) -> Result<T, SafeTensorsError> {
+    Err(SafeTensorsError::Format(msg.into()))
+}
+
+// The product of the dims, an error if it overflows
+fn product(dims: &[usize]) -> Result<usize, SafeTensorsError> {
+    match dims
+        .iter()
+        .try_fold(1usize, |len, dim| len.checked_mul(*dim))
+    {
+        Some(len) => Ok(len),
+        None => format_error(format!("shape {:?} is too large", dims)),
+    }
+}
+
+/// The dtype of a tensor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Dtype {
+    F32,
+    F16,
+    BF16,
+    I8,
+    U8,
+    /// Any other dtype, by its name
+    Other(String),
+}
+
+impl Dtype {
+    fn from_name(name: &str) -> Self {
+        match name {
+            "F32" => Dtype::F32,
+            "F16" => Dtype::F16,
+            "BF16" => Dtype::BF16,
+            "I8" => Dtype::I8,
+            "U8" => Dtype::U8,
+            name => Dtype::Other(name.to_string()),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Dtype::F32 => "F32",
+            Dtype::F16 => "F16",
+            Dtype::BF16 => "BF16",
+            Dtype::I8 => "I8",
+            Dtype::U8 => "U8",
+            Dtype::Other(name) => name,
+        }
+    }
+
+    // Bytes per element, None for dtypes the crate doesn't read
+    fn size(&self) -> Option<usize> {
+        match self {
+            Dtype::F32 => Some(4),
+            Dtype::F16 | Dtype::BF16 => Some(2),
+            Dtype::I8 | Dtype::U8 => Some(1),
+            Dtype::Other(_) => None,
+        }
+    }
+}
+
+/// An entry of the header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dtype: Dtype,
+    /// Row-major shape, outermost first
+    pub shape: Vec<usize>,
+    /// Start and end of the data, relative to the end of the header
+    pub data_offsets: (usize, usize),
+}
+
+impl TensorInfo {
+    // (m, k) of a matrix, higher dimensions are folded into the rows. An error if the shape
+    // overflows
+    fn shape(&self) -> Result<(usize, usize), SafeTensorsError> {
+        match self.shape.split_last() {
+            Some((k, rows)) => Ok((product(rows)?, *k)),
+            None => Ok((1, 1)),
+        }
+    }
+}
+
+// The subset of JSON in safetensors headers
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    // Integers, kept as text until the caller knows what they are
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn as_usizes(&self) -> Option<Vec<usize>> {
+        match self {
+            Json::Array(items) => items.iter().map(Json::as_usize).collect(),
+            _ => None,
+        }
+    }
+}
+
+// Parses JSON text, one value per call of `value`
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.bytes.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), SafeTensorsError> {
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) != Some(&byte) {
+            return format_error(format!("expected '{}' at {}", byte as char, self.pos));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Consumes `byte` if it's next
+    fn next_is(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.bytes.get(self.pos) == Some(&byte);
+        self.pos += found as usize;
+        found
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Result<Json, SafeTensorsError> {
+        if !self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            return format_error(format!("invalid value at {}", self.pos));
+        }
+        self.pos += literal.len();
+        Ok(value)
+    }
+
+    fn hex(&mut self) -> Result<u32, SafeTensorsError> {
+        let digits = self.bytes.get(self.pos..(self.pos + 4));
+        let code = digits
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        self.pos += 4;
+        code.map_or_else(|| format_error("invalid \\u escape"), Ok)
+    }
+
+    fn string(&mut self) -> Result<String, SafeTensorsError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&byte) = self.bytes.get(self.pos) else {
+                return format_error("unterminated string");
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.bytes.get(self.pos) else {
+                        return format_error("unterminated string");
+                    };
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex()?;
+                            // A surrogate pair
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.bytes[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex()?;
+                                code =
+                                    0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                            }
+                            match char::from_u32(code) {
+                                Some(c) => c,
+                                None => return format_error("invalid \\u escape"),
+                            }
+                        }
+                        _ => return format_error("invalid escape"),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_or_else(|_| format_error("string is not utf-8"), Ok)
+    }
+
+    // A value, `depth` is the number of objects and arrays it is nested in
+    fn value(&mut self, depth: usize) -> Result<Json, SafeTensorsError> {
+        self.skip_whitespace();
+        if depth > MAX_DEPTH {
+            return format_error(format!("values nested deeper than {}", MAX_DEPTH));
+        }
+        match self.bytes.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut entries = Vec::new();
+                if !self.next_is(b'}') {
+                    loop {
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        entries.push((key, self.value(depth + 1)?));
+                        if self.next_is(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Object(entries))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.next_is(b']') {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.next_is(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while matches!(
+                    self.bytes.get(self.pos),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+                Ok(Json::Number(number.to_string()))
+            }
+            _ => format_error(format!("invalid value at {}", self.pos)),
+        }
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// A parsed safetensors file, held in memory.
+pub struct SafeTensors {
+    /// The `__metadata__` of the header
+    pub metadata: BTreeMap<String, String>,
+    /// The tensors in header order
+    pub tensors: Vec<TensorInfo>,
+    bytes: Vec<u8>,
+    data_start: usize,
+}
+
+impl SafeTensors {
+    /// Reads and parses a safetensors file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SafeTensorsError> {
+        Self::parse(fs::read(path)?)
+    }
+
+    /// Parses the bytes of a safetensors file
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, SafeTensorsError> {
+        let Some(size) = bytes.get(..8) else {
+            return format_error("missing header size");
+        };
+        let size = u64::from_le_bytes(size.try_into().unwrap());
+        if size > (bytes.len() - 8) as u64 {
+            return format_error(format!("header size {} larger than the file", size));
+        }
+        let data_start = 8 + size as usize;
+
+        let mut parser = Parser {
+            bytes: &bytes[8..data_start],
+            pos: 0,
+        };
+        let Json::Object(entries) = parser.value(0)? else {
+            return format_error("header is not an object");
+        };
+
+        let mut metadata = BTreeMap::new();
+        let mut tensors = Vec::with_capacity(entries.len());
+        for (name, entry) in entries {
+            if name == METADATA_KEY {
+                let Json::Object(values) = entry else {
+                    return format_error("metadata is not an object");
+                };
+                for (key, value) in values {
+                    let Json::String(value) = value else {
+                        return format_error(format!("metadata {} is not a string", key));
+                    };
+                    metadata.insert(key, value);
+                }
+                continue;
+            }
+
+            let dtype = match entry.get("dtype") {
+                Some(Json::String(dtype)) => Dtype::from_name(dtype),
+                _ => return format_error(format!("tensor {} has no dtype", name)),
+            };
+            let shape = entry.get("shape").and_then(Json::as_usizes);
+            let offsets = entry.get("data_offsets").and_then(Json::as_usizes);
+            let (Some(shape), Some(&[start, end])) = (shape, offsets.as_deref()) else {
+                return format_error(format!("tensor {} has no shape or data offsets", name));
+            };
+            if start > end || end > bytes.len() - data_start {
+                return format_error(format!("data of tensor {} outside the file", name));
+            }
+            let elements = product(&shape)?;
+            let len = dtype.size().map(|size| size.checked_mul(elements));
+            if len.is_some_and(|len| len != Some(end - start)) {
+                return format_error(format!("data of tensor {} doesn't fit its shape", name));
+            }
+            tensors.push(TensorInfo {
+                name,
+                dtype,
+                shape,
+                data_offsets: (start, end),
+            });
+        }
+
+        Ok(Self {
+            metadata,
+            tensors,
+            bytes,
+            data_start,
+        })
+    }
+
+    /// Looks up a tensor in the header
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.iter().find(|info| info.name == name)
+    }
+
+    /// The raw little-endian data of a tensor
+    pub fn tensor_data(&self, info: &TensorInfo) -> &[u8] {
+        let (start, end) = info.data_offsets;
+        &self.bytes[(self.data_start + start)..(self.data_start + end)]
+    }
+
+    fn lookup(&self, name: &str) -> Result<&TensorInfo, SafeTensorsError> {
+        self.tensor(name)
+            .ok_or_else(|| SafeTensorsError::MissingTensor(name.to_string()))
+    }
+
+    fn unsupported<T>(info: &TensorInfo) -> Result<T, SafeTensorsError> {
+        Err(SafeTensorsError::UnsupportedType {
+            name: info.name.clone(),
+            dtype: info.dtype.clone(),
+        })
+    }
+
+    /// Reads a row-major `m x k` matrix (the last dimension is k) as ternary weights. f32, f16
+    /// and bf16 tensors are quantized with absmean at the given granularity, int8 tensors have
+    /// to be ternary and get a per-tensor scale of 1.
+    pub fn weights(
+        &self,
+        name: &str,
+        granularity: Granularity,
+    ) -> Result<TernaryWeights, SafeTensorsError> {
+        let info = self.lookup(name)?;
+        let (m, k) = info.shape()?;
+        let data = self.tensor_data(info);
+
+        fn quantize<T: Float, const N: usize>(
+            (m, k): (usize, usize),
+            data: &[u8],
+            granularity: Granularity,
+            from_bytes: impl Fn([u8; N]) -> T,
+        ) -> Result<TernaryWeights, TernaryError> {
+            let values: Vec<T> = data
+                .chunks_exact(N)
+                .map(|bytes| from_bytes(bytes.try_into().unwrap()))
+                .collect();
+            try_quantize_weights(m, k, &values, Layout::RowMajor, k.max(1), granularity)
+        }
+
+        Ok(match info.dtype {
+            Dtype::F32 => quantize((m, k), data, granularity, f32::from_le_bytes)?,
+            Dtype::F16 => quantize((m, k), data, granularity, |bytes| {
+                f16::from_bits(u16::from_le_bytes(bytes))
+            })?,
+            Dtype::BF16 => quantize((m, k), data, granularity, |bytes| {
+                Bf16(u16::from_le_bytes(bytes))
+            })?,
+            Dtype::I8 => {
+                let values: Vec<i8> = data.iter().map(|byte| *byte as i8).collect();
+                validate_trits(&values)?;
+                let zeros = values.iter().filter(|value| **value == 0).count();
+                TernaryWeights {
+                    m,
+                    k,
+                    layout: Layout::RowMajor,
+                    granularity: Granularity::PerTensor,
+                    scales: vec![1.],
+                    mse: 0.,
+                    sparsity: zeros as f32 / values.len().max(1) as f32,
+                    values,
+                }
+            }
+            _ => return Self::unsupported(info),
+        })
+    }
+
+    /// Reads ternary weights written by [`SafeTensorsWriter::ternary`]
+    pub fn ternary(&self, name: &str) -> Result<TernaryTensor, SafeTensorsError> {
+        let metadata = |key: &str| {
+            let key = format!("{}.{}", name, key);
+            match self.metadata.get(&key) {
+                Some(value) => Ok(value.as_str()),
+                None => format_error(format!("missing metadata {}", key)),
+            }
+        };
+        if metadata("format")? != FORMAT {
+            return format_error(format!("{} is not in the {} format", name, FORMAT));
+        }
+        let version = metadata("packing_version")?;
+        if version != PACKING_VERSION.to_string() {
+            return format_error(format!("unsupported packing version {}", version));
+        }
+        if metadata("layout")? != "row_major" {
+            return format_error(format!("{} is not row-major", name));
+        }
+        let shape: Option<Vec<usize>> = metadata("shape")?
+            .split(',')
+            .map(|dim| dim.trim().parse().ok())
+            .collect();
+        let Some(&[m, k]) = shape.as_deref() else {
+            return format_error(format!("invalid shape of {}", name));
+        };
+        let granularity = match metadata("granularity")? {
+            "per_tensor" => Granularity::PerTensor,
+            "per_channel" => Granularity::PerChannel,
+            group => match group.strip_prefix("per_group:").map(str::parse) {
+                Some(Ok(size)) if size > 0 => Granularity::PerGroup(size),
+                _ => return format_error(format!("invalid granularity {}", group)),
+            },
+        };
+
+        let bits = |suffix: &str| {
+            let info = self.lookup(&format!("{}.{}", name, suffix))?;
+            if info.dtype != Dtype::U8 {
+                return Self::unsupported(info);
+            }
+            Ok(self.tensor_data(info).to_vec())
+        };
+        let (vals, signs) = (bits("vals")?, bits("signs")?);
+        let info = self.lookup(&format!("{}.scales", name))?;
+        if info.dtype != Dtype::F32 {
+            return Self::unsupported(info);
+        }
+        let scales: Vec<f32> = self
+            .tensor_data(info)
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+
+        let tensor = TernaryTensor {
+            m,
+            k,
+            vals,
+            signs,
+            granularity,
+            scales,
+        };
+        let scales_len = match granularity {
+            Granularity::PerGroup(size) => m.checked_mul(k.div_ceil(size)),
+            granularity => Some(granularity.scales(m, k)),
+        };
+        let (Some(bits_len), Some(scales_len)) = (m.checked_mul(tensor.ld()), scales_len) else {
+            return format_error(format!("{} x {} tensor {} is too large", m, k, name));
+        };
+        for (what, len, required) in [
+            ("vals", tensor.vals.len(), bits_len),
+            ("signs", tensor.signs.len(), bits_len),
+            ("scales", tensor.scales.len(), scales_len),
+        ] {
+            if len < required {
+                let err = TernaryError::BufferTooSmall {
+                    what,
+                    len,
+                    required,
+                };
+                return Err(err.into());
+            }
+        }
+        Ok(tensor)
+    }
+}
+
+/// Writes safetensors files.
+#[derive(Default)]
+pub struct SafeTensorsWriter {
+    metadata: BTreeMap<String, String>,
+    tensors: Vec<(String, Dtype, Vec<usize>, Vec<u8>)>,
+}
+
+impl SafeTensorsWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a `__metadata__` entry
+    pub fn metadata(&mut self, key: &str, value: &str) {
+        self.metadata.insert(key.to_string(), value.to_string());
+    }
+
+    /// Adds a tensor with little-endian data of the given row-major shape
+    pub fn tensor(&mut self, name: &str, dtype: Dtype, shape: &[usize], data: Vec<u8>) {
+        self.tensors
+            .push((name.to_string(), dtype, shape.to_vec(), data));
+    }
+
+    /// Adds an f32 tensor
+    pub fn f32_tensor(&mut self, name: &str, shape: &[usize], values: &[f32]) {
+        let data = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        self.tensor(name, Dtype::F32, shape, data);
+    }
+
+    /// Adds compressed ternary weights as the tensors `{name}.vals`, `{name}.signs` (U8,
+    /// `m x ld`) and `{name}.scales` (F32), and their shape, layout, granularity and packing
+    /// version as `{name}.*` metadata.
+    pub fn ternary(&mut self, name: &str, tensor: &TernaryTensor) {
+        let granularity = match tensor.granularity {
+            Granularity::PerTensor => "per_tensor".to_string(),
+            Granularity::PerChannel => "per_channel".to_string(),
+            Granularity::PerGroup(size) => format!("per_group:{}", size),
+        };
+        let shape = format!("{},{}", tensor.m, tensor.k);
+        let version = PACKING_VERSION.to_string();
+        for (key, value) in [
+            ("format", FORMAT),
+            ("packing_version", &version),
+            ("shape", &shape),
+            ("layout", "row_major"),
+            ("granularity", &granularity),
+        ] {
+            self.metadata(&format!("{}.{}", name, key), value);
+        }
+
+        let bits_shape = [tensor.m, tensor.ld()];
+        let bits_len = tensor.m * tensor.ld();
+        self.tensor(
+            &format!("{}.vals", name),
+            Dtype::U8,
+            &bits_shape,
+            tensor.vals[..bits_len].to_vec(),
+        );
+        self.tensor(
+            &format!("{}.signs", name),
+            Dtype::U8,
+            &bits_shape,
+            tensor.signs[..bits_len].to_vec(),
+        );
+        self.f32_tensor(
+            &format!("{}.scales", name),
+            &[tensor.scales.len()],
+            &tensor.scales,
+        );
+    }
+
+    /// The bytes of the file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = String::from("{");
+        if !self.metadata.is_empty() {
+            write_json_string(&mut header, METADATA_KEY);
+            header.push_str(":{");
+            for (i, (key, value)) in self.metadata.iter().enumerate() {
+                if i > 0 {
+                    header.push(',');
+                }
+                write_json_string(&mut header, key);
+                header.push(':');
+                write_json_string(&mut header, value);
+            }
+            header.push('}');
+        }
+
+        let mut offset = 0;
+        for (i, (name, dtype, shape, data)) in self.tensors.iter().enumerate() {
+            if i > 0 || !self.metadata.is_empty() {
+                header.push(',');
+            }
+            write_json_string(&mut header, name);
+            let shape: Vec<String> = shape.iter().map(usize::to_string).collect();
+            header.push_str(&format!(
+                ":{{\"dtype\":\"{}\",\"shape\":[{}],\"data_offsets\":[{},{}]}}",
+                dtype.name(),
+                shape.join(","),
+                offset,
+                offset + data.len()
+            ));
+            offset += data.len();
+        }
+        header.push('}');
+        // The data starts 8-byte aligned
+        while header.len() % 8 != 0 {
+            header.push(' ');
+        }
+
+        let mut out = (header.len() as u64).to_le_bytes().to_vec();
+        out.extend_from_slice(header.as_bytes());
+        for (_, _, _, data) in &self.tensors {
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    /// Writes the file
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), SafeTensorsError> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gemm::{quantize_weights, Granularity, Layout, TernaryError, TernaryTensor};
+
+    use super::{Dtype, Json, Parser, SafeTensors, SafeTensorsError, SafeTensorsWriter, MAX_DEPTH};
+
+    #[test]
+    fn test_weights() {
+        let (m, k) = (5, 40);
+        let w: Vec<f32> = (0..(m * k)).map(|i| ((i * 37) % 11) as f32 - 5.).collect();
+        let trits: Vec<i8> = (0..(m * k)).map(|i| (i % 3) as i8 - 1).collect();
+
+        let mut writer = SafeTensorsWriter::new();
+        writer.metadata("source", "test \"run\"\n");
+        writer.f32_tensor("f32", &[m, k], &w);
+        // bf16 and f16 of small integers are exact
+        let bf16 = w
+            .iter()
+            .flat_map(|v| ((v.to_bits() >> 16) as u16).to_le_bytes());
+        writer.tensor("bf16", Dtype::BF16, &[m, k], bf16.collect());
+        let f16 = w.iter().flat_map(|v| (*v as f16).to_bits().to_le_bytes());
+        writer.tensor("f16", Dtype::F16, &[m, k], f16.collect());
+        let i8 = trits.iter().map(|v| *v as u8).collect();
+        writer.tensor("i8", Dtype::I8, &[m, k], i8);
+        writer.tensor("bool", Dtype::Other("BOOL".into()), &[2], vec![0, 1]);
+
+        let file = SafeTensors::parse(writer.to_bytes()).unwrap();
+        assert_eq!(file.metadata["source"], "test \"run\"\n");
+        assert_eq!(file.tensors.len(), 5);
+
+        let granularity = Granularity::PerChannel;
+        let expected = quantize_weights(m, k, &w, Layout::RowMajor, k, granularity);
+        for name in ["f32", "bf16", "f16"] {
+            let weights = file.weights(name, granularity).unwrap();
+            assert_eq!(weights.values, expected.values, "{}", name);
+            assert_eq!(weights.scales, expected.scales, "{}", name);
+        }
+        let weights = file.weights("i8", granularity).unwrap();
+        assert_eq!((weights.values, weights.scales), (trits, vec![1.]));
+
+        assert!(matches!(
+            file.weights("bool", granularity),
+            Err(SafeTensorsError::UnsupportedType { .. })
+        ));
+        assert!(matches!(
+            file.weights("missing", granularity),
+            Err(SafeTensorsError::MissingTensor(_))
+        ));
+    }
+
+    #[test]
+    fn test_ternary() {
+        let (m, k) = (4, 300);
+        let w: Vec<f32> = (0..(m * k)).map(|i| ((i * 13) % 7) as f32 - 3.).collect();
+        for layout in [Layout::RowMajor, Layout::ColMajor] {
+            let ld = if layout == Layout::RowMajor { k } else { m };
+            let weights = quantize_weights(m, k, &w, layout, ld, Granularity::PerGroup(128));
+            let tensor = TernaryTensor::from(&weights);
+
+            let mut writer = SafeTensorsWriter::new();
+            writer.ternary("layer.0", &tensor);
+            let path = std::env::temp_dir().join(format!(
+                "ternary-{}-{:?}.safetensors",
+                std::process::id(),
+                layout
+            ));
+            writer.write(&path).unwrap();
+            let file = SafeTensors::open(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(file.metadata["layer.0.shape"], "4,300");
+            assert_eq!(file.metadata["layer.0.packing_version"], "1");
+            assert_eq!(file.ternary("layer.0").unwrap(), tensor);
+        }
+
+        // Compressed rows match compressing the row-major values
+        let weights = quantize_weights(m, k, &w, Layout::RowMajor, k, Granularity::PerTensor);
+        let tensor = TernaryTensor::from(&weights);
+        assert_eq!(
+            (tensor.vals.clone(), tensor.signs.clone()),
+            weights.compress()
+        );
+
+        // Scales cut short
+        let mut short = tensor.clone();
+        short.scales.clear();
+        let mut writer = SafeTensorsWriter::new();
+        writer.ternary("w", &short);
+        let file = SafeTensors::parse(writer.to_bytes()).unwrap();
+        assert!(matches!(
+            file.ternary("w"),
+            Err(SafeTensorsError::Ternary(TernaryError::BufferTooSmall {
+                what: "scales",
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut writer = SafeTensorsWriter::new();
+        writer.tensor("i8", Dtype::I8, &[2, 2], vec![0, 1, 2, 255]);
+        let bytes = writer.to_bytes();
+
+        let file = SafeTensors::parse(bytes.clone()).unwrap();
+        assert!(matches!(
+            file.weights("i8", Granularity::PerTensor),
+            Err(SafeTensorsError::Ternary(TernaryError::InvalidTrit {
+                index: 2,
+                value: 2
+            }))
+        ));
+        assert!(matches!(
+            file.ternary("i8"),
+            Err(SafeTensorsError::Format(_))
+        ));
+
+        for len in [4, 20, bytes.len() - 1] {
+            assert!(matches!(
+                SafeTensors::parse(bytes[..len].to_vec()),
+                Err(SafeTensorsError::Format(_))
+            ));
+        }
+
+        let mut parser = Parser {
+            bytes: br#" {"a": [1, -2, true, null], "b\u00e9\ud83d\ude00\n": {}} "#,
+            pos: 0,
+        };
+        assert_eq!(
+            parser.value(0).unwrap(),
+            Json::Object(vec![
+                (
+                    "a".into(),
+                    Json::Array(vec![
+                        Json::Number("1".into()),
+                        Json::Number("-2".into()),
+                        Json::Bool(true),
+                        Json::Null
+                    ])
+                ),
+                ("bé😀\n".into(), Json::Object(vec![])),
+            ])
+        );
+
+        // Values nested too deep
+        let nested = "[".repeat(MAX_DEPTH + 2) + &"]".repeat(MAX_DEPTH + 2);
+        let mut parser = Parser {
+            bytes: nested.as_bytes(),
+            pos: 0,
+        };
+        assert!(matches!(parser.value(0), Err(SafeTensorsError::Format(_))));
+
+        // Shapes whose product overflows
+        let mut writer = SafeTensorsWriter::new();
+        writer.tensor("huge", Dtype::Other("X".into()), &[usize::MAX, 2], vec![]);
+        assert!(matches!(
+            SafeTensors::parse(writer.to_bytes()),
+            Err(SafeTensorsError::Format(_))
+        ));
+        let mut writer = SafeTensorsWriter::new();
+        let tensor = TernaryTensor {
+            m: 1,
+            k: 8,
+            vals: vec![0],
+            signs: vec![0],
+            granularity: Granularity::PerTensor,
+            scales: vec![1.],
+        };
+        writer.ternary("w", &tensor);
+        writer.metadata("w.shape", &format!("{},64", usize::MAX / 2));
+        let file = SafeTensors::parse(writer.to_bytes()).unwrap();
+        assert!(matches!(
+            file.ternary("w"),
+            Err(SafeTensorsError::Format(_))
+        ));
+    }
+}
@@ -1,11 +1,12 @@
 // #[cfg(test)]
+#[allow(clippy::module_inception)]
 pub mod test_util {
     use ndarray::{Array2, ShapeBuilder};
     use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use crate::{constants::SIZE, test_util::print_matrix};
 
-    pub fn test_matmul(matmul: fn(a: &[i8], b: &[i8]) -> Vec<i8>) -> () {
+    pub fn test_matmul(matmul: impl Fn(&[i8], &[i8]) -> Vec<i8>) {
         let m = SIZE;
         let k = SIZE;
         let n = SIZE;
@@ -32,7 +33,7 @@ pub mod test_util {
 
         let res_clone = res.clone();
 
-        let res_array = Array2::from_shape_vec((m, n).f(), res).unwrap();
+        let res_array = Array2::from_shape_vec((m, n).f(), res).expect("result has the wrong size");
 
         println!("Res:\n");
         print_matrix(&res_clone, 32, 32, 32, 1);
@@ -42,9 +43,8 @@ pub mod test_util {
         assert_eq!(res_array, res_true);
     }
 
-    pub fn test_kernel(
-        kernel: fn(m: usize, k: usize, n: usize, a: &[i8], b: &[i8]) -> Vec<i8>,
-    ) -> () {
+    #[allow(clippy::type_complexity)]
+    pub fn test_kernel(kernel: fn(m: usize, k: usize, n: usize, a: &[i8], b: &[i8]) -> Vec<i8>) {
         let m = 16;
         let k = 256;
         let n = 16;
@@ -71,7 +71,7 @@ pub mod test_util {
 
         let res_clone = res.clone();
 
-        let res_array = Array2::from_shape_vec((m, n).f(), res).unwrap();
+        let res_array = Array2::from_shape_vec((m, n).f(), res).expect("result has the wrong size");
         println!("Res:\n");
         print_matrix(&res_clone, 16, 16, 16, 1);
         println!("Res true:\n");
@@ -80,49 +80,110 @@ pub mod test_util {
         assert_eq!(res_array, res_true);
     }
 
+    /// Tests a matmul of a col-major `m x k` A and a col-major `k x n` B against ndarray.
+    /// The reference is wrapped to i8, the same way the i8 accumulators wrap.
+    pub fn test_matmul_shape(
+        m: usize,
+        k: usize,
+        n: usize,
+        matmul: impl Fn(&[i8], &[i8]) -> Vec<i8>,
+    ) {
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+
+        let a_array =
+            Array2::from_shape_vec((m, k).f(), a.iter().map(|e| *e as f32).collect()).unwrap();
+
+        let b_array =
+            Array2::from_shape_vec((k, n).f(), b.iter().map(|e| *e as f32).collect()).unwrap();
+
+        let res_true = a_array.dot(&b_array).map(|f| *f as i32 as i8);
+
+        let res = matmul(&a, &b);
+
+        let res_array = Array2::from_shape_vec((m, n).f(), res).expect("result has the wrong size");
+
+        assert_eq!(res_array, res_true, "m = {}, k = {}, n = {}", m, k, n);
+    }
+
+    /// Tests an i32 matmul of a col-major `m x k` A and a col-major `k x n` B against an
+    /// exact ndarray reference.
+    pub fn test_matmul_i32_shape(
+        m: usize,
+        k: usize,
+        n: usize,
+        matmul: impl Fn(&[i8], &[i8]) -> Vec<i32>,
+    ) {
+        let (a, b) = rand_vecs_sized(m * k, k * n);
+        assert_matmul_i32(m, k, n, &a, &b, matmul);
+    }
+
+    /// Same as [`test_matmul_i32_shape`], but B holds random int8 values (activations).
+    pub fn test_matmul_int8_shape(
+        m: usize,
+        k: usize,
+        n: usize,
+        matmul: impl Fn(&[i8], &[i8]) -> Vec<i32>,
+    ) {
+        let (a, b) = rand_int8_vecs(m * k, k * n);
+        assert_matmul_i32(m, k, n, &a, &b, matmul);
+    }
+
+    fn assert_matmul_i32(
+        m: usize,
+        k: usize,
+        n: usize,
+        a: &[i8],
+        b: &[i8],
+        matmul: impl Fn(&[i8], &[i8]) -> Vec<i32>,
+    ) {
+        let a_array =
+            Array2::from_shape_vec((m, k).f(), a.iter().map(|e| *e as i32).collect()).unwrap();
+        let b_array =
+            Array2::from_shape_vec((k, n).f(), b.iter().map(|e| *e as i32).collect()).unwrap();
+
+        let res_true = a_array.dot(&b_array);
+
+        let res = matmul(a, b);
+
+        let res_array = Array2::from_shape_vec((m, n).f(), res).expect("result has the wrong size");
+
+        assert_eq!(res_array, res_true, "m = {}, k = {}, n = {}", m, k, n);
+    }
+
     pub fn rand_vecs(size: usize) -> (Vec<i8>, Vec<i8>) {
+        rand_vecs_sized(size, size)
+    }
+
+    pub fn rand_vecs_sized(a_size: usize, b_size: usize) -> (Vec<i8>, Vec<i8>) {
         let mut rng = StdRng::seed_from_u64(1337);
-        let a = rand_ternary_vec(&mut rng, size);
-        let b = rand_ternary_vec(&mut rng, size);
+        let a = rand_ternary_vec(&mut rng, a_size);
+        let b = rand_ternary_vec(&mut rng, b_size);
+        (a, b)
+    }
+
+    /// Returns a random ternary vec and a random int8 vec (covering the full i8 range)
+    pub fn rand_int8_vecs(a_size: usize, b_size: usize) -> (Vec<i8>, Vec<i8>) {
+        let mut rng = StdRng::seed_from_u64(1337);
+        let a = rand_ternary_vec(&mut rng, a_size);
+        let b = (0..b_size).map(|_| rng.gen()).collect();
         (a, b)
     }
 
     fn rand_ternary_vec(rng: &mut impl Rng, length: usize) -> Vec<i8> {
         let mut vec = vec![0; length];
-        for i in 0..vec.len() {
-            vec[i] = rng.gen_range(-1..2);
+        for e in vec.iter_mut() {
+            *e = rng.gen_range(-1..2);
         }
         vec
     }
 }
 
-pub fn print_matrix(inp: &[i8], rows: usize, cols: usize, cstride: usize, rstride: usize) -> () {
+pub fn print_matrix(inp: &[i8], rows: usize, cols: usize, cstride: usize, rstride: usize) {
     for ri in 0..rows {
         for ci in 0..cols {
             let val = inp[cstride * ci + ri * rstride];
             print!(" {: >3} ", val);
         }
-        println!("");
+        println!();
     }
 }
-
-macro_rules! set_c {
-    ($i:expr) => {
-        // Broadcast ith lane (col) in B to a full 16-col simd
-        let b1_val_i = Simd::splat(b1_val[$i]); // vdupq_laneq_u8 in aarch64 asm
-        let b1_sign_i = Simd::splat(b1_sign[$i]);
-        let b2_val_i = Simd::splat(b2_val[$i]);
-        let b2_sign_i = Simd::splat(b2_sign[$i]);
-
-        let b3_val_i = Simd::splat(b3_val[$i]); // vdupq_laneq_u8 in aarch64 asm
-        let b3_sign_i = Simd::splat(b3_sign[$i]);
-        let b4_val_i = Simd::splat(b4_val[$i]);
-        let b4_sign_i = Simd::splat(b4_sign[$i]);
-
-        // output 16 rows to C (16 rows of A x ith col of B)
-        ab[$i] += dot(a1_val, a1_sign, b1_val_i, b1_sign_i);
-        ab[$i] += dot(a2_val, a2_sign, b2_val_i, b2_sign_i);
-        ab[$i] += dot(a3_val, a3_sign, b3_val_i, b3_sign_i);
-        ab[$i] += dot(a4_val, a4_sign, b4_val_i, b4_sign_i);
-    };
-}
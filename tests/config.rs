@@ -0,0 +1,21 @@
+//! Tests of the process-wide gemm configuration. They run in their own test binary, so
+//! changing the configuration can't race with the unit tests of the kernels.
+use matmul::gemm::{selected_kernel, set_kernel, set_threads, threads, Kernel};
+
+#[test]
+fn test_set_kernel() {
+    set_kernel(Some(Kernel::Portable));
+    assert_eq!(selected_kernel(), Kernel::Portable);
+    set_kernel(None);
+    assert!(selected_kernel().is_supported());
+}
+
+#[test]
+fn test_set_threads() {
+    set_threads(Some(3));
+    assert_eq!(threads(), 3);
+    set_threads(Some(0));
+    assert_eq!(threads(), 1);
+    set_threads(None);
+    assert!(threads() >= 1);
+}